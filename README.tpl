@@ -1,6 +1,7 @@
 [![usdpl-back](https://img.shields.io/crates/v/usdpl-back?label=usdpl-back&style=flat-square)](https://crates.io/crates/usdpl-back)
 [![usdpl-core](https://img.shields.io/crates/v/usdpl-core?label=usdpl-core&style=flat-square)](https://crates.io/crates/usdpl-core)
 [![usdpl-front](https://img.shields.io/crates/v/usdpl-front?label=usdpl-front&style=flat-square)](https://crates.io/crates/usdpl-front)
+[![usdpl-client](https://img.shields.io/crates/v/usdpl-client?label=usdpl-client&style=flat-square)](https://crates.io/crates/usdpl-client)
 
 # {{crate}}
 
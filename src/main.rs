@@ -16,6 +16,332 @@
 //! - [ ] Cross-framework tooling
 //! - [ ] Other programming languages support (C bindings)
 //!
-fn main() {
-    println!("Hello, USDPL!");
+//! ## Usage
+//! The `usdpl` binary is a tool for developing plugins:
+//! - `usdpl call <port> <function> [parameters...]` calls a back-end function and prints the result
+//! - `usdpl decode [packet]` prints a packet copied from the browser's devtools (or stdin)
+//! - `usdpl keygen` generates a new `USDPL_ENCRYPTION_KEY`
+//!
+//! With the `encrypt` feature, `call` and `decode` use the key from `--key <hex>` or the `USDPL_ENCRYPTION_KEY` environment variable.
+//!
+use std::io::Read;
+use std::process::ExitCode;
+
+use usdpl_core::serdes::{Loadable, Primitive};
+use usdpl_core::socket;
+
+const USAGE: &str = "Usage:
+    usdpl call [--key <hex>] <port> <function> [parameters...]
+    usdpl decode [--key <hex>] [--tagged] [packet]
+    usdpl keygen
+
+Parameters are sent as bool, null (empty), integer (i64), float (f64) or string, in that order of preference.
+Prefix a parameter with its type to choose it, e.g. `u32:15`, `string:true` or `json:{}`.
+Packets are read from stdin when not given as an argument; use --tagged for WebSocket messages.";
+
+/// Size of an encryption key, in bytes
+const KEY_SIZE: usize = 32;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(|x| x.as_str()) {
+        Some("call") => call(&args[1..]),
+        Some("decode") => decode(&args[1..]),
+        Some("keygen") => keygen(),
+        Some("help" | "--help" | "-h") => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        _ => Err(USAGE.to_owned()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Command line arguments of a subcommand, with the options taken out
+struct Args {
+    positional: Vec<String>,
+    #[cfg_attr(not(feature = "encrypt"), allow(dead_code))]
+    key: Option<String>,
+    tagged: bool,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        Self::parse_with_key(args, std::env::var("USDPL_ENCRYPTION_KEY").ok())
+    }
+
+    /// Parse arguments, using `key` unless `--key` is given
+    fn parse_with_key(args: &[String], key: Option<String>) -> Result<Self, String> {
+        let mut result = Self {
+            positional: Vec::with_capacity(args.len()),
+            key,
+            tagged: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--key" => result.key = Some(args.next().ok_or("--key requires a value")?.to_owned()),
+                "--tagged" => result.tagged = true,
+                "--" => {
+                    result.positional.extend(args.cloned());
+                    break;
+                },
+                _ => result.positional.push(arg.to_owned()),
+            }
+        }
+        Ok(result)
+    }
+
+    #[cfg(feature = "encrypt")]
+    fn key(&self) -> Result<Option<Vec<u8>>, String> {
+        self.key
+            .as_ref()
+            .map(|key| hex::decode(key).map_err(|e| format!("Invalid encryption key: {}", e)))
+            .transpose()
+    }
+}
+
+fn call(args: &[String]) -> Result<(), String> {
+    let args = Args::parse(args)?;
+    if args.positional.len() < 2 {
+        return Err(USAGE.to_owned());
+    }
+    let port: u16 = args.positional[0]
+        .parse()
+        .map_err(|e| format!("Invalid port `{}`: {}", args.positional[0], e))?;
+    let parameters = args.positional[2..]
+        .iter()
+        .map(|x| parse_primitive(x))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let client = usdpl_client::blocking::Client::new(port);
+    #[cfg(feature = "encrypt")]
+    let client = match args.key()? {
+        Some(key) => {
+            let client = client.with_key(key);
+            client.handshake().map_err(|e| format!("Handshake failed: {}", e))?;
+            client
+        },
+        None => client,
+    };
+    let results = client
+        .call(args.positional[1].as_str(), parameters)
+        .map_err(|e| e.to_string())?;
+    for result in results {
+        println!("{:?}", result);
+    }
+    Ok(())
+}
+
+fn parse_primitive(arg: &str) -> Result<Primitive, String> {
+    if let Some((ty, value)) = arg.split_once(':') {
+        let invalid = |e: &dyn std::fmt::Display| format!("Invalid {} parameter `{}`: {}", ty, value, e);
+        match ty {
+            "empty" | "null" => return Ok(Primitive::Empty),
+            "string" | "str" => return Ok(Primitive::String(value.to_owned())),
+            "json" => return Ok(Primitive::Json(value.to_owned())),
+            "bool" => return value.parse().map(Primitive::Bool).map_err(|e| invalid(&e)),
+            "f32" => return value.parse().map(Primitive::F32).map_err(|e| invalid(&e)),
+            "f64" => return value.parse().map(Primitive::F64).map_err(|e| invalid(&e)),
+            "u32" => return value.parse().map(Primitive::U32).map_err(|e| invalid(&e)),
+            "u64" => return value.parse().map(Primitive::U64).map_err(|e| invalid(&e)),
+            "i32" => return value.parse().map(Primitive::I32).map_err(|e| invalid(&e)),
+            "i64" => return value.parse().map(Primitive::I64).map_err(|e| invalid(&e)),
+            _ => {}, // not a type, so the colon is part of the value
+        }
+    }
+    Ok(match arg {
+        "true" => Primitive::Bool(true),
+        "false" => Primitive::Bool(false),
+        "null" => Primitive::Empty,
+        _ => if let Ok(x) = arg.parse() {
+            Primitive::I64(x)
+        } else if let Ok(x) = arg.parse() {
+            Primitive::F64(x)
+        } else {
+            Primitive::String(arg.to_owned())
+        },
+    })
+}
+
+fn decode(args: &[String]) -> Result<(), String> {
+    let args = Args::parse(args)?;
+    let data = match args.positional.first() {
+        Some(x) => x.to_owned(),
+        None => {
+            let mut buffer = String::new();
+            std::io::stdin().read_to_string(&mut buffer).map_err(|e| e.to_string())?;
+            buffer
+        }
+    };
+    println!("{}", decode_packet(&args, &data)?);
+    Ok(())
+}
+
+/// Decode a (possibly encrypted) base64 packet into its pretty-printed form
+fn decode_packet(args: &Args, data: &str) -> Result<String, String> {
+    let data = data.trim().trim_matches('"').as_bytes();
+    #[cfg(feature = "encrypt")]
+    if let Some(key) = args.key()? {
+        // captured packets are usually old, so only check the nonce was generated correctly
+        let guard = usdpl_core::serdes::ReplayGuard::new(std::time::Duration::MAX);
+        // packets encrypted with a session key start with its id, which is skipped (the key must be that session key then)
+        let keys = |_: Option<u64>| Some(&key);
+        return if args.tagged {
+            load(socket::Tagged::load_encrypted_with_id(data, keys, &guard).map(|(x, len, _)| (x, len))).map(|x| format!("{:#?}", x))
+        } else {
+            load(socket::Packet::load_encrypted_with_id(data, keys, &guard).map(|(x, len, _)| (x, len))).map(|x| format!("{:#?}", x))
+        };
+    }
+    if args.tagged {
+        load(socket::Tagged::load_base64(data)).map(|x| format!("{:#?}", x))
+    } else {
+        load(socket::Packet::load_base64(data)).map(|x| format!("{:#?}", x))
+    }
+}
+
+fn load<T>(result: Result<(T, usize), usdpl_core::serdes::LoadError>) -> Result<T, String> {
+    result
+        .map(|(x, _)| x)
+        .map_err(|e| format!("Failed to decode packet: {}", e))
+}
+
+fn keygen() -> Result<(), String> {
+    let mut key = [0u8; KEY_SIZE];
+    getrandom::getrandom(&mut key).map_err(|e| format!("Failed to generate key: {}", e))?;
+    let hex: String = key.iter().map(|b| format!("{:02X}", b)).collect();
+    println!("{}", hex);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usdpl_core::serdes::Dumpable;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_primitive_untyped_test() {
+        assert!(matches!(parse_primitive("true"), Ok(Primitive::Bool(true))), "true was not a bool");
+        assert!(matches!(parse_primitive("false"), Ok(Primitive::Bool(false))), "false was not a bool");
+        assert!(matches!(parse_primitive("null"), Ok(Primitive::Empty)), "null was not empty");
+        assert!(matches!(parse_primitive("-42"), Ok(Primitive::I64(-42))), "-42 was not an i64");
+        assert!(matches!(parse_primitive("4.2"), Ok(Primitive::F64(x)) if x == 4.2), "4.2 was not an f64");
+        assert!(matches!(parse_primitive("hello"), Ok(Primitive::String(x)) if x == "hello"), "hello was not a string");
+        assert!(matches!(parse_primitive(""), Ok(Primitive::String(x)) if x.is_empty()), "Empty argument was not a string");
+    }
+
+    #[test]
+    fn parse_primitive_typed_test() {
+        assert!(matches!(parse_primitive("u32:15"), Ok(Primitive::U32(15))), "u32 prefix was ignored");
+        assert!(matches!(parse_primitive("u64:15"), Ok(Primitive::U64(15))), "u64 prefix was ignored");
+        assert!(matches!(parse_primitive("i32:-15"), Ok(Primitive::I32(-15))), "i32 prefix was ignored");
+        assert!(matches!(parse_primitive("i64:-15"), Ok(Primitive::I64(-15))), "i64 prefix was ignored");
+        assert!(matches!(parse_primitive("f32:1.5"), Ok(Primitive::F32(x)) if x == 1.5), "f32 prefix was ignored");
+        assert!(matches!(parse_primitive("f64:15"), Ok(Primitive::F64(x)) if x == 15.0), "f64 prefix was ignored");
+        assert!(matches!(parse_primitive("bool:false"), Ok(Primitive::Bool(false))), "bool prefix was ignored");
+        assert!(matches!(parse_primitive("string:true"), Ok(Primitive::String(x)) if x == "true"), "string prefix was ignored");
+        assert!(matches!(parse_primitive("str:42"), Ok(Primitive::String(x)) if x == "42"), "str prefix was ignored");
+        assert!(matches!(parse_primitive("string:a:b"), Ok(Primitive::String(x)) if x == "a:b"), "Only the first colon should separate the type");
+        assert!(matches!(parse_primitive("json:{\"a\":1}"), Ok(Primitive::Json(x)) if x == "{\"a\":1}"), "json prefix was ignored");
+        assert!(matches!(parse_primitive("empty:"), Ok(Primitive::Empty)), "empty prefix was ignored");
+        assert!(matches!(parse_primitive("null:anything"), Ok(Primitive::Empty)), "null prefix was ignored");
+        // not a type, so the whole argument is the value
+        assert!(matches!(parse_primitive("http://localhost"), Ok(Primitive::String(x)) if x == "http://localhost"), "Unknown prefix was not kept");
+    }
+
+    #[test]
+    fn parse_primitive_malformed_test() {
+        for arg in ["u32:-1", "u32:", "u64:1.5", "i32:2147483648", "i64:abc", "f32:x", "f64:", "bool:yes", "bool:1"] {
+            let err = parse_primitive(arg).expect_err(arg);
+            let (ty, value) = arg.split_once(':').unwrap();
+            assert!(
+                err.starts_with(&format!("Invalid {} parameter `{}`: ", ty, value)),
+                "Unexpected error for `{}`: {}", arg, err
+            );
+        }
+    }
+
+    #[test]
+    fn args_parse_test() {
+        let parsed = Args::parse_with_key(&args(&["8080", "--tagged", "hello", "--key", "00ff", "world"]), None).unwrap();
+        assert_eq!(parsed.positional, ["8080", "hello", "world"], "Options were not taken out");
+        assert_eq!(parsed.key.as_deref(), Some("00ff"), "--key was ignored");
+        assert!(parsed.tagged, "--tagged was ignored");
+
+        let parsed = Args::parse_with_key(&args(&["8080"]), Some("default".into())).unwrap();
+        assert_eq!(parsed.key.as_deref(), Some("default"), "Default key was not used");
+        assert!(!parsed.tagged, "Expected untagged without --tagged");
+        let parsed = Args::parse_with_key(&args(&["--key", "override"]), Some("default".into())).unwrap();
+        assert_eq!(parsed.key.as_deref(), Some("override"), "--key did not override the default key");
+        assert!(parsed.positional.is_empty(), "--key value was taken as positional");
+
+        let parsed = Args::parse_with_key(&args(&["fn", "--", "--tagged", "--key", "--"]), None).unwrap();
+        assert_eq!(parsed.positional, ["fn", "--tagged", "--key", "--"], "Arguments after -- were not positional");
+        assert!(!parsed.tagged && parsed.key.is_none(), "Options after -- were parsed");
+
+        assert!(Args::parse_with_key(&args(&["8080", "--key"]), None).is_err(), "--key without a value was accepted");
+    }
+
+    #[test]
+    fn decode_base64_test() {
+        let mut buffer = String::new();
+        socket::Packet::Message("hello".into()).dump_base64(&mut buffer).unwrap();
+        let untagged = Args::parse_with_key(&[], None).unwrap();
+        let decoded = decode_packet(&untagged, &format!(" \"{}\"\n", buffer)).unwrap();
+        assert!(decoded.contains("Message") && decoded.contains("\"hello\""), "Unexpected packet: {}", decoded);
+
+        let mut buffer = String::new();
+        socket::Tagged { id: 42, packet: socket::Packet::EventPoll(7) }.dump_base64(&mut buffer).unwrap();
+        let tagged = Args::parse_with_key(&args(&["--tagged"]), None).unwrap();
+        let decoded = decode_packet(&tagged, &buffer).unwrap();
+        assert!(decoded.contains("id: 42") && decoded.contains("EventPoll("), "Unexpected packet: {}", decoded);
+
+        let err = decode_packet(&untagged, "not base64!").expect_err("Invalid base64 was decoded");
+        assert!(err.starts_with("Failed to decode packet: "), "Unexpected error: {}", err);
+        assert!(decode_packet(&untagged, "").is_err(), "Empty packet was decoded");
+    }
+
+    #[cfg(feature = "encrypt")]
+    #[test]
+    fn decode_encrypted_test() {
+        use usdpl_core::handshake::{new_key_id, SessionKey};
+
+        const KEY: &str = "59C4E408F27250B3147E7724511824F1D28ED7BEF43CF7103ACE747F77A2B265";
+        let key = hex::decode(KEY).unwrap();
+        let with_key = Args::parse_with_key(&[], Some(KEY.into())).unwrap();
+
+        let mut buffer = Vec::new();
+        socket::Packet::Message("secret".into()).dump_encrypted(&mut buffer, &key).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        let decoded = decode_packet(&with_key, &data).unwrap();
+        assert!(decoded.contains("\"secret\""), "Unexpected packet: {}", decoded);
+        // captured packets are decoded repeatedly, which must not count as a replay
+        assert!(decode_packet(&with_key, &data).is_ok(), "Decoding the same packet again failed");
+
+        let session_key = SessionKey { id: new_key_id().unwrap(), key: key.clone() };
+        let mut buffer = Vec::new();
+        socket::Tagged { id: 3, packet: socket::Packet::Message("session".into()) }
+            .dump_encrypted_with_id(&mut buffer, &session_key)
+            .unwrap();
+        let tagged = Args::parse_with_key(&args(&["--tagged"]), Some(KEY.into())).unwrap();
+        let decoded = decode_packet(&tagged, std::str::from_utf8(&buffer).unwrap()).unwrap();
+        assert!(decoded.contains("id: 3") && decoded.contains("\"session\""), "Unexpected packet: {}", decoded);
+
+        let wrong_key = Args::parse_with_key(&[], Some("00".repeat(KEY_SIZE))).unwrap();
+        let err = decode_packet(&wrong_key, &data).expect_err("Packet was decrypted with the wrong key");
+        assert!(err.starts_with("Failed to decode packet: "), "Unexpected error: {}", err);
+        let invalid_key = Args::parse_with_key(&[], Some("not hex".into())).unwrap();
+        let err = decode_packet(&invalid_key, &data).expect_err("Invalid key was accepted");
+        assert!(err.starts_with("Invalid encryption key: "), "Unexpected error: {}", err);
+    }
 }
@@ -5,24 +5,48 @@ import os
 
 HOME_DIR = str(pathlib.Path(os.getcwd()).parent.parent.resolve())
 PARENT_DIR = str(pathlib.Path(__file__).parent.resolve())
+# how long get_port waits for the back-end to write its discovery file (in seconds)
+DISCOVERY_TIMEOUT = 10
+DISCOVERY_DELAY = 0.25
 
 class Plugin:
     backend_proc = None
     # Asyncio-compatible long-running code, executed in a task when the plugin is loaded
     async def _main(self):
         # startup
+        # a discovery file left by a back-end which was killed would point at the wrong port
+        runtime_dir = os.environ.get("DECKY_PLUGIN_RUNTIME_DIR")
+        if runtime_dir is not None:
+            for stale in pathlib.Path(runtime_dir).glob("usdpl-*.port"):
+                stale.unlink(missing_ok=True)
         self.backend_proc = subprocess.Popen(
             [PARENT_DIR + "/bin/backend"],
             env = dict(os.environ))
         while True:
             await asyncio.sleep(1)
 
+    # Port the back-end is listening on, which is a fallback port when the preferred one is taken.
+    # The back-end writes it to a discovery file once it's listening, so this waits for that while it starts.
+    async def get_port(self, preferred: int) -> int:
+        runtime_dir = os.environ.get("DECKY_PLUGIN_RUNTIME_DIR")
+        if runtime_dir is None:
+            return preferred
+        path = os.path.join(runtime_dir, "usdpl-{}.port".format(preferred))
+        for _ in range(int(DISCOVERY_TIMEOUT / DISCOVERY_DELAY)):
+            try:
+                with open(path) as f:
+                    return int(f.read())
+            except (OSError, ValueError):
+                await asyncio.sleep(DISCOVERY_DELAY)
+        return preferred
+
     async def _unload(self):
         # shutdown
         if self.backend_proc is not None:
+            # SIGTERM lets the back-end finish requests and run its shutdown hooks
             self.backend_proc.terminate()
             try:
-                self.backend_proc.wait(timeout=5) # 5 seconds timeout
+                self.backend_proc.wait(timeout=10) # longer than the back-end's grace period
             except subprocess.TimeoutExpired:
                 self.backend_proc.kill()
             self.backend_proc = None
@@ -108,7 +108,9 @@ export default definePlugin((serverApi: ServerAPI) => {
   // this is required to interface with the backend
   (async () => {
     await init_embedded();
-    init_usdpl(USDPL_PORT);
+    // the back-end may be listening on a fallback port, which get_port waits for the back-end to publish
+    const port = await serverApi.callPluginMethod<{ preferred: number }, number>("get_port", { preferred: USDPL_PORT });
+    init_usdpl(port.success ? port.result : USDPL_PORT);
     console.log("USDPL started for framework: " + target_usdpl());
   })();
 
@@ -25,3 +25,8 @@ pub fn home() -> Option<PathBuf> {
 pub fn log() -> Option<PathBuf> {
     Some(PathBuf::from("/tmp"))
 }
+
+/// The recommended directory for files which only matter while the plugin is running
+pub fn runtime() -> Option<PathBuf> {
+    std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from)
+}
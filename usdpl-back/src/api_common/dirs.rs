@@ -10,11 +10,11 @@ pub fn home() -> Option<PathBuf> {
     let result = None; // TODO
     #[cfg(all(feature = "decky", not(any(feature = "crankshaft"))))]
     let result = crate::api_decky::home().ok()
-        .map(|x| PathBuf::from(x)
+        .and_then(|x| PathBuf::from(x)
             .join("..")
             .canonicalize()
             .ok()
-        ).flatten();
+        );
 
     result
 }
@@ -42,3 +42,15 @@ pub fn log() -> Option<PathBuf> {
 
     result
 }
+
+/// The recommended directory for files which only matter while the plugin is running
+pub fn runtime() -> Option<PathBuf> {
+    #[cfg(not(any(feature = "decky", feature = "crankshaft")))]
+    let result = crate::api_any::dirs::runtime();
+    #[cfg(all(feature = "crankshaft", not(any(feature = "decky"))))]
+    let result = None; // TODO
+    #[cfg(all(feature = "decky", not(any(feature = "crankshaft"))))]
+    let result = crate::api_decky::runtime_dir().ok().map(|x| x.into());
+
+    result
+}
@@ -0,0 +1,124 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use super::Instance;
+
+/// Origin of the Steam client's UI, which Decky plugin front-ends run in
+pub const STEAM_ORIGIN: &str = "https://steamloopback.host";
+
+/// Default request size limit, 1 MiB
+const DEFAULT_MAX_REQUEST_SIZE: usize = 1 << 20;
+
+/// Web pages which may call the back-end from a browser
+#[derive(Clone, Debug)]
+pub(crate) enum Origins {
+    Any,
+    Only(Vec<String>),
+}
+
+impl Origins {
+    /// The `Access-Control-Allow-Origin` value for a request from this origin.
+    /// Fails when the origin is not allowed.
+    pub fn allow(&self, origin: Option<&str>) -> Result<Option<String>, ()> {
+        match (self, origin) {
+            (Self::Any, _) => Ok(Some("*".to_owned())),
+            // not sent by a browser, so it's not a web page
+            (Self::Only(_), None) => Ok(None),
+            (Self::Only(origins), Some(origin)) if origins.iter().any(|o| o == origin) => Ok(Some(origin.to_owned())),
+            (Self::Only(_), Some(_)) => Err(()),
+        }
+    }
+}
+
+/// How the instance serves requests
+#[derive(Clone, Debug)]
+pub(crate) struct ServerConfig {
+    pub address: IpAddr,
+    pub origins: Origins,
+    pub max_request_size: usize,
+    pub max_response_size: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            origins: Origins::Any,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            max_response_size: usize::MAX,
+        }
+    }
+}
+
+/// Builder for a back-end instance with non-default server settings
+pub struct InstanceBuilder {
+    port: u16,
+    config: ServerConfig,
+}
+
+impl InstanceBuilder {
+    /// Start building an instance of the back-end
+    pub fn new(port_usdpl: u16) -> Self {
+        Self {
+            port: port_usdpl,
+            config: ServerConfig::default(),
+        }
+    }
+
+    /// Listen on this address (127.0.0.1 by default, so only this device can connect).
+    /// Use 0.0.0.0 to allow remote access, e.g. for debugging from another device.
+    pub fn bind_address(mut self, address: IpAddr) -> Self {
+        self.config.address = address;
+        self
+    }
+
+    /// Only allow calls from web pages with these origins (any origin by default), e.g. `STEAM_ORIGIN`.
+    /// This can be used more than once to allow more origins.
+    /// Requests which don't come from a browser (without an `Origin` header) are always allowed.
+    pub fn allow_origin<S: Into<String>>(mut self, origin: S) -> Self {
+        match &mut self.config.origins {
+            Origins::Any => self.config.origins = Origins::Only(vec![origin.into()]),
+            Origins::Only(origins) => origins.push(origin.into()),
+        }
+        self
+    }
+
+    /// Allow calls from web pages with any origin (the default)
+    pub fn allow_any_origin(mut self) -> Self {
+        self.config.origins = Origins::Any;
+        self
+    }
+
+    /// Reject requests (and WebSocket messages) larger than this many bytes (1 MiB by default).
+    /// Each call in a larger request gets an error response, unless the request is more than 4 times larger.
+    /// Those HTTP requests are refused with status 413, and those WebSocket messages close the socket.
+    /// Strings and vectors in a request can't claim to be longer than the largest message either.
+    pub fn max_request_size(mut self, bytes: usize) -> Self {
+        self.config.max_request_size = bytes;
+        self
+    }
+
+    /// Respond with an error instead of responses larger than this many bytes (unlimited by default)
+    pub fn max_response_size(mut self, bytes: usize) -> Self {
+        self.config.max_response_size = bytes;
+        self
+    }
+
+    /// Build the instance, which can then be configured like one from `Instance::new`
+    pub fn build(self) -> Instance {
+        Instance::with_config(self.port, self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_test() {
+        assert_eq!(Origins::Any.allow(Some("http://example.com")), Ok(Some("*".to_owned())));
+        let only = InstanceBuilder::new(31337).allow_origin(STEAM_ORIGIN).config.origins;
+        assert_eq!(only.allow(Some(STEAM_ORIGIN)), Ok(Some(STEAM_ORIGIN.to_owned())));
+        assert_eq!(only.allow(Some("http://example.com")), Err(()), "Unlisted origin was allowed");
+        assert_eq!(only.allow(None), Ok(None), "Request without origin was rejected");
+    }
+}
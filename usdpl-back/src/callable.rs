@@ -1,11 +1,23 @@
-use std::sync::{Arc, Mutex};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::{JoinError, JoinHandle};
 
 use usdpl_core::serdes::Primitive;
+use usdpl_core::{CallError, ErrorCode};
+
+use super::CallContext;
 
 /// A mutable function which can be called from the front-end (remotely)
 pub trait MutCallable: Send + Sync {
     /// Invoke the function
     fn call(&mut self, params: Vec<Primitive>) -> Vec<Primitive>;
+
+    /// Restore a usable state after the function panicked (with `PanicPolicy::Reset`)
+    fn reset(&mut self) {}
 }
 
 impl<F: (FnMut(Vec<Primitive>) -> Vec<Primitive>) + Send + Sync> MutCallable for F {
@@ -40,27 +52,286 @@ impl<F: (Fn(Vec<Primitive>) -> A) + Send + Sync, A: core::future::Future<Output=
     }
 }
 
-pub enum WrappedCallable {
-    Blocking(Arc<Mutex<Box<dyn MutCallable>>>),
-    Ref(Arc<Box<dyn Callable>>),
-    Async(Arc<Box<dyn AsyncCallable>>),
+/// Result of a call which may fail
+pub(crate) type CallResult = Result<Vec<Primitive>, CallError>;
+
+/// A mutable function which can be called from the front-end (remotely) and may fail
+pub trait FallibleMutCallable: Send + Sync {
+    /// Invoke the function
+    fn call(&mut self, params: Vec<Primitive>) -> Result<Vec<Primitive>, CallError>;
+
+    /// Restore a usable state after the function panicked (with `PanicPolicy::Reset`)
+    fn reset(&mut self) {}
+}
+
+impl<F: (FnMut(Vec<Primitive>) -> Result<Vec<Primitive>, CallError>) + Send + Sync> FallibleMutCallable for F {
+    fn call(&mut self, params: Vec<Primitive>) -> Result<Vec<Primitive>, CallError> {
+        (self)(params)
+    }
+}
+
+/// A function which can be called from the front-end (remotely) and may fail
+pub trait FallibleCallable: Send + Sync {
+    /// Invoke the function
+    fn call(&self, params: Vec<Primitive>) -> Result<Vec<Primitive>, CallError>;
+}
+
+impl<F: (Fn(Vec<Primitive>) -> Result<Vec<Primitive>, CallError>) + Send + Sync> FallibleCallable for F {
+    fn call(&self, params: Vec<Primitive>) -> Result<Vec<Primitive>, CallError> {
+        (self)(params)
+    }
+}
+
+/// An async function which can be called from the front-end (remotely) and may fail
+#[async_trait::async_trait]
+pub trait FallibleAsyncCallable: Send + Sync {
+    /// Invoke the function
+    async fn call(&self, params: Vec<Primitive>) -> Result<Vec<Primitive>, CallError>;
+}
+
+#[async_trait::async_trait]
+impl<F: (Fn(Vec<Primitive>) -> A) + Send + Sync, A: core::future::Future<Output=Result<Vec<Primitive>, CallError>> + Send> FallibleAsyncCallable for F {
+    async fn call(&self, params: Vec<Primitive>) -> Result<Vec<Primitive>, CallError> {
+        (self)(params).await
+    }
+}
+
+/// Adapter for callables which always succeed
+struct Infallible<F>(F);
+
+impl<F: Callable> FallibleCallable for Infallible<F> {
+    fn call(&self, params: Vec<Primitive>) -> CallResult {
+        Ok(self.0.call(params))
+    }
+}
+
+impl<F: MutCallable> FallibleMutCallable for Infallible<F> {
+    fn call(&mut self, params: Vec<Primitive>) -> CallResult {
+        Ok(self.0.call(params))
+    }
+
+    fn reset(&mut self) {
+        self.0.reset()
+    }
+}
+
+#[async_trait::async_trait]
+impl<F: AsyncCallable> FallibleAsyncCallable for Infallible<F> {
+    async fn call(&self, params: Vec<Primitive>) -> CallResult {
+        Ok(self.0.call(params).await)
+    }
+}
+
+/// How many calls to a function may run at the same time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Concurrency {
+    /// One call at a time, the others wait their turn (without holding up other functions)
+    Serialized,
+    /// Any amount of calls at the same time
+    Unlimited,
+}
+
+/// What happens to a function after it panicked during a call.
+/// The call itself always fails with an `Internal` error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Keep calling the function, with its state as the panic left it
+    Recover,
+    /// Reset the state of a mutable function before it is called again (see `MutCallable::reset`)
+    Reset,
+    /// Fail every following call, until the function is registered again
+    Disable,
+}
+
+/// Most calls to a function which may run at the same time, even when unlimited
+const CALL_PERMITS: u32 = 1 << 24;
+
+/// A call which panicked, with the panic's message
+pub(crate) struct Panicked(pub String);
+
+enum CallableKind {
+    Blocking(Arc<Mutex<Box<dyn FallibleMutCallable>>>),
+    Ref(Arc<Box<dyn FallibleCallable>>),
+    Async(Arc<Box<dyn FallibleAsyncCallable>>),
+}
+
+/// Type-erased function, which runs sync functions on a blocking thread pool
+pub struct WrappedCallable {
+    kind: CallableKind,
+    concurrency: Concurrency,
+    /// Every running call holds one permit, a serialized call holds all of them.
+    /// Shared by all versions of the function, so changing its concurrency also waits for calls which are already running.
+    running: Arc<Semaphore>,
+    panic_policy: PanicPolicy,
+    /// Set when the function panicked with `PanicPolicy::Disable`
+    disabled: Arc<AtomicBool>,
+    /// Overrides the instance's timeout
+    timeout: Option<Duration>,
 }
 
 impl WrappedCallable {
+    fn new(kind: CallableKind) -> Self {
+        Self {
+            kind,
+            concurrency: Concurrency::Unlimited,
+            running: Arc::new(Semaphore::new(CALL_PERMITS as usize)),
+            panic_policy: PanicPolicy::Reset,
+            disabled: Arc::new(AtomicBool::new(false)),
+            timeout: None,
+        }
+    }
+
     pub fn new_ref<T: Callable + 'static>(callable: T) -> Self {
-        Self::Ref(Arc::new(Box::new(callable)))
+        Self::new(CallableKind::Ref(Arc::new(Box::new(Infallible(callable)))))
     }
 
     pub fn new_locking<T: MutCallable + 'static>(callable: T) -> Self {
-        Self::Blocking(Arc::new(Mutex::new(Box::new(callable))))
+        Self::new(CallableKind::Blocking(Arc::new(Mutex::new(Box::new(Infallible(callable))))))
     }
 
     pub fn new_async<T: AsyncCallable + 'static>(callable: T) -> Self {
-        Self::Async(Arc::new(Box::new(callable)))
+        Self::new(CallableKind::Async(Arc::new(Box::new(Infallible(callable)))))
+    }
+
+    pub fn new_fallible_ref<T: FallibleCallable + 'static>(callable: T) -> Self {
+        Self::new(CallableKind::Ref(Arc::new(Box::new(callable))))
+    }
+
+    pub fn new_fallible_locking<T: FallibleMutCallable + 'static>(callable: T) -> Self {
+        Self::new(CallableKind::Blocking(Arc::new(Mutex::new(Box::new(callable)))))
+    }
+
+    pub fn new_fallible_async<T: FallibleAsyncCallable + 'static>(callable: T) -> Self {
+        Self::new(CallableKind::Async(Arc::new(Box::new(callable))))
+    }
+
+    /// Limit how many calls may run at the same time.
+    /// Mutable functions are always serialized, since they can't be called while they're already running.
+    pub fn with_concurrency(&self, concurrency: Concurrency) -> Self {
+        Self {
+            concurrency,
+            ..self.clone()
+        }
+    }
+
+    /// Choose what happens to the function after it panicked
+    pub fn with_panic_policy(&self, panic_policy: PanicPolicy) -> Self {
+        Self {
+            panic_policy,
+            ..self.clone()
+        }
+    }
+
+    /// Give up on calls which take longer than this, instead of the instance's timeout
+    pub fn with_timeout(&self, timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            ..self.clone()
+        }
+    }
+
+    /// How long a call may take, if this overrides the instance's timeout
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Invoke the function, giving up once the context's deadline passes.
+    /// Async functions are cancelled then, but sync functions can't be and keep running in the background,
+    /// still counting towards the function's concurrency until they return.
+    pub async fn call(&self, context: CallContext, params: Vec<Primitive>) -> Result<CallResult, Panicked> {
+        match context.deadline() {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), self.call_until_done(context, params))
+                .await
+                .unwrap_or_else(|_| Ok(Err(CallError::new(ErrorCode::Timeout, "Function did not complete in time")))),
+            None => self.call_until_done(context, params).await,
+        }
+    }
+
+    /// Invoke the function, catching any panic so the function and the runtime stay usable
+    async fn call_until_done(&self, context: CallContext, params: Vec<Primitive>) -> Result<CallResult, Panicked> {
+        if self.disabled.load(Ordering::Acquire) {
+            return Ok(Err(CallError::new(ErrorCode::Internal, "Function is disabled since it panicked")));
+        }
+        // waiting for the lock doesn't block the runtime, unlike the functions themselves
+        let permits = match self.concurrency {
+            Concurrency::Serialized => CALL_PERMITS,
+            Concurrency::Unlimited => 1,
+        };
+        let permit = self.running.clone().acquire_many_owned(permits).await;
+        let result = match &self.kind {
+            CallableKind::Blocking(mut_callable) => {
+                let mut callable = mut_callable.clone().lock_owned().await;
+                let reset = self.panic_policy == PanicPolicy::Reset;
+                // the permit is released when the function returns, even if the call was given up on
+                run_blocking(move || context.scope_blocking(|| {
+                    let _permit = permit;
+                    match std::panic::catch_unwind(AssertUnwindSafe(|| callable.call(params))) {
+                        Ok(result) => result,
+                        Err(payload) => {
+                            // reset while still holding the lock, so no other call sees the broken state
+                            if reset {
+                                callable.reset();
+                            }
+                            std::panic::resume_unwind(payload)
+                        }
+                    }
+                })).await
+            },
+            CallableKind::Ref(callable) => {
+                let callable = callable.clone();
+                run_blocking(move || context.scope_blocking(|| {
+                    let _permit = permit;
+                    callable.call(params)
+                })).await
+            },
+            CallableKind::Async(async_callable) => {
+                // the task is aborted when the call is given up on, so the permit can be released with it
+                let _permit = permit;
+                let callable = async_callable.clone();
+                let mut task = AbortOnDrop(tokio::spawn(context.scope(async move { callable.call(params).await })));
+                joined((&mut task.0).await)
+            },
+        };
+        if result.is_err() && self.panic_policy == PanicPolicy::Disable {
+            self.disabled.store(true, Ordering::Release);
+        }
+        result
     }
 }
 
-impl Clone for WrappedCallable {
+/// Run a sync function on the blocking thread pool, so it doesn't hold up other calls
+async fn run_blocking<F: FnOnce() -> CallResult + Send + 'static>(f: F) -> Result<CallResult, Panicked> {
+    joined(tokio::task::spawn_blocking(f).await)
+}
+
+/// Cancels an async function when its call is given up on
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn joined(result: Result<CallResult, JoinError>) -> Result<CallResult, Panicked> {
+    match result {
+        Ok(result) => Ok(result),
+        Err(e) if e.is_panic() => {
+            let payload = e.into_panic();
+            let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                message.to_string()
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "unknown panic".to_owned()
+            };
+            Err(Panicked(message))
+        },
+        Err(e) => Ok(Err(CallError::new(ErrorCode::Internal, format!("Function did not complete: {}", e)))),
+    }
+}
+
+impl Clone for CallableKind {
     fn clone(&self) -> Self {
         match self {
             Self::Blocking(x) => Self::Blocking(x.clone()),
@@ -70,18 +341,15 @@ impl Clone for WrappedCallable {
     }
 }
 
-#[async_trait::async_trait]
-impl AsyncCallable for WrappedCallable {
-    async fn call(&self, params: Vec<Primitive>) -> Vec<Primitive> {
-        match self {
-            Self::Blocking(mut_callable) => {
-                mut_callable
-                    .lock()
-                    .expect("Failed to acquire mut_callable lock")
-                    .call(params)
-            },
-            Self::Ref(callable) => callable.call(params),
-            Self::Async(async_callable) => async_callable.call(params).await,
+impl Clone for WrappedCallable {
+    fn clone(&self) -> Self {
+        Self {
+            kind: self.kind.clone(),
+            concurrency: self.concurrency,
+            running: self.running.clone(),
+            panic_policy: self.panic_policy,
+            disabled: self.disabled.clone(),
+            timeout: self.timeout,
         }
     }
 }
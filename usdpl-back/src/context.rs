@@ -0,0 +1,83 @@
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static ASYNC_CONTEXT: CallContext;
+}
+
+thread_local! {
+    static BLOCKING_CONTEXT: RefCell<Option<CallContext>> = const { RefCell::new(None) };
+}
+
+/// Information about the call a function is handling.
+/// Long-running functions can check the deadline to stop early, since the front-end stops waiting then.
+#[derive(Clone, Debug)]
+pub struct CallContext {
+    id: u64,
+    function: String,
+    deadline: Option<Instant>,
+}
+
+impl CallContext {
+    pub(crate) fn new(id: u64, function: String, deadline: Option<Instant>) -> Self {
+        Self {
+            id,
+            function,
+            deadline,
+        }
+    }
+
+    /// The context of the call being handled by this function.
+    /// Returns None when not called (directly) by the back-end for a front-end call.
+    pub fn current() -> Option<Self> {
+        ASYNC_CONTEXT
+            .try_with(|context| context.clone())
+            .ok()
+            .or_else(|| BLOCKING_CONTEXT.with(|context| context.borrow().clone()))
+    }
+
+    /// The call id assigned by the front-end
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The called function's name
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+
+    /// When the call times out, if ever
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Time left until the call times out, if it ever does
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Whether the call timed out, so the front-end won't receive the result anymore
+    pub fn is_expired(&self) -> bool {
+        self.deadline.map(|deadline| deadline <= Instant::now()).unwrap_or(false)
+    }
+
+    /// Run an async function with this context
+    pub(crate) async fn scope<F: core::future::Future>(self, f: F) -> F::Output {
+        ASYNC_CONTEXT.scope(self, f).await
+    }
+
+    /// Run a sync function with this context, on the current thread
+    pub(crate) fn scope_blocking<R, F: FnOnce() -> R>(self, f: F) -> R {
+        let previous = BLOCKING_CONTEXT.with(|context| context.replace(Some(self)));
+        // restore even when f panics, since the thread is reused
+        struct Restore(Option<CallContext>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take();
+                BLOCKING_CONTEXT.with(|context| *context.borrow_mut() = previous);
+            }
+        }
+        let _restore = Restore(previous);
+        f()
+    }
+}
@@ -1,14 +1,35 @@
+/// Errors from running a back-end instance
 #[derive(Debug)]
 pub enum ServerError {
+    /// None of the ports could be listened on, with the error for the last port tried
+    Bind(u16, std::io::Error),
+    /// Other IO failure
     Io(std::io::Error),
 }
 
 impl std::fmt::Display for ServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Io(io) => (&io as &dyn std::fmt::Display).fmt(f),
+            Self::Bind(port, io) => write!(f, "ServerError: cannot listen on port {}: {}", port, io),
+            Self::Io(io) => write!(f, "ServerError: {}", io),
         }
     }
 }
 
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Bind(_, io) => Some(io),
+            Self::Io(io) => Some(io),
+        }
+    }
+}
+
+impl std::convert::From<std::io::Error> for ServerError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Result of running a back-end instance
 pub type ServerResult = Result<(), ServerError>;
@@ -0,0 +1,170 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use usdpl_core::serdes::Primitive;
+use usdpl_core::{socket, RemoteEvent, RemoteEvents};
+
+use super::lifecycle::ShutdownHandle;
+
+/// Maximum amount of past events kept for front-ends which are between polls
+const EVENT_BUFFER_SIZE: usize = 64;
+/// How long an event poll waits for new events before responding with a KeepAlive
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+struct EventBuffer {
+    /// Id of the oldest event in the buffer
+    first_id: u64,
+    events: VecDeque<RemoteEvent>,
+}
+
+/// Events emitted by the back-end, waiting to be polled by front-ends
+pub(crate) struct EventQueue {
+    buffer: Mutex<EventBuffer>,
+    last_id: watch::Sender<u64>,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        let (last_id, _) = watch::channel(0);
+        Self {
+            buffer: Mutex::new(EventBuffer {
+                first_id: 1,
+                events: VecDeque::with_capacity(EVENT_BUFFER_SIZE),
+            }),
+            last_id,
+        }
+    }
+
+    fn push(&self, event: RemoteEvent) {
+        let mut buffer = self.buffer.lock().expect("Failed to acquire event buffer lock");
+        if buffer.events.len() == EVENT_BUFFER_SIZE {
+            buffer.events.pop_front();
+            buffer.first_id += 1;
+        }
+        buffer.events.push_back(event);
+        let id = buffer.first_id + buffer.events.len() as u64 - 1;
+        // notify while still holding the lock, so ids are published in order
+        self.last_id.send_replace(id);
+    }
+
+    /// Events after `since` and the id of the latest event
+    fn collect(&self, since: u64) -> RemoteEvents {
+        let buffer = self.buffer.lock().expect("Failed to acquire event buffer lock");
+        let last_id = buffer.first_id + buffer.events.len() as u64 - 1;
+        let skip = since.saturating_add(1).saturating_sub(buffer.first_id) as usize;
+        RemoteEvents {
+            last_id,
+            events: buffer
+                .events
+                .iter()
+                .skip(skip)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Respond to an event poll, waiting for new events if there are none yet.
+    /// Stops waiting once the instance is asked to stop, so the poll doesn't hold up the shutdown.
+    pub async fn poll(&self, since: u64, shutdown: &ShutdownHandle) -> socket::Packet {
+        let mut receiver = self.last_id.subscribe();
+        let last_id = *receiver.borrow_and_update();
+        if since > last_id {
+            // new front-end (or restarted back-end), only tell it where to poll from
+            log::debug!("Event poll from {} (latest is {}), sending latest id", since, last_id);
+            return socket::Packet::Events(RemoteEvents {
+                last_id,
+                events: Vec::new(),
+            });
+        }
+        if since == last_id {
+            let waited = tokio::time::timeout(POLL_TIMEOUT, async {
+                while *receiver.borrow_and_update() <= since {
+                    if receiver.changed().await.is_err() {
+                        break;
+                    }
+                }
+            });
+            tokio::select! {
+                waited = waited => if waited.is_err() {
+                    return socket::Packet::KeepAlive;
+                },
+                _ = shutdown.wait() => return socket::Packet::KeepAlive,
+            }
+        }
+        socket::Packet::Events(self.collect(since))
+    }
+}
+
+/// Handle for pushing events to the front-end.
+/// This can be cloned and used from any thread.
+#[derive(Clone)]
+pub struct Emitter {
+    queue: Arc<EventQueue>,
+}
+
+impl Emitter {
+    pub(crate) fn new(queue: Arc<EventQueue>) -> Self {
+        Self { queue }
+    }
+
+    /// Send an event to every front-end listening to `topic`
+    pub fn emit<S: Into<String>>(&self, topic: S, data: Vec<Primitive>) {
+        let topic = topic.into();
+        log::debug!("Emitting USDPL event `{}` (data: {})", topic, data.len());
+        self.queue.push(RemoteEvent { topic, data });
+    }
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_poll_test() {
+        let queue = Arc::new(EventQueue::new());
+        let emitter = Emitter::new(queue.clone());
+        let shutdown = ShutdownHandle::new();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        let last_id = match runtime.block_on(queue.poll(u64::MAX, &shutdown)) {
+            socket::Packet::Events(events) => {
+                assert!(events.events.is_empty(), "First poll should not receive events");
+                events.last_id
+            },
+            _ => panic!("First poll did not respond with Events"),
+        };
+
+        emitter.emit("first", vec![]);
+        emitter.emit("second", vec![42u32.into()]);
+        match runtime.block_on(queue.poll(last_id, &shutdown)) {
+            socket::Packet::Events(events) => {
+                assert_eq!(events.last_id, last_id + 2, "Expected last id to follow emitted events");
+                let topics: Vec<_> = events.events.iter().map(|e| e.topic.as_str()).collect();
+                assert_eq!(topics, ["first", "second"], "Expected events in emitted order");
+            },
+            _ => panic!("Poll did not respond with Events"),
+        }
+
+        shutdown.shutdown();
+        let response = runtime.block_on(async {
+            tokio::time::timeout(Duration::from_secs(1), queue.poll(last_id + 2, &shutdown)).await
+        });
+        assert!(matches!(response, Ok(socket::Packet::KeepAlive)), "Poll did not stop waiting on shutdown");
+    }
+
+    #[test]
+    fn event_buffer_overflow_test() {
+        let queue = Arc::new(EventQueue::new());
+        let emitter = Emitter::new(queue.clone());
+        for i in 0..(EVENT_BUFFER_SIZE + 10) {
+            emitter.emit(i.to_string(), vec![]);
+        }
+        let events = queue.collect(1);
+        assert_eq!(events.last_id, (EVENT_BUFFER_SIZE + 10) as u64);
+        assert_eq!(events.events.len(), EVENT_BUFFER_SIZE, "Expected only buffered events");
+        assert_eq!(events.events[0].topic, "10", "Expected oldest events to be dropped");
+    }
+}
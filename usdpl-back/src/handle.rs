@@ -0,0 +1,154 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{Callable, MutCallable, AsyncCallable, Concurrency, PanicPolicy, WrappedCallable};
+use super::{FallibleCallable, FallibleMutCallable, FallibleAsyncCallable};
+use super::{TypedCallable, TypedMutCallable, TypedAsyncCallable};
+use super::events::{Emitter, EventQueue};
+use super::registry::Registry;
+use super::typed::Typed;
+
+/// Handle for changing the functions of a running back-end instance.
+/// This can be cloned and used from any thread, before or after the instance is running.
+#[derive(Clone)]
+pub struct InstanceHandle {
+    calls: Arc<Registry>,
+    events: Arc<EventQueue>,
+}
+
+impl InstanceHandle {
+    pub(crate) fn new(calls: Arc<Registry>, events: Arc<EventQueue>) -> Self {
+        Self { calls, events }
+    }
+
+    /// Get a handle for pushing events to the front-end.
+    pub fn emitter(&self) -> Emitter {
+        Emitter::new(self.events.clone())
+    }
+
+    /// Limit how many calls to the function may run at the same time (unlimited by default).
+    /// Returns whether a function with this name was registered.
+    pub fn set_concurrency(&self, name: &str, concurrency: Concurrency) -> bool {
+        self.calls.update(name, |callable| callable.with_concurrency(concurrency))
+    }
+
+    /// Choose what happens to the function after it panicked (`PanicPolicy::Reset` by default).
+    /// Returns whether a function with this name was registered.
+    pub fn set_panic_policy(&self, name: &str, panic_policy: PanicPolicy) -> bool {
+        self.calls.update(name, |callable| callable.with_panic_policy(panic_policy))
+    }
+
+    /// Give up on calls to the function which take longer than this, instead of using the instance's timeout.
+    /// Returns whether a function with this name was registered.
+    pub fn set_timeout(&self, name: &str, timeout: Option<Duration>) -> bool {
+        self.calls.update(name, |callable| callable.with_timeout(timeout))
+    }
+
+    /// Stop the function from being invoked by the front-end.
+    /// Returns whether a function with this name was registered.
+    pub fn unregister(&self, name: &str) -> bool {
+        self.calls.remove(name)
+    }
+
+    /// Register a thread-safe function which can be invoked by the front-end
+    pub fn register<S: std::convert::Into<String>, F: Callable + 'static>(
+        &self,
+        name: S,
+        f: F,
+    ) -> &Self {
+        self.calls
+            .insert(name.into(), WrappedCallable::new_ref(f));
+        self
+    }
+
+    /// Register a thread-unsafe function which can be invoked by the front-end
+    pub fn register_blocking<S: std::convert::Into<String>, F: MutCallable + 'static>(
+        &self,
+        name: S,
+        f: F,
+    ) -> &Self {
+        self.calls
+            .insert(name.into(), WrappedCallable::new_locking(f));
+        self
+    }
+
+    /// Register a thread-unsafe function which can be invoked by the front-end
+    pub fn register_async<S: std::convert::Into<String>, F: AsyncCallable + 'static>(
+        &self,
+        name: S,
+        f: F,
+    ) -> &Self {
+        self.calls
+            .insert(name.into(), WrappedCallable::new_async(f));
+        self
+    }
+
+    /// Register a thread-safe function which can be invoked by the front-end and may fail
+    pub fn register_fallible<S: std::convert::Into<String>, F: FallibleCallable + 'static>(
+        &self,
+        name: S,
+        f: F,
+    ) -> &Self {
+        self.calls
+            .insert(name.into(), WrappedCallable::new_fallible_ref(f));
+        self
+    }
+
+    /// Register a thread-unsafe function which can be invoked by the front-end and may fail
+    pub fn register_fallible_blocking<S: std::convert::Into<String>, F: FallibleMutCallable + 'static>(
+        &self,
+        name: S,
+        f: F,
+    ) -> &Self {
+        self.calls
+            .insert(name.into(), WrappedCallable::new_fallible_locking(f));
+        self
+    }
+
+    /// Register an async function which can be invoked by the front-end and may fail
+    pub fn register_fallible_async<S: std::convert::Into<String>, F: FallibleAsyncCallable + 'static>(
+        &self,
+        name: S,
+        f: F,
+    ) -> &Self {
+        self.calls
+            .insert(name.into(), WrappedCallable::new_fallible_async(f));
+        self
+    }
+
+    /// Register a thread-safe function with typed arguments and result which can be invoked by the front-end.
+    /// Parameters are converted with `TryFrom<Primitive>` and results with `Into<Primitive>`.
+    pub fn register_typed<S: std::convert::Into<String>, A: 'static, F: TypedCallable<A> + 'static>(
+        &self,
+        name: S,
+        f: F,
+    ) -> &Self {
+        self.calls
+            .insert(name.into(), WrappedCallable::new_fallible_ref(Typed::new(f)));
+        self
+    }
+
+    /// Register a thread-unsafe function with typed arguments and result which can be invoked by the front-end.
+    /// Parameters are converted with `TryFrom<Primitive>` and results with `Into<Primitive>`.
+    pub fn register_typed_blocking<S: std::convert::Into<String>, A: 'static, F: TypedMutCallable<A> + 'static>(
+        &self,
+        name: S,
+        f: F,
+    ) -> &Self {
+        self.calls
+            .insert(name.into(), WrappedCallable::new_fallible_locking(Typed::new(f)));
+        self
+    }
+
+    /// Register an async function with typed arguments and result which can be invoked by the front-end.
+    /// Parameters are converted with `TryFrom<Primitive>` and results with `Into<Primitive>`.
+    pub fn register_typed_async<S: std::convert::Into<String>, A: 'static, F: TypedAsyncCallable<A> + 'static>(
+        &self,
+        name: S,
+        f: F,
+    ) -> &Self {
+        self.calls
+            .insert(name.into(), WrappedCallable::new_fallible_async(Typed::new(f)));
+        self
+    }
+}
@@ -1,77 +1,326 @@
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use warp::Filter;
+use futures_util::{SinkExt, StreamExt};
+use warp::http::header::{self, HeaderValue};
+use warp::{Filter, Reply};
 
-use usdpl_core::serdes::{Dumpable, Loadable};
-use usdpl_core::{socket, RemoteCallResponse};
-
-use super::{Callable, MutCallable, AsyncCallable, WrappedCallable};
+use usdpl_core::serdes::{DumpError, Dumpable, LoadError, LoadLimits, Loadable};
+#[cfg(feature = "encrypt")]
+use usdpl_core::serdes::ReplayGuard;
+#[cfg(feature = "encrypt")]
+use super::session_keys::SessionKeys;
+use usdpl_core::{socket, CallError, ErrorCode, RemoteCallError, RemoteCallResponse};
 
-static LAST_ID: AtomicU64 = AtomicU64::new(0);
-const MAX_ID_DIFFERENCE: u64 = 32;
+use super::{InstanceBuilder, ServerError, ServerResult};
+use super::builder::ServerConfig;
+use super::{Callable, MutCallable, AsyncCallable, CallContext, Concurrency, InstanceHandle, PanicPolicy};
+use super::{FallibleCallable, FallibleMutCallable, FallibleAsyncCallable};
+use super::{TypedCallable, TypedMutCallable, TypedAsyncCallable};
+use super::events::{Emitter, EventQueue};
+use super::callable::Panicked;
+use super::lifecycle::{self, FirstClient, Hook, ShutdownHandle};
+use super::registry::Registry;
+use super::sessions::Sessions;
 
 //type WrappedCallable = Arc<Mutex<Box<dyn Callable>>>; // thread-safe, cloneable Callable
 
+/// Maximum amount of packets in a batch
+const MAX_BATCH_SIZE: usize = 64;
+/// Maximum amount of batches nested in each other
+const MAX_BATCH_DEPTH: usize = 4;
+/// How many times larger than the request size limit a request may be, to still get an error response
+const OVERSIZED_MESSAGE_FACTOR: usize = 4;
+/// WebSocket close code for a message which can't be decrypted (in the range for private use)
 #[cfg(feature = "encrypt")]
-const NONCE: [u8; socket::NONCE_SIZE] = [0u8; socket::NONCE_SIZE];
+const WS_CLOSE_UNDECRYPTABLE: u16 = 4001;
+/// Default time requests get to complete when the instance stops.
+/// The Decky template's main.py kills the back-end 10 seconds after asking it to stop.
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
 
 /// Back-end instance for interacting with the front-end
 pub struct Instance {
-    calls: HashMap<String, WrappedCallable>,
+    calls: Arc<Registry>,
+    events: Arc<EventQueue>,
+    sessions: Arc<Sessions>,
     port: u16,
+    config: Arc<ServerConfig>,
+    fallback_ports: Option<RangeInclusive<u16>>,
+    any_port: bool,
+    discovery_file: Option<PathBuf>,
+    timeout: Option<Duration>,
+    shutdown: ShutdownHandle,
+    grace_period: Duration,
+    on_start: Vec<Hook>,
+    on_first_client: Vec<Hook>,
+    on_shutdown: Vec<Hook>,
+    #[cfg(feature = "encrypt")]
+    encryption_key: Arc<Vec<u8>>,
     #[cfg(feature = "encrypt")]
-    encryption_key: Vec<u8>,
+    replay_guard: Arc<ReplayGuard>,
+    #[cfg(feature = "encrypt")]
+    session_keys: Arc<SessionKeys>,
+    #[cfg(feature = "encrypt")]
+    require_handshake: bool,
 }
 
 impl Instance {
-    /// Initialise an instance of the back-end
+    /// Initialise an instance of the back-end, with the default server settings (see `InstanceBuilder`)
     #[inline]
     pub fn new(port_usdpl: u16) -> Self {
+        Self::with_config(port_usdpl, ServerConfig::default())
+    }
+
+    /// Start building an instance of the back-end, to change its server settings
+    pub fn builder(port_usdpl: u16) -> InstanceBuilder {
+        InstanceBuilder::new(port_usdpl)
+    }
+
+    pub(crate) fn with_config(port_usdpl: u16, config: ServerConfig) -> Self {
         Instance {
-            calls: HashMap::new(),
+            calls: Arc::new(Registry::new()),
+            events: Arc::new(EventQueue::new()),
+            sessions: Arc::new(Sessions::new()),
             port: port_usdpl,
+            config: Arc::new(config),
+            fallback_ports: None,
+            any_port: false,
+            discovery_file: None,
+            timeout: None,
+            shutdown: ShutdownHandle::new(),
+            grace_period: GRACE_PERIOD,
+            on_start: Vec::new(),
+            on_first_client: Vec::new(),
+            on_shutdown: Vec::new(),
+            #[cfg(feature = "encrypt")]
+            encryption_key: Arc::new(hex::decode(obfstr::obfstr!(env!("USDPL_ENCRYPTION_KEY"))).unwrap()),
+            #[cfg(feature = "encrypt")]
+            replay_guard: Arc::new(ReplayGuard::default()),
+            #[cfg(feature = "encrypt")]
+            session_keys: Arc::new(SessionKeys::new()),
             #[cfg(feature = "encrypt")]
-            encryption_key: hex::decode(obfstr::obfstr!(env!("USDPL_ENCRYPTION_KEY"))).unwrap(),
+            require_handshake: false,
+        }
+    }
+
+    /// Only accept calls encrypted with a session key, agreed on through a handshake.
+    /// The compile-time key is then only used to authenticate handshakes.
+    #[cfg(feature = "encrypt")]
+    pub fn require_handshake(mut self) -> Self {
+        self.require_handshake = true;
+        self
+    }
+
+    /// Get a handle for pushing events to the front-end.
+    /// The handle can be used from any thread, before or after the instance is running.
+    pub fn emitter(&self) -> Emitter {
+        Emitter::new(self.events.clone())
+    }
+
+    /// Get a handle for (un)registering functions.
+    /// The handle can be used from any thread, before or after the instance is running.
+    pub fn handle(&self) -> InstanceHandle {
+        InstanceHandle::new(self.calls.clone(), self.events.clone())
+    }
+
+    /// Listen on the first available port in this range when the instance's port is taken.
+    /// The port which is used is written to the discovery file.
+    pub fn with_fallback_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.fallback_ports = Some(ports);
+        self
+    }
+
+    /// Listen on a port picked by the operating system when the instance's (and fallback) ports are taken.
+    /// The port which is used is written to the discovery file.
+    pub fn with_any_port(mut self) -> Self {
+        self.any_port = true;
+        self
+    }
+
+    /// Write the port the instance listens on to this file, instead of `usdpl-<port>.port` in the runtime directory.
+    /// The file is removed when the instance stops.
+    pub fn with_discovery_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.discovery_file = Some(path.into());
+        self
+    }
+
+    /// Get a handle for stopping the instance, like SIGTERM or SIGINT does.
+    /// The handle can be used from any thread, before or after the instance is running.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// How long requests which are being handled get to complete when the instance stops (5 seconds by default).
+    /// The shutdown hooks run after that, and always run to completion.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Run an async function when the instance starts, before it accepts requests
+    pub fn on_start<F, A>(mut self, f: F) -> Self
+    where
+        F: Fn() -> A + Send + Sync + 'static,
+        A: core::future::Future<Output = ()> + Send + 'static,
+    {
+        self.on_start.push(lifecycle::hook(f));
+        self
+    }
+
+    /// Run an async function before the first request from a front-end is handled
+    pub fn on_first_client<F, A>(mut self, f: F) -> Self
+    where
+        F: Fn() -> A + Send + Sync + 'static,
+        A: core::future::Future<Output = ()> + Send + 'static,
+    {
+        self.on_first_client.push(lifecycle::hook(f));
+        self
+    }
+
+    /// Run an async function when the instance stops (e.g. to save settings), before `run` returns
+    pub fn on_shutdown<F, A>(mut self, f: F) -> Self
+    where
+        F: Fn() -> A + Send + Sync + 'static,
+        A: core::future::Future<Output = ()> + Send + 'static,
+    {
+        self.on_shutdown.push(lifecycle::hook(f));
+        self
+    }
+
+    /// Give up on calls which take longer than this, responding with a `Timeout` error (no timeout by default).
+    /// Async functions are cancelled then, while sync functions keep running in the background;
+    /// functions can check `CallContext::current()` to stop early.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on calls to a registered function which take longer than this, instead of using the instance's timeout
+    pub fn with_function_timeout(self, name: &str, timeout: Duration) -> Self {
+        if !self.handle().set_timeout(name, Some(timeout)) {
+            log::warn!("Cannot set timeout of unregistered function `{}`", name);
+        }
+        self
+    }
+
+    /// Limit how many calls to a registered function may run at the same time (unlimited by default).
+    /// Sync functions run on a blocking thread pool, so waiting for them doesn't hold up other functions.
+    pub fn with_concurrency(self, name: &str, concurrency: Concurrency) -> Self {
+        if !self.handle().set_concurrency(name, concurrency) {
+            log::warn!("Cannot set concurrency of unregistered function `{}`", name);
         }
+        self
+    }
+
+    /// Choose what happens to a registered function after it panicked (`PanicPolicy::Reset` by default).
+    /// A panicking call always fails with an `Internal` error, without affecting other calls.
+    pub fn with_panic_policy(self, name: &str, panic_policy: PanicPolicy) -> Self {
+        if !self.handle().set_panic_policy(name, panic_policy) {
+            log::warn!("Cannot set panic policy of unregistered function `{}`", name);
+        }
+        self
     }
 
     /// Register a thread-safe function which can be invoked by the front-end
     pub fn register<S: std::convert::Into<String>, F: Callable + 'static>(
-        mut self,
+        self,
         name: S,
         f: F,
     ) -> Self {
-        self.calls
-            .insert(name.into(), WrappedCallable::new_ref(f));
+        self.handle().register(name, f);
         self
     }
 
     /// Register a thread-unsafe function which can be invoked by the front-end
     pub fn register_blocking<S: std::convert::Into<String>, F: MutCallable + 'static>(
-        mut self,
+        self,
         name: S,
         f: F,
     ) -> Self {
-        self.calls
-            .insert(name.into(), WrappedCallable::new_locking(f));
+        self.handle().register_blocking(name, f);
         self
     }
 
     /// Register a thread-unsafe function which can be invoked by the front-end
     pub fn register_async<S: std::convert::Into<String>, F: AsyncCallable + 'static>(
-        mut self,
+        self,
+        name: S,
+        f: F,
+    ) -> Self {
+        self.handle().register_async(name, f);
+        self
+    }
+
+    /// Register a thread-safe function which can be invoked by the front-end and may fail
+    pub fn register_fallible<S: std::convert::Into<String>, F: FallibleCallable + 'static>(
+        self,
+        name: S,
+        f: F,
+    ) -> Self {
+        self.handle().register_fallible(name, f);
+        self
+    }
+
+    /// Register a thread-unsafe function which can be invoked by the front-end and may fail
+    pub fn register_fallible_blocking<S: std::convert::Into<String>, F: FallibleMutCallable + 'static>(
+        self,
+        name: S,
+        f: F,
+    ) -> Self {
+        self.handle().register_fallible_blocking(name, f);
+        self
+    }
+
+    /// Register an async function which can be invoked by the front-end and may fail
+    pub fn register_fallible_async<S: std::convert::Into<String>, F: FallibleAsyncCallable + 'static>(
+        self,
         name: S,
         f: F,
     ) -> Self {
-        self.calls
-            .insert(name.into(), WrappedCallable::new_async(f));
+        self.handle().register_fallible_async(name, f);
         self
     }
 
-    /// Run the web server instance forever, blocking this thread
+    /// Register a thread-safe function with typed arguments and result which can be invoked by the front-end.
+    /// Parameters are converted with `TryFrom<Primitive>` and results with `Into<Primitive>`.
+    pub fn register_typed<S: std::convert::Into<String>, A: 'static, F: TypedCallable<A> + 'static>(
+        self,
+        name: S,
+        f: F,
+    ) -> Self {
+        self.handle().register_typed(name, f);
+        self
+    }
+
+    /// Register a thread-unsafe function with typed arguments and result which can be invoked by the front-end.
+    /// Parameters are converted with `TryFrom<Primitive>` and results with `Into<Primitive>`.
+    pub fn register_typed_blocking<S: std::convert::Into<String>, A: 'static, F: TypedMutCallable<A> + 'static>(
+        self,
+        name: S,
+        f: F,
+    ) -> Self {
+        self.handle().register_typed_blocking(name, f);
+        self
+    }
+
+    /// Register an async function with typed arguments and result which can be invoked by the front-end.
+    /// Parameters are converted with `TryFrom<Primitive>` and results with `Into<Primitive>`.
+    pub fn register_typed_async<S: std::convert::Into<String>, A: 'static, F: TypedAsyncCallable<A> + 'static>(
+        self,
+        name: S,
+        f: F,
+    ) -> Self {
+        self.handle().register_typed_async(name, f);
+        self
+    }
+
+    /// Run the web server instance until it is stopped, blocking this thread.
+    /// The instance stops on SIGTERM, SIGINT or through its shutdown handle.
     #[cfg(feature = "blocking")]
-    pub fn run_blocking(&self) -> Result<(), ()> {
+    pub fn run_blocking(&self) -> ServerResult {
         let result = self.serve_internal();
         tokio::runtime::Builder::new_multi_thread()
             .enable_all()
@@ -80,154 +329,604 @@ impl Instance {
             .block_on(result)
     }
 
-    /// Run the web server forever, asynchronously
-    pub async fn run(&self) -> Result<(), ()> {
+    /// Run the web server until it is stopped, asynchronously.
+    /// The instance stops on SIGTERM, SIGINT or through its shutdown handle.
+    pub async fn run(&self) -> ServerResult {
         self.serve_internal().await
     }
 
-    #[async_recursion::async_recursion]
-    async fn handle_call(
-        packet: socket::Packet,
-        handlers: &HashMap<String, WrappedCallable>,
-    ) -> socket::Packet {
-        match packet {
-            socket::Packet::Call(call) => {
-                log::debug!("Got USDPL call {} (`{}`, params: {})", call.id, call.function, call.parameters.len());
-                let last_id = LAST_ID.load(Ordering::SeqCst);
-                if last_id == 0 {
-                    log::info!("Last id is 0, assuming resumed connection (overriding last id)");
-                    LAST_ID.store(call.id, Ordering::SeqCst);
-                } else if call.id < MAX_ID_DIFFERENCE {
-                    log::info!("Call ID is low, assuming new connection (resetting last id)");
-                    LAST_ID.store(call.id, Ordering::SeqCst);
-                } else if call.id > last_id && call.id - last_id < MAX_ID_DIFFERENCE {
-                    LAST_ID.store(call.id, Ordering::SeqCst);
-                } else if call.id < last_id && last_id - call.id < MAX_ID_DIFFERENCE {
-                    // Allowed, but don't store new (lower) LAST_ID
-                } else {
-                    #[cfg(not(debug_assertions))]
-                    {
-                        log::error!("Got USDPL call with strange ID! got:{} last id:{} (rejecting packet)", call.id, last_id);
-                        return socket::Packet::Invalid
-                    }
-                    #[cfg(debug_assertions)]
-                    log::warn!("Got USDPL call with strange ID! got:{} last id:{} (in release mode this packet will be rejected)", call.id, last_id);
-                }
-                //let handlers = CALLS.lock().expect("Failed to acquire CALLS lock");
-                if let Some(target) = handlers.get(&call.function) {
-                    let result = target.call(call.parameters).await;
-                    socket::Packet::CallResponse(RemoteCallResponse {
-                        id: call.id,
-                        response: result,
-                    })
-                } else {
-                    socket::Packet::Invalid
-                }
-            },
-            socket::Packet::Many(packets) => {
-                let mut result = Vec::with_capacity(packets.len());
-                for packet in packets {
-                    result.push(Self::handle_call(packet, handlers).await);
-                }
-                socket::Packet::Many(result)
-            },
-            #[cfg(feature = "translate")]
-            socket::Packet::Language(lang) => socket::Packet::Translations(get_all_translations(lang)),
-            _ => socket::Packet::Invalid,
-        }
-    }
-
-    #[cfg(not(feature = "encrypt"))]
-    async fn process_body((data, handlers): (bytes::Bytes, HashMap<String, WrappedCallable>)) -> impl warp::Reply {
-        let (packet, _) = match socket::Packet::load_base64(&data) {
+    /// Handle a request body, which is raw bytes when `binary` and base64 otherwise.
+    /// The response is raw bytes when the request was, or when the front-end accepts them.
+    async fn process_body((data, state): (bytes::Bytes, ServerState), binary: bool, accept_binary: bool) -> impl warp::Reply {
+        let (packet, mut encoding): (socket::Packet, _) = match state.load(&data, binary) {
             Ok(x) => x,
             Err(e) => {
                 return warp::reply::with_status(
                     warp::http::Response::builder()
-                        .body(format!("Failed to load packet: {}", e)),
+                        .body(format!("Failed to load packet: {}", e).into_bytes()),
                     warp::http::StatusCode::from_u16(400).unwrap(),
                 )
             }
         };
-        //let mut buffer = [0u8; socket::PACKET_BUFFER_SIZE];
-        let mut buffer = String::with_capacity(socket::PACKET_BUFFER_SIZE);
-        let response = Self::handle_call(packet, &handlers).await;
-        let _len = match response.dump_base64(&mut buffer) {
+        encoding.binary |= accept_binary;
+        let response = match data.len() {
+            len if len > state.config.max_request_size => state.oversized_request(packet, len),
+            _ => state.respond(packet, &encoding).await,
+        };
+        let dumped = state.dump(&response, &encoding).and_then(|buffer| match buffer.len() {
+            len if len > state.config.max_response_size => state.dump(&state.oversized(response, len), &encoding),
+            _ => Ok(buffer),
+        });
+        let buffer = match dumped {
             Ok(x) => x,
             Err(e) => {
                 return warp::reply::with_status(
                     warp::http::Response::builder()
-                        .body(format!("Failed to dump response packet: {}", e)),
+                        .body(format!("Failed to dump response packet: {}", e).into_bytes()),
                     warp::http::StatusCode::from_u16(500).unwrap(),
                 )
             }
         };
+        let mut response = warp::http::Response::builder();
+        if encoding.binary {
+            response = response.header(header::CONTENT_TYPE, socket::BINARY_CONTENT_TYPE);
+        }
         warp::reply::with_status(
-            warp::http::Response::builder().body(buffer),
+            response.body(buffer),
             warp::http::StatusCode::from_u16(200).unwrap(),
         )
     }
 
-    #[cfg(feature = "encrypt")]
-    async fn process_body((data, handlers, key): (bytes::Bytes, HashMap<String, WrappedCallable>, Vec<u8>)) -> impl warp::Reply {
-        let (packet, _) = match socket::Packet::load_encrypted(&data, &key, &NONCE) {
-            Ok(x) => x,
-            Err(_) => {
-                return warp::reply::with_status(
-                    warp::http::Response::builder()
-                        .body("Failed to load packet".to_string()),
-                    warp::http::StatusCode::from_u16(400).unwrap(),
-                )
+    /// Handle requests from a WebSocket connection until it is closed, or the instance is asked to stop.
+    /// Requests are handled concurrently, so responses may be sent out of order (they are tagged with the request id).
+    async fn process_socket(socket: warp::ws::WebSocket, state: ServerState) {
+        log::debug!("USDPL WebSocket connected");
+        let (mut sink, mut stream) = socket.split();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<warp::ws::Message>();
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if let Err(e) = sink.send(message).await {
+                    log::debug!("Failed to send WebSocket message: {}", e);
+                    return;
+                }
             }
-        };
-        let mut buffer = Vec::with_capacity(socket::PACKET_BUFFER_SIZE);
-        //buffer.extend(&[0u8; socket::PACKET_BUFFER_SIZE]);
-        let response = Self::handle_call(packet, &handlers).await;
-        let len = match response.dump_encrypted(&mut buffer, &key, &NONCE) {
-            Ok(x) => x,
-            Err(_) => {
-                return warp::reply::with_status(
-                    warp::http::Response::builder()
-                        .body("Failed to dump response packet".to_string()),
-                    warp::http::StatusCode::from_u16(500).unwrap(),
-                )
+            // every request got its response, so the connection can be closed
+            sink.send(warp::ws::Message::close()).await.ok();
+            sink.close().await.ok();
+        });
+        let shutdown = state.shutdown.clone();
+        loop {
+            let message = tokio::select! {
+                message = stream.next() => message,
+                _ = shutdown.wait() => {
+                    log::debug!("Closing USDPL WebSocket for shutdown");
+                    break;
+                },
+            };
+            let message = match message {
+                Some(x) => x,
+                None => break,
+            };
+            let message = match message {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("USDPL WebSocket error: {}", e);
+                    break;
+                }
+            };
+            if message.is_close() {
+                break;
+            } else if !message.is_text() && !message.is_binary() {
+                continue;
             }
-        };
-        buffer.truncate(len);
-        let string: String = String::from_utf8(buffer).unwrap().into();
-        warp::reply::with_status(
-            warp::http::Response::builder().body(string),
-            warp::http::StatusCode::from_u16(200).unwrap(),
-        )
+            let state = state.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                // responses are sent the same way as the request, so both kinds can be mixed
+                let (request, encoding): (socket::Tagged, _) = match state.load(message.as_bytes(), message.is_binary()) {
+                    Ok(x) => x,
+                    #[cfg(feature = "encrypt")]
+                    Err(LoadError::DecryptionError) => {
+                        // the request id is encrypted too, so the front-end can't be told which request failed.
+                        // Closing the socket fails its requests, so it can do a new handshake (e.g. after its session key timed out).
+                        log::warn!("Failed to decrypt WebSocket packet, closing socket");
+                        sender.send(warp::ws::Message::close_with(WS_CLOSE_UNDECRYPTABLE, "Cannot decrypt message")).ok();
+                        return;
+                    },
+                    Err(e) => {
+                        log::warn!("Failed to load WebSocket packet: {}", e);
+                        return;
+                    }
+                };
+                let len = message.as_bytes().len();
+                let response = socket::Tagged {
+                    id: request.id,
+                    packet: match len > state.config.max_request_size {
+                        true => state.oversized_request(request.packet, len),
+                        false => state.respond(request.packet, &encoding).await,
+                    },
+                };
+                let dumped = state.dump(&response, &encoding).and_then(|buffer| match buffer.len() {
+                    len if len > state.config.max_response_size => state.dump(&socket::Tagged {
+                        id: response.id,
+                        packet: state.oversized(response.packet, len),
+                    }, &encoding),
+                    _ => Ok(buffer),
+                });
+                match dumped {
+                    Ok(buffer) => {
+                        let message = match encoding.binary {
+                            true => warp::ws::Message::binary(buffer),
+                            // base64 is always valid UTF-8
+                            false => warp::ws::Message::text(String::from_utf8(buffer).unwrap()),
+                        };
+                        // the connection closing is handled by the receiving loop
+                        sender.send(message).ok();
+                    },
+                    Err(e) => log::error!("Failed to dump WebSocket response packet: {}", e),
+                }
+            });
+        }
+        log::debug!("USDPL WebSocket disconnected");
     }
 
-    /// Receive and execute callbacks forever
-    async fn serve_internal(&self) -> Result<(), ()> {
-        let handlers = self.calls.clone();
-        #[cfg(not(feature = "encrypt"))]
-        let input_mapper = move |data: bytes::Bytes| { (data, handlers.clone()) };
-        #[cfg(feature = "encrypt")]
-        let key = self.encryption_key.clone();
-        #[cfg(feature = "encrypt")]
-        let input_mapper = move |data: bytes::Bytes| { (data, handlers.clone(), key.clone()) };
+    fn state(&self) -> ServerState {
+        ServerState {
+            handlers: self.calls.clone(),
+            events: self.events.clone(),
+            sessions: self.sessions.clone(),
+            config: self.config.clone(),
+            timeout: self.timeout,
+            first_client: Arc::new(FirstClient::new(self.on_first_client.clone())),
+            shutdown: self.shutdown.clone(),
+            #[cfg(feature = "encrypt")]
+            key: self.encryption_key.clone(),
+            #[cfg(feature = "encrypt")]
+            replay_guard: self.replay_guard.clone(),
+            #[cfg(feature = "encrypt")]
+            session_keys: self.session_keys.clone(),
+            #[cfg(feature = "encrypt")]
+            require_handshake: self.require_handshake,
+        }
+    }
+
+    /// HTTP and WebSocket routes for the front-end
+    fn routes(&self) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let state = self.state();
+        let call_state = state.clone();
         //self.calls = HashMap::new();
         let calls = warp::post()
             .and(warp::path!("usdpl" / "call"))
+            .and(warp::header::optional::<String>("origin"))
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::header::optional::<String>("accept"))
+            // somewhat larger requests are still read, to respond with errors like the WebSocket does
             .and(warp::body::content_length_limit(
-                (socket::PACKET_BUFFER_SIZE * 2) as _,
+                self.config.max_request_size.saturating_mul(OVERSIZED_MESSAGE_FACTOR) as _
             ))
             .and(warp::body::bytes())
-            .map(input_mapper)
-            .then(Self::process_body)
-            .map(|reply| warp::reply::with_header(reply, "Access-Control-Allow-Origin", "*"));
-        #[cfg(debug_assertions)]
-        warp::serve(calls).run(([0, 0, 0, 0], self.port)).await;
-        #[cfg(not(debug_assertions))]
-        warp::serve(calls).run(([127, 0, 0, 1], self.port)).await;
+            .then(move |origin: Option<String>, content_type: Option<String>, accept: Option<String>, data: bytes::Bytes| {
+                let state = call_state.clone();
+                async move {
+                    let allow_origin = match state.config.origins.allow(origin.as_deref()) {
+                        Ok(x) => x,
+                        Err(()) => return Self::forbidden(origin),
+                    };
+                    let binary = content_type.map(|x| x.starts_with(socket::BINARY_CONTENT_TYPE)).unwrap_or(false);
+                    let accept_binary = accept.map(|x| x.contains(socket::BINARY_CONTENT_TYPE)).unwrap_or(false);
+                    let response = Self::process_body((data, state), binary, accept_binary).await.into_response();
+                    Self::allow_origin(response, allow_origin)
+                }
+            });
+        let preflight_state = state.clone();
+        // binary requests aren't "simple" requests, so browsers ask whether they may send them first
+        let preflight = warp::options()
+            .and(warp::path!("usdpl" / "call"))
+            .and(warp::header::optional::<String>("origin"))
+            .map(move |origin: Option<String>| {
+                let allow_origin = match preflight_state.config.origins.allow(origin.as_deref()) {
+                    Ok(x) => x,
+                    Err(()) => return Self::forbidden(origin),
+                };
+                let mut response = warp::http::StatusCode::NO_CONTENT.into_response();
+                let headers = response.headers_mut();
+                headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("POST"));
+                headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("content-type"));
+                Self::allow_origin(response, allow_origin)
+            });
+        let sockets = warp::path!("usdpl" / "ws")
+            .and(warp::header::optional::<String>("origin"))
+            .and(warp::header::optional::<String>("sec-websocket-protocol"))
+            .and(warp::ws())
+            .map(move |origin: Option<String>, protocols: Option<String>, ws: warp::ws::Ws| {
+                // browsers don't apply CORS to WebSockets, so this is the only check
+                if state.config.origins.allow(origin.as_deref()).is_err() {
+                    return Self::forbidden(origin);
+                }
+                let state = state.clone();
+                // somewhat larger messages are still read, to respond with an error instead of closing the socket
+                let mut response = ws.max_message_size(state.config.max_request_size.saturating_mul(OVERSIZED_MESSAGE_FACTOR))
+                    .on_upgrade(move |socket| Self::process_socket(socket, state))
+                    .into_response();
+                // tell the front-end it may send binary messages
+                if protocols.map(|x| x.split(',').any(|p| p.trim() == socket::BINARY_PROTOCOL)).unwrap_or(false) {
+                    response.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(socket::BINARY_PROTOCOL));
+                }
+                response
+            });
+        calls.or(preflight).or(sockets)
+    }
+
+    /// Add CORS headers for an allowed origin to the response
+    fn allow_origin(mut response: warp::reply::Response, allow_origin: Option<String>) -> warp::reply::Response {
+        if let Some(value) = allow_origin.and_then(|x| HeaderValue::from_str(&x).ok()) {
+            if value != "*" {
+                response.headers_mut().insert(header::VARY, HeaderValue::from_static("Origin"));
+            }
+            response.headers_mut().insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        }
+        response
+    }
+
+    fn forbidden(origin: Option<String>) -> warp::reply::Response {
+        log::warn!("Rejecting USDPL request from origin {:?}", origin);
+        warp::reply::with_status(
+            format!("Origin {} is not allowed", origin.unwrap_or_default()),
+            warp::http::StatusCode::FORBIDDEN,
+        ).into_response()
+    }
+
+    /// Listen on the first available port, preferring the instance's port
+    async fn bind(&self) -> Result<tokio::net::TcpListener, ServerError> {
+        let host = self.config.address;
+        let fallback_ports = self.fallback_ports
+            .clone()
+            .into_iter()
+            .flatten()
+            .filter(|port| *port != self.port)
+            .chain(self.any_port.then_some(0));
+        let mut result = tokio::net::TcpListener::bind(SocketAddr::from((host, self.port)))
+            .await
+            .map_err(|e| ServerError::Bind(self.port, e));
+        for port in fallback_ports {
+            match result {
+                Ok(listener) => return Ok(listener),
+                Err(e) => log::warn!("{}, trying next port", e),
+            }
+            result = tokio::net::TcpListener::bind(SocketAddr::from((host, port)))
+                .await
+                .map_err(|e| ServerError::Bind(port, e));
+        }
+        result
+    }
+
+    /// Receive and execute callbacks until stopped
+    async fn serve_internal(&self) -> ServerResult {
+        let listener = self.bind().await?;
+        let port = listener.local_addr()?.port();
+        log::info!("USDPL back-end listening on port {}", port);
+        let discovery_file = self.discovery_file
+            .clone()
+            .or_else(|| crate::api::dirs::runtime().map(|dir| dir.join(format!("usdpl-{}.port", self.port))));
+        if let Some(path) = &discovery_file {
+            if let Err(e) = std::fs::write(path, port.to_string()) {
+                log::warn!("Failed to write USDPL discovery file {}: {}", path.display(), e);
+            }
+        }
+
+        lifecycle::run_hooks(&self.on_start).await;
+        let signal_shutdown = self.shutdown.clone();
+        let signals = tokio::spawn(async move {
+            lifecycle::signal().await;
+            signal_shutdown.shutdown();
+        });
+
+        let routes = self.routes();
+        let incoming = futures_util::stream::unfold(listener, |listener| async move {
+            loop {
+                match listener.accept().await {
+                    Ok((connection, _)) => return Some((Ok::<_, std::io::Error>(connection), listener)),
+                    Err(e) => {
+                        // e.g. too many open files, which may resolve itself
+                        log::warn!("Failed to accept USDPL connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        });
+        let server_shutdown = self.shutdown.clone();
+        let server = warp::serve(routes)
+            .serve_incoming_with_graceful_shutdown(incoming, async move { server_shutdown.wait().await });
+        // the server stops accepting requests once shutdown starts, then waits for the ones being handled
+        let grace_period = async {
+            self.shutdown.wait().await;
+            tokio::time::sleep(self.grace_period).await;
+        };
+        tokio::select! {
+            _ = server => {},
+            _ = grace_period => log::warn!("USDPL requests did not complete within {:?}, stopping anyway", self.grace_period),
+        }
+        signals.abort();
+
+        log::info!("Stopping USDPL back-end");
+        if let Some(path) = &discovery_file {
+            std::fs::remove_file(path).ok();
+        }
+        lifecycle::run_hooks(&self.on_shutdown).await;
         Ok(())
     }
 }
 
+/// Everything needed to handle requests, shared by all connections
+#[derive(Clone)]
+struct ServerState {
+    handlers: Arc<Registry>,
+    events: Arc<EventQueue>,
+    sessions: Arc<Sessions>,
+    config: Arc<ServerConfig>,
+    timeout: Option<Duration>,
+    first_client: Arc<FirstClient>,
+    shutdown: ShutdownHandle,
+    #[cfg(feature = "encrypt")]
+    key: Arc<Vec<u8>>,
+    #[cfg(feature = "encrypt")]
+    replay_guard: Arc<ReplayGuard>,
+    #[cfg(feature = "encrypt")]
+    session_keys: Arc<SessionKeys>,
+    #[cfg(feature = "encrypt")]
+    require_handshake: bool,
+}
+
+/// How a request was encoded, which its response is encoded with too
+struct Encoding {
+    /// Raw bytes instead of base64
+    binary: bool,
+    #[cfg(feature = "encrypt")]
+    key: Arc<Vec<u8>>,
+}
+
+impl ServerState {
+    /// Decode a request body, which is raw bytes when binary and base64 otherwise.
+    /// Decoded data is never larger than the largest message accepted, so lengths claiming more are malformed.
+    fn load<T: Loadable>(&self, data: &[u8], binary: bool) -> Result<(T, Encoding), LoadError> {
+        let max_message_size = self.config.max_request_size.saturating_mul(OVERSIZED_MESSAGE_FACTOR);
+        let limits = LoadLimits {
+            max_length: max_message_size,
+            max_bytes: max_message_size,
+            ..LoadLimits::DEFAULT
+        };
+        limits.scope(|| self.decode(data, binary))
+    }
+
+    fn decode<T: Loadable>(&self, data: &[u8], binary: bool) -> Result<(T, Encoding), LoadError> {
+        #[cfg(not(feature = "encrypt"))]
+        {
+            let loaded = match binary {
+                true => T::load_limited(&mut &data[..], LoadLimits::current()),
+                false => T::load_base64(data),
+            };
+            loaded.map(|(x, _)| (x, Encoding { binary }))
+        }
+        #[cfg(feature = "encrypt")]
+        {
+            // requests encrypted with a session key start with its id, others use the compile-time key
+            let keys = |key_id: Option<u64>| match key_id {
+                Some(key_id) => self.session_keys.get(key_id),
+                None => Some(self.key.clone()),
+            };
+            let loaded = match binary {
+                true => T::load_encrypted_bytes_with_id(data, keys, &self.replay_guard),
+                false => T::load_encrypted_with_id(data, keys, &self.replay_guard),
+            };
+            loaded.map(|(x, _, key)| (x, Encoding { binary, key }))
+        }
+    }
+
+    /// Encode a response body, as raw bytes when binary and base64 otherwise
+    fn dump<T: Dumpable>(&self, data: &T, encoding: &Encoding) -> Result<Vec<u8>, DumpError> {
+        #[cfg(not(feature = "encrypt"))]
+        {
+            if encoding.binary {
+                let mut buffer = Vec::with_capacity(socket::PACKET_BUFFER_SIZE);
+                data.dump(&mut buffer)?;
+                return Ok(buffer);
+            }
+            let mut buffer = String::with_capacity(socket::PACKET_BUFFER_SIZE);
+            data.dump_base64(&mut buffer)?;
+            Ok(buffer.into_bytes())
+        }
+        #[cfg(feature = "encrypt")]
+        {
+            let mut buffer = Vec::with_capacity(socket::PACKET_BUFFER_SIZE);
+            let len = match encoding.binary {
+                true => data.dump_encrypted_bytes(&mut buffer, &encoding.key)?,
+                false => data.dump_encrypted(&mut buffer, &encoding.key)?,
+            };
+            buffer.truncate(len);
+            Ok(buffer)
+        }
+    }
+
+    /// Replace a response which is larger than the limit with errors, so the front-end learns why it failed
+    fn oversized(&self, response: socket::Packet, len: usize) -> socket::Packet {
+        log::error!("USDPL response of {} bytes is larger than the limit of {} bytes", len, self.config.max_response_size);
+        fail_calls(response, &CallError::new(
+            ErrorCode::Internal,
+            format!("Response of {} bytes is larger than the limit of {} bytes", len, self.config.max_response_size),
+        ))
+    }
+
+    /// Respond to a request which is larger than the limit with errors, instead of handling it
+    fn oversized_request(&self, request: socket::Packet, len: usize) -> socket::Packet {
+        log::warn!("USDPL request of {} bytes is larger than the limit of {} bytes (rejecting packet)", len, self.config.max_request_size);
+        fail_calls(request, &CallError::new(
+            ErrorCode::Rejected,
+            format!("Request of {} bytes is larger than the limit of {} bytes", len, self.config.max_request_size),
+        ))
+    }
+
+    /// Handle a decoded request
+    async fn respond(&self, packet: socket::Packet, #[allow(unused_variables)] encoding: &Encoding) -> socket::Packet {
+        self.first_client.connected().await;
+        #[cfg(feature = "encrypt")]
+        {
+            let is_handshake_key = Arc::ptr_eq(&encoding.key, &self.key);
+            match packet {
+                socket::Packet::Handshake(handshake) if is_handshake_key => match self.session_keys.handshake(handshake) {
+                    Some(response) => {
+                        log::debug!("Completed USDPL handshake");
+                        socket::Packet::HandshakeResponse(response)
+                    },
+                    None => {
+                        log::warn!("Got USDPL handshake with invalid public key");
+                        socket::Packet::Invalid
+                    }
+                },
+                socket::Packet::Handshake(_) => {
+                    log::warn!("Got USDPL handshake encrypted with a session key (rejecting packet)");
+                    socket::Packet::Invalid
+                },
+                socket::Packet::Call(call) if is_handshake_key && self.require_handshake => {
+                    log::warn!("Got USDPL call {} without a session key (rejecting packet)", call.id);
+                    socket::Packet::CallError(RemoteCallError {
+                        id: call.id,
+                        error: CallError::new(ErrorCode::Rejected, "Handshake required"),
+                    })
+                },
+                _ if is_handshake_key && self.require_handshake => {
+                    log::warn!("Got USDPL packet without a session key (rejecting packet)");
+                    socket::Packet::Invalid
+                },
+                packet => self.handle_call(packet).await,
+            }
+        }
+        #[cfg(not(feature = "encrypt"))]
+        self.handle_call(packet).await
+    }
+
+    async fn handle_call(&self, packet: socket::Packet) -> socket::Packet {
+        self.handle_packet(packet, 0).await
+    }
+
+    /// Handle a packet nested in `depth` batches
+    #[async_recursion::async_recursion]
+    async fn handle_packet(&self, packet: socket::Packet, depth: usize) -> socket::Packet {
+        match packet {
+            socket::Packet::Call(call) => {
+                log::debug!("Got USDPL call {} (`{}`, params: {})", call.id, call.function, call.parameters.len());
+                if let Err(rejection) = self.sessions.accept(call.session, call.id) {
+                    log::error!("Rejecting USDPL call {} in session {}: {}", call.id, call.session, rejection);
+                    return socket::Packet::CallError(RemoteCallError {
+                        id: call.id,
+                        error: CallError::new(ErrorCode::Rejected, format!("Call ID {} rejected: {}", call.id, rejection)),
+                    });
+                }
+                //let handlers = CALLS.lock().expect("Failed to acquire CALLS lock");
+                if let Some(target) = self.handlers.get(&call.function) {
+                    let timeout = target.timeout().or(self.timeout);
+                    let deadline = match deadline(call.deadline, timeout) {
+                        Ok(deadline) => deadline,
+                        Err(()) => {
+                            log::warn!("Dropping USDPL call {} (`{}`) since its deadline passed", call.id, call.function);
+                            return socket::Packet::CallError(RemoteCallError {
+                                id: call.id,
+                                error: CallError::new(ErrorCode::Timeout, "Call deadline passed before it was handled"),
+                            });
+                        }
+                    };
+                    let context = CallContext::new(call.id, call.function.clone(), deadline);
+                    match target.call(context, call.parameters).await {
+                        Ok(Ok(result)) => socket::Packet::CallResponse(RemoteCallResponse {
+                            id: call.id,
+                            response: result,
+                        }),
+                        Err(Panicked(message)) => {
+                            log::error!("USDPL call {} (`{}`) panicked: {}", call.id, call.function, message);
+                            socket::Packet::CallError(RemoteCallError {
+                                id: call.id,
+                                error: CallError::new(ErrorCode::Internal, format!("Function `{}` panicked", call.function)),
+                            })
+                        },
+                        Ok(Err(error)) => {
+                            log::warn!("USDPL call {} (`{}`) failed: {}", call.id, call.function, error);
+                            socket::Packet::CallError(RemoteCallError {
+                                id: call.id,
+                                error,
+                            })
+                        }
+                    }
+                } else {
+                    log::warn!("USDPL call {} to unknown function `{}`", call.id, call.function);
+                    socket::Packet::CallError(RemoteCallError {
+                        id: call.id,
+                        error: CallError::new(ErrorCode::UnknownFunction, format!("No function named `{}`", call.function)),
+                    })
+                }
+            },
+            socket::Packet::Many(_) if depth >= MAX_BATCH_DEPTH => {
+                log::warn!("Got USDPL batch nested more than {} deep (rejecting packet)", MAX_BATCH_DEPTH);
+                socket::Packet::Invalid
+            },
+            socket::Packet::Many(packets) if packets.len() > MAX_BATCH_SIZE => {
+                log::warn!("Got USDPL batch of {} packets, more than {} (rejecting packets)", packets.len(), MAX_BATCH_SIZE);
+                let error = CallError::new(
+                    ErrorCode::Rejected,
+                    format!("Batch of {} packets is larger than the limit of {}", packets.len(), MAX_BATCH_SIZE),
+                );
+                fail_calls(socket::Packet::Many(packets), &error)
+            },
+            socket::Packet::Many(packets) => {
+                // run every packet at the same time, as far as their functions allow it, responding in request order
+                let responses = packets.into_iter().map(|packet| self.handle_packet(packet, depth + 1));
+                socket::Packet::Many(futures_util::future::join_all(responses).await)
+            },
+            socket::Packet::EventPoll(_) if depth > 0 => {
+                // a poll waits for new events, which would hold up the responses of the rest of the batch
+                log::warn!("Got USDPL event poll in a batch (rejecting packet)");
+                socket::Packet::Invalid
+            },
+            socket::Packet::EventPoll(since) => self.events.poll(since, &self.shutdown).await,
+            #[cfg(feature = "translate")]
+            socket::Packet::Language(lang) => socket::Packet::Translations(get_all_translations(lang)),
+            _ => socket::Packet::Invalid,
+        }
+    }
+}
+
+/// Replace every call (or call response) in the packet with this error
+fn fail_calls(packet: socket::Packet, error: &CallError) -> socket::Packet {
+    let fail = |id| socket::Packet::CallError(RemoteCallError {
+        id,
+        error: error.clone(),
+    });
+    match packet {
+        socket::Packet::Call(call) => fail(call.id),
+        socket::Packet::CallResponse(response) => fail(response.id),
+        socket::Packet::CallError(response) => fail(response.id),
+        socket::Packet::Many(packets) => socket::Packet::Many(packets.into_iter().map(|p| fail_calls(p, error)).collect()),
+        _ => socket::Packet::Invalid,
+    }
+}
+
+/// The earliest of the call's deadline (in milliseconds since the Unix epoch, 0 for none) and the timeout.
+/// Fails when the call's deadline already passed.
+fn deadline(call_deadline: u64, timeout: Option<Duration>) -> Result<Option<Instant>, ()> {
+    let now = Instant::now();
+    let timeout_deadline = timeout.map(|timeout| now + timeout);
+    if call_deadline == 0 {
+        return Ok(timeout_deadline);
+    }
+    let unix_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    if call_deadline <= unix_now {
+        return Err(());
+    }
+    let call_deadline = now + Duration::from_millis(call_deadline - unix_now);
+    Ok(Some(timeout_deadline.map(|d| d.min(call_deadline)).unwrap_or(call_deadline)))
+}
+
 #[cfg(feature = "translate")]
 fn get_all_translations(language: String) -> Vec<(String, Vec<String>)> {
     log::debug!("Loading translations for language `{}`...", language);
@@ -251,7 +950,7 @@ fn get_all_translations(language: String) -> Vec<(String, Vec<String>)> {
 #[cfg(feature = "translate")]
 fn load_locale(lang: &str) -> Result<gettext_ng::Catalog, gettext_ng::Error> {
     let path = crate::api::dirs::plugin().unwrap_or_else(|| "".into()).join("translations").join(format!("{}.mo", lang));
-    let file = std::fs::File::open(path).map_err(|e| gettext_ng::Error::Io(e))?;
+    let file = std::fs::File::open(path).map_err(gettext_ng::Error::Io)?;
     gettext_ng::Catalog::parse(file)
 }
 
@@ -259,4 +958,773 @@ fn load_locale(lang: &str) -> Result<gettext_ng::Catalog, gettext_ng::Error> {
 mod tests {
     #[allow(unused_imports)]
     use super::*;
+    use usdpl_core::serdes::Primitive;
+    #[cfg(feature = "blocking")]
+    use usdpl_core::RemoteCall;
+
+    /// A call from session 1, without a deadline
+    #[cfg(feature = "blocking")]
+    fn remote_call(id: u64, function: &str, parameters: Vec<Primitive>) -> RemoteCall {
+        RemoteCall {
+            id,
+            session: 1,
+            deadline: 0,
+            function: function.into(),
+            parameters,
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    fn call(id: u64, function: &str, parameters: Vec<Primitive>) -> socket::Packet {
+        socket::Packet::Call(remote_call(id, function, parameters))
+    }
+
+    /// Runtime for handling requests on the test's thread.
+    /// Sync functions still run on its blocking pool, so they don't block it.
+    #[cfg(feature = "blocking")]
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    /// Encoding of requests sent in tests, encrypted with the compile-time key when built with `encrypt`
+    #[cfg(feature = "blocking")]
+    fn encoding(#[allow(unused_variables)] state: &ServerState, binary: bool) -> Encoding {
+        Encoding {
+            binary,
+            #[cfg(feature = "encrypt")]
+            key: state.key.clone(),
+        }
+    }
+
+    #[test]
+    fn register_typed_test() {
+        let mut counter = 0u64;
+        let instance = Instance::new(31337)
+            .register_typed("sync", |watts: u32, persist: bool| -> Result<String, String> {
+                Ok(format!("{} {}", watts, persist))
+            })
+            .register_typed_blocking("blocking", move || {
+                counter += 1;
+                counter
+            })
+            .register_typed_async("async", |name: String| async move { name })
+            .register_fallible("fallible", |_: Vec<Primitive>| -> Result<Vec<Primitive>, CallError> {
+                Err("nope".into())
+            });
+        assert_eq!(instance.calls.len(), 4, "Expected all functions to be registered");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn handle_test() {
+        let instance = Instance::new(31337);
+        let handle = instance.handle();
+        let state = instance.state();
+        let runtime = runtime();
+
+        handle.register_typed("late", || true);
+        assert!(
+            matches!(runtime.block_on(state.handle_call(call(1, "late", vec![]))), socket::Packet::CallResponse(_)),
+            "Function registered after starting was not called"
+        );
+        assert!(handle.unregister("late"), "Registered function was not unregistered");
+        assert!(
+            matches!(runtime.block_on(state.handle_call(call(2, "late", vec![]))), socket::Packet::CallError(_)),
+            "Unregistered function was called"
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn concurrency_test() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (running_c, most_c) = (running.clone(), most.clone());
+        let slow = move || {
+            let now = running_c.fetch_add(1, Ordering::SeqCst) + 1;
+            most_c.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            running_c.fetch_sub(1, Ordering::SeqCst);
+        };
+        let instance = Instance::new(31337)
+            .register_typed("slow", slow);
+        let handle = instance.handle();
+        let state = instance.state();
+        let runtime = runtime();
+        let spawn_many = |first_id: u64, count: u64| -> Vec<_> {
+            (first_id..first_id + count)
+                .map(|id| {
+                    let state = state.clone();
+                    runtime.spawn(async move { state.handle_call(call(id, "slow", vec![])).await })
+                })
+                .collect()
+        };
+        let wait_all = |calls: Vec<tokio::task::JoinHandle<socket::Packet>>| {
+            runtime.block_on(async {
+                for call in calls {
+                    assert!(matches!(call.await.unwrap(), socket::Packet::CallResponse(_)), "Call failed");
+                }
+            });
+        };
+        let call_many = |first_id: u64| wait_all(spawn_many(first_id, 4));
+
+        // a single-threaded runtime can only run these at the same time if they don't block it
+        call_many(1);
+        assert!(most.load(Ordering::SeqCst) > 1, "Unlimited function calls did not run at the same time");
+
+        most.store(0, Ordering::SeqCst);
+        assert!(handle.set_concurrency("slow", Concurrency::Serialized), "Function is not registered");
+        call_many(5);
+        assert_eq!(most.load(Ordering::SeqCst), 1, "Serialized function calls ran at the same time");
+
+        // serialized calls also wait for calls which started before the function was serialized
+        assert!(handle.set_concurrency("slow", Concurrency::Unlimited), "Function is not registered");
+        let unlimited = spawn_many(9, 2);
+        // let the calls start on the blocking pool
+        runtime.block_on(tokio::task::yield_now());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(running.load(Ordering::SeqCst) > 0, "Unlimited function calls did not start");
+        assert!(handle.set_concurrency("slow", Concurrency::Serialized), "Function is not registered");
+        most.store(0, Ordering::SeqCst);
+        let serialized = spawn_many(11, 1);
+        wait_all(unlimited);
+        wait_all(serialized);
+        assert_eq!(most.load(Ordering::SeqCst), 1, "Serialized function call ran alongside an earlier call");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn panic_test() {
+        struct Counter(u32);
+
+        impl MutCallable for Counter {
+            fn call(&mut self, params: Vec<Primitive>) -> Vec<Primitive> {
+                self.0 += 1;
+                if !params.is_empty() {
+                    panic!("counter panic");
+                }
+                vec![self.0.into()]
+            }
+
+            fn reset(&mut self) {
+                self.0 = 0;
+            }
+        }
+
+        let instance = Instance::new(31337)
+            .register_blocking("count", Counter(0))
+            .register("panic", |_| -> Vec<Primitive> { panic!("always panics") })
+            .with_panic_policy("panic", PanicPolicy::Disable);
+        let state = instance.state();
+        let runtime = runtime();
+        let call = |id: u64, function: &str, parameters: Vec<Primitive>| {
+            runtime.block_on(state.handle_call(call(id, function, parameters)))
+        };
+        let count = |packet| match packet {
+            socket::Packet::CallResponse(r) => match r.response.as_slice() {
+                [Primitive::U32(count)] => *count,
+                _ => panic!("Unexpected count response"),
+            },
+            _ => panic!("Count call failed"),
+        };
+
+        assert_eq!(count(call(1, "count", vec![])), 1);
+        match call(2, "count", vec![true.into()]) {
+            socket::Packet::CallError(e) => {
+                assert_eq!(e.id, 2, "Error does not match call id");
+                assert_eq!(e.error.code, ErrorCode::Internal);
+            },
+            _ => panic!("Panicking call did not respond with CallError"),
+        }
+        assert_eq!(count(call(3, "count", vec![])), 1, "Function state was not reset after panicking");
+
+        assert!(matches!(call(4, "panic", vec![]), socket::Packet::CallError(_)), "Panicking call did not fail");
+        match call(5, "panic", vec![]) {
+            socket::Packet::CallError(e) => assert_eq!(e.error.message, "Function is disabled since it panicked"),
+            _ => panic!("Disabled function was called"),
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn timeout_test() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran_c = ran.clone();
+        let instance = Instance::new(31337)
+            .with_timeout(Duration::from_secs(5))
+            .register_typed_async("hang", || async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            })
+            .with_function_timeout("hang", Duration::from_millis(50))
+            .register_typed("context", move || {
+                ran_c.store(true, Ordering::SeqCst);
+                let context = CallContext::current().expect("No context in function");
+                context.remaining().map(|remaining| remaining <= Duration::from_secs(5)).unwrap_or(false)
+            });
+        let state = instance.state();
+        let runtime = runtime();
+        let call = |id: u64, function: &str, deadline: u64| {
+            runtime.block_on(state.handle_call(socket::Packet::Call(RemoteCall {
+                deadline,
+                ..remote_call(id, function, vec![])
+            })))
+        };
+        let error_code = |packet| match packet {
+            socket::Packet::CallError(e) => e.error.code,
+            _ => panic!("Call did not respond with CallError"),
+        };
+
+        assert_eq!(error_code(call(1, "hang", 0)), ErrorCode::Timeout, "Hanging function did not time out");
+        assert_eq!(error_code(call(2, "context", 1)), ErrorCode::Timeout, "Expired call was not dropped");
+        assert!(!ran.load(Ordering::SeqCst), "Expired call was handled");
+        match call(3, "context", 0) {
+            socket::Packet::CallResponse(r) => assert!(
+                matches!(r.response.as_slice(), [Primitive::Bool(true)]),
+                "Function context did not have the instance's timeout"
+            ),
+            _ => panic!("Call with context failed"),
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn timeout_concurrency_test() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (running_c, most_c) = (running.clone(), most.clone());
+        let instance = Instance::new(31337)
+            .register_typed("slow", move |millis: u64| {
+                let now = running_c.fetch_add(1, Ordering::SeqCst) + 1;
+                most_c.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(millis));
+                running_c.fetch_sub(1, Ordering::SeqCst);
+            })
+            .with_concurrency("slow", Concurrency::Serialized)
+            .with_function_timeout("slow", Duration::from_millis(100));
+        let state = instance.state();
+        let runtime = runtime();
+        let call = |id: u64, millis: u64| runtime.block_on(state.handle_call(call(id, "slow", vec![millis.into()])));
+
+        assert!(
+            matches!(call(1, 150), socket::Packet::CallError(e) if e.error.code == ErrorCode::Timeout),
+            "Slow function did not time out"
+        );
+        assert_eq!(running.load(Ordering::SeqCst), 1, "Timed out function is not running in the background");
+        // waits for the timed out call to return, which is still within this call's timeout
+        assert!(matches!(call(2, 10), socket::Packet::CallResponse(_)), "Call after a timed out call failed");
+        assert_eq!(most.load(Ordering::SeqCst), 1, "Serialized function ran alongside a timed out call");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn batch_test() {
+        let instance = Instance::new(31337)
+            .register_typed("slow", |n: u32| {
+                std::thread::sleep(Duration::from_millis(100));
+                n
+            });
+        let state = instance.state();
+        let runtime = runtime();
+        let call = |id: u64| call(id, "slow", vec![(id as u32).into()]);
+
+        let start = std::time::Instant::now();
+        let response = runtime.block_on(state.handle_call(socket::Packet::Many((1..=4).map(call).collect())));
+        assert!(start.elapsed() < Duration::from_millis(400), "Batched calls did not run at the same time");
+        match response {
+            socket::Packet::Many(responses) => {
+                let results: Vec<_> = responses.into_iter().map(|response| match response {
+                    socket::Packet::CallResponse(r) => (r.id, r.response),
+                    _ => panic!("Batched call failed"),
+                }).collect();
+                for (i, (id, response)) in results.into_iter().enumerate() {
+                    assert_eq!(id, i as u64 + 1, "Batch responses are not in request order");
+                    assert!(matches!(response.as_slice(), [Primitive::U32(n)] if *n == id as u32), "Wrong batched response");
+                }
+            },
+            _ => panic!("Batch did not respond with Many"),
+        }
+
+        let response = runtime.block_on(state.handle_call(
+            socket::Packet::Many((10..(11 + MAX_BATCH_SIZE as u64)).map(call).collect())
+        ));
+        match response {
+            socket::Packet::Many(responses) => assert!(
+                responses.iter().all(|r| matches!(r, socket::Packet::CallError(e) if e.error.code == ErrorCode::Rejected)),
+                "Oversized batch was not rejected"
+            ),
+            _ => panic!("Oversized batch did not respond with Many"),
+        }
+
+        let mut nested = call(100);
+        for _ in 0..=MAX_BATCH_DEPTH {
+            nested = socket::Packet::Many(vec![nested]);
+        }
+        let mut response = runtime.block_on(state.handle_call(nested));
+        for _ in 0..MAX_BATCH_DEPTH {
+            response = match response {
+                socket::Packet::Many(mut responses) => responses.pop().expect("Empty nested batch response"),
+                _ => panic!("Nested batch did not respond with Many"),
+            };
+        }
+        assert!(matches!(response, socket::Packet::Invalid), "Deeply nested batch was not rejected");
+
+        // polls would wait for events before the batch responds, and handshakes are only done on their own
+        let start = std::time::Instant::now();
+        let response = runtime.block_on(state.handle_call(socket::Packet::Many(vec![
+            call(200),
+            socket::Packet::EventPoll(0),
+            #[cfg(feature = "encrypt")]
+            socket::Packet::Handshake(usdpl_core::handshake::Handshake {
+                public_key: usdpl_core::handshake::KeyExchange::new().public_key(),
+            }),
+        ])));
+        assert!(start.elapsed() < Duration::from_secs(1), "Batch waited for the event poll");
+        match response {
+            socket::Packet::Many(responses) => {
+                assert!(matches!(responses[0], socket::Packet::CallResponse(_)), "Call in batch with a poll failed");
+                assert!(
+                    responses[1..].iter().all(|r| matches!(r, socket::Packet::Invalid)),
+                    "Poll or handshake in a batch was not rejected"
+                );
+            },
+            _ => panic!("Batch with a poll did not respond with Many"),
+        }
+    }
+
+    /// Discovery file which no other test (or test run) uses
+    #[cfg(feature = "blocking")]
+    fn test_discovery_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("usdpl-{}-test-{}.port", name, std::process::id()))
+    }
+
+    /// Port an instance listens on, once it has written it to its discovery file
+    #[cfg(feature = "blocking")]
+    async fn discovered_port(discovery_file: &std::path::Path) -> u16 {
+        loop {
+            if let Some(port) = std::fs::read_to_string(discovery_file).ok().and_then(|x| x.parse().ok()) {
+                return port;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn lifecycle_test() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let event = |name: &'static str| {
+            let events = events.clone();
+            move || {
+                let events = events.clone();
+                async move { events.lock().unwrap().push(name) }
+            }
+        };
+        let grace_period = Duration::from_secs(5);
+        let discovery_file = test_discovery_file("lifecycle");
+        let instance = Instance::new(0)
+            .with_discovery_file(&discovery_file)
+            .with_grace_period(grace_period)
+            .on_start(event("start"))
+            .on_first_client(event("first client"))
+            .on_shutdown(event("shutdown"));
+        let shutdown = instance.shutdown_handle();
+        let runtime = runtime();
+
+        let state = instance.state();
+        let encoding = encoding(&state, false);
+        // both wait for new events, which never come
+        let socket_poll = state.dump(&socket::Tagged {
+            id: 1,
+            packet: socket::Packet::EventPoll(0),
+        }, &encoding).unwrap();
+        let http_poll = state.dump(&socket::Packet::EventPoll(0), &encoding).unwrap();
+
+        runtime.block_on(async {
+            // open connections and event polls must not hold up the shutdown
+            let client = async {
+                let port = discovered_port(&discovery_file).await;
+                let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/usdpl/ws", port))
+                    .await
+                    .expect("WebSocket did not connect");
+                let socket_poll = String::from_utf8(socket_poll).unwrap();
+                socket.send(tokio_tungstenite::tungstenite::Message::Text(socket_poll)).await.unwrap();
+                let http_poll = async {
+                    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                    let body = String::from_utf8(http_poll).unwrap();
+                    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                    let request = format!(
+                        "POST /usdpl/call HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body,
+                    );
+                    stream.write_all(request.as_bytes()).await.unwrap();
+                    let mut response = String::new();
+                    stream.read_to_string(&mut response).await.ok();
+                    response
+                };
+                let stop = async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    shutdown.shutdown();
+                };
+                let socket_closed = async {
+                    let mut messages = Vec::new();
+                    while let Some(Ok(message)) = socket.next().await {
+                        messages.push(message);
+                    }
+                    messages
+                };
+                let (messages, response, _) = tokio::join!(socket_closed, http_poll, stop);
+                assert!(
+                    matches!(messages.last(), Some(tokio_tungstenite::tungstenite::Message::Close(_))),
+                    "WebSocket was not closed on shutdown"
+                );
+                assert!(response.starts_with("HTTP/1.1 200"), "Event poll did not end on shutdown");
+            };
+            let started = std::time::Instant::now();
+            let stopped = tokio::time::timeout(grace_period * 2, async { tokio::join!(instance.run(), client) }).await;
+            let (result, _) = stopped.expect("Connections were not closed on shutdown");
+            assert!(result.is_ok(), "Instance did not stop cleanly");
+            assert!(started.elapsed() < grace_period / 2, "Shutdown waited for open connections");
+        });
+        assert_eq!(*events.lock().unwrap(), ["start", "first client", "shutdown"], "Lifecycle hooks did not run in order");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn bind_test() {
+        let taken = std::net::TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let runtime = runtime();
+        let result = runtime.block_on(Instance::new(port).run());
+        assert!(matches!(result, Err(ServerError::Bind(p, _)) if p == port), "Taken port did not fail to bind");
+
+        let discovery_file = test_discovery_file("bind");
+        let discovered = Arc::new(std::sync::Mutex::new(None));
+        let discovered_c = discovered.clone();
+        let discovery_file_c = discovery_file.clone();
+        // the fallback port may be taken too, by anything else running
+        let instance = Instance::new(port)
+            .with_fallback_ports(port..=port.saturating_add(1))
+            .with_any_port()
+            .with_discovery_file(&discovery_file)
+            .on_start(move || {
+                let port = std::fs::read_to_string(&discovery_file_c).ok();
+                *discovered_c.lock().unwrap() = port;
+                async {}
+            });
+        instance.shutdown_handle().shutdown();
+        runtime.block_on(instance.run()).expect("Fallback port did not bind");
+        let discovered: Option<u16> = discovered.lock().unwrap().as_deref().and_then(|x| x.parse().ok());
+        assert!(
+            matches!(discovered, Some(p) if p != port && p != 0),
+            "Discovery file does not contain the fallback port"
+        );
+        assert!(!discovery_file.exists(), "Discovery file was not removed");
+        drop(taken);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn builder_test() {
+        let instance = Instance::builder(31337)
+            .allow_origin(crate::STEAM_ORIGIN)
+            .max_request_size(512)
+            .max_response_size(128)
+            .build()
+            .register_typed("echo", |text: String| text);
+        let state = instance.state();
+        let call = |id: u64, text: String| state.dump(&call(id, "echo", vec![text.into()]), &encoding(&state, false)).unwrap();
+        runtime().block_on(async {
+            let request = |origin: &str, body: Vec<u8>| warp::test::request()
+                .method("POST")
+                .path("/usdpl/call")
+                .header("origin", origin)
+                .body(body);
+
+            let response = request("http://example.com", call(1, "hi".into())).reply(&instance.routes()).await;
+            assert_eq!(response.status(), 403, "Request from unlisted origin was not rejected");
+
+            let response = request(crate::STEAM_ORIGIN, call(2, "hi".into())).reply(&instance.routes()).await;
+            assert_eq!(response.status(), 200, "Request from allowed origin failed");
+            assert_eq!(response.headers()["access-control-allow-origin"], crate::STEAM_ORIGIN);
+            let (packet, _): (socket::Packet, _) = state.load(response.body(), false).unwrap();
+            assert!(matches!(packet, socket::Packet::CallResponse(_)), "Small response was not sent");
+
+            let response = request(crate::STEAM_ORIGIN, call(3, "x".repeat(200))).reply(&instance.routes()).await;
+            let (packet, _): (socket::Packet, _) = state.load(response.body(), false).unwrap();
+            match packet {
+                socket::Packet::CallError(e) => assert_eq!(e.id, 3, "Error does not match call id"),
+                _ => panic!("Oversized response was not replaced with CallError"),
+            }
+
+            let response = request(crate::STEAM_ORIGIN, call(4, "x".repeat(600))).reply(&instance.routes()).await;
+            assert_eq!(response.status(), 200, "Oversized request did not get a response");
+            let (packet, _): (socket::Packet, _) = state.load(response.body(), false).unwrap();
+            match packet {
+                socket::Packet::CallError(e) => assert_eq!(e.id, 4, "Error does not match call id"),
+                _ => panic!("Oversized request did not respond with CallError"),
+            }
+
+            // far larger requests are not read at all
+            let response = request(crate::STEAM_ORIGIN, call(5, "x".repeat(3000))).reply(&instance.routes()).await;
+            assert_eq!(response.status(), 413, "Far oversized request was read");
+        });
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn large_payload_test() {
+        let instance = Instance::builder(31337)
+            .max_request_size(2048)
+            .build()
+            .register_typed("echo", |text: String| text);
+        let state = instance.state();
+        let encoding = encoding(&state, false);
+        let call = |id: u64, text: String| call(id, "echo", vec![text.into()]);
+        runtime().block_on(async {
+            // larger than the old fixed limit, but within the default one
+            let text = "x".repeat(100 * 1024);
+            let body = state.dump(&call(1, text.clone()), &encoding).unwrap();
+            let default_instance = Instance::new(31337).register_typed("echo", |text: String| text);
+            let response = warp::test::request()
+                .method("POST")
+                .path("/usdpl/call")
+                .body(body)
+                .reply(&default_instance.routes())
+                .await;
+            assert_eq!(response.status(), 200, "Large request was not accepted");
+            // the small instance won't load anything this large
+            let (packet, _): (socket::Packet, _) = default_instance.state().load(response.body(), false).unwrap();
+            match packet {
+                socket::Packet::CallResponse(r) => assert!(
+                    matches!(&r.response[0], Primitive::String(s) if *s == text),
+                    "Large response does not match"
+                ),
+                _ => panic!("Large request did not respond with CallResponse"),
+            }
+
+            let mut socket = warp::test::ws()
+                .path("/usdpl/ws")
+                .handshake(instance.routes())
+                .await
+                .expect("WebSocket handshake failed");
+            let tagged = |packet| String::from_utf8(state.dump(&socket::Tagged { id: 7, packet }, &encoding).unwrap()).unwrap();
+            socket.send_text(tagged(socket::Packet::Many(vec![call(2, "x".repeat(4096)), call(3, "hi".into())]))).await;
+            let message = socket.recv().await.unwrap();
+            let (response, _): (socket::Tagged, _) = state.load(message.as_bytes(), false).unwrap();
+            assert_eq!(response.id, 7, "Response does not match request id");
+            match response.packet {
+                socket::Packet::Many(packets) => {
+                    assert_eq!(packets.len(), 2);
+                    for (packet, id) in packets.into_iter().zip([2, 3]) {
+                        match packet {
+                            socket::Packet::CallError(e) => {
+                                assert_eq!(e.id, id, "Error does not match call id");
+                                assert_eq!(e.error.code, ErrorCode::Rejected);
+                            },
+                            _ => panic!("Call in oversized message did not respond with CallError"),
+                        }
+                    }
+                },
+                _ => panic!("Oversized message did not respond with Many"),
+            }
+
+            // the socket still works after the oversized message
+            socket.send_text(tagged(call(4, "hi".into()))).await;
+            let message = socket.recv().await.unwrap();
+            let (response, _): (socket::Tagged, _) = state.load(message.as_bytes(), false).unwrap();
+            assert!(matches!(response.packet, socket::Packet::CallResponse(_)), "Small message after oversized one failed");
+        });
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn binary_test() {
+        let instance = Instance::new(31337)
+            .register_typed("echo", |text: String| text);
+        let state = instance.state();
+        let encoding = |binary| encoding(&state, binary);
+        let call = |id: u64| call(id, "echo", vec!["hi".into()]);
+        let is_response = |packet: socket::Packet, id: u64| matches!(packet, socket::Packet::CallResponse(r) if r.id == id);
+        runtime().block_on(async {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/usdpl/call")
+                .header("content-type", socket::BINARY_CONTENT_TYPE)
+                .body(state.dump(&call(1), &encoding(true)).unwrap())
+                .reply(&instance.routes())
+                .await;
+            assert_eq!(response.status(), 200, "Binary request failed");
+            assert_eq!(response.headers()["content-type"], socket::BINARY_CONTENT_TYPE);
+            let (packet, _): (socket::Packet, _) = state.load(response.body(), true).unwrap();
+            assert!(is_response(packet, 1), "Binary request did not get a binary response");
+
+            // base64 requests can ask for binary responses, which is how the front-end finds out they're supported
+            let response = warp::test::request()
+                .method("POST")
+                .path("/usdpl/call")
+                .header("accept", socket::BINARY_CONTENT_TYPE)
+                .body(state.dump(&call(2), &encoding(false)).unwrap())
+                .reply(&instance.routes())
+                .await;
+            assert_eq!(response.headers()["content-type"], socket::BINARY_CONTENT_TYPE);
+            let (packet, _): (socket::Packet, _) = state.load(response.body(), true).unwrap();
+            assert!(is_response(packet, 2), "Accepted binary response was not sent");
+
+            let response = warp::test::request()
+                .method("POST")
+                .path("/usdpl/call")
+                .body(state.dump(&call(3), &encoding(false)).unwrap())
+                .reply(&instance.routes())
+                .await;
+            assert!(response.headers().get("content-type").is_none(), "Base64 response has binary content type");
+            let (packet, _): (socket::Packet, _) = state.load(response.body(), false).unwrap();
+            assert!(is_response(packet, 3), "Base64 request did not get a base64 response");
+
+            let response = warp::test::request()
+                .method("OPTIONS")
+                .path("/usdpl/call")
+                .header("origin", crate::STEAM_ORIGIN)
+                .reply(&instance.routes())
+                .await;
+            assert_eq!(response.status(), 204, "Preflight request failed");
+            assert_eq!(response.headers()["access-control-allow-headers"], "content-type");
+
+            let mut socket = warp::test::ws()
+                .path("/usdpl/ws")
+                .header("sec-websocket-protocol", socket::BINARY_PROTOCOL)
+                .handshake(instance.routes())
+                .await
+                .expect("WebSocket handshake failed");
+            let request = socket::Tagged { id: 9, packet: call(4) };
+            socket.send(warp::ws::Message::binary(state.dump(&request, &encoding(true)).unwrap())).await;
+            let message = socket.recv().await.unwrap();
+            assert!(message.is_binary(), "Binary message did not get a binary response");
+            let (response, _): (socket::Tagged, _) = state.load(message.as_bytes(), true).unwrap();
+            assert_eq!(response.id, 9, "Response does not match request id");
+            assert!(is_response(response.packet, 4), "Binary message did not get a call response");
+        });
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn call_error_test() {
+        let instance = Instance::new(31337)
+            .register_typed("fail", || -> Result<(), &str> { Err("nope") });
+        let state = instance.state();
+        let runtime = runtime();
+
+        let response = runtime.block_on(state.handle_call(call(1, "fail", vec![])));
+        if let socket::Packet::CallError(e) = response {
+            assert_eq!(e.id, 1, "Error does not match call id");
+            assert_eq!(e.error, CallError::new(ErrorCode::Failed, "nope"));
+        } else {
+            panic!("Failing call did not respond with CallError");
+        }
+
+        let response = runtime.block_on(state.handle_call(call(2, "missing", vec![])));
+        if let socket::Packet::CallError(e) = response {
+            assert_eq!(e.error.code, ErrorCode::UnknownFunction);
+        } else {
+            panic!("Unknown function did not respond with CallError");
+        }
+
+        let response = runtime.block_on(state.handle_call(call(2, "fail", vec![])));
+        if let socket::Packet::CallError(e) = response {
+            assert_eq!(e.error.code, ErrorCode::Rejected, "Replayed call was not rejected");
+        } else {
+            panic!("Replayed call did not respond with CallError");
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn websocket_test() {
+        let instance = Instance::new(31337)
+            .register_typed_async("echo", |name: String| async move { name });
+        let state = instance.state();
+        let encoding = encoding(&state, false);
+        runtime().block_on(async {
+            let mut client = warp::test::ws()
+                .path("/usdpl/ws")
+                .handshake(instance.routes())
+                .await
+                .expect("WebSocket handshake failed");
+            let request = socket::Tagged {
+                id: 42,
+                packet: call(7, "echo", vec!["hello".into()]),
+            };
+            client.send_text(String::from_utf8(state.dump(&request, &encoding).unwrap()).unwrap()).await;
+            let message = client.recv().await.expect("No WebSocket response");
+            let (response, _): (socket::Tagged, _) = state.load(message.as_bytes(), false).unwrap();
+            assert_eq!(response.id, 42, "Response is not tagged with the request id");
+            if let socket::Packet::CallResponse(resp) = response.packet {
+                assert_eq!(resp.id, 7, "Response does not match call id");
+                assert!(matches!(&resp.response[..], [Primitive::String(s)] if s == "hello"));
+            } else {
+                panic!("Call over WebSocket did not respond with CallResponse");
+            }
+        });
+    }
+
+    #[cfg(all(feature = "encrypt", feature = "blocking"))]
+    #[test]
+    fn handshake_test() {
+        use usdpl_core::handshake::{Handshake, KeyExchange, SessionKey};
+
+        let instance = Instance::new(31337)
+            .register_typed("hello", || "world")
+            .require_handshake();
+        let state = instance.state();
+        let runtime = runtime();
+        let call = || call(1, "hello", vec![]);
+        let send = |packet: socket::Packet, session_key: Option<&SessionKey>| {
+            let mut request = Vec::new();
+            match session_key {
+                Some(session_key) => packet.dump_encrypted_with_id(&mut request, session_key).unwrap(),
+                None => packet.dump_encrypted(&mut request, &state.key).unwrap(),
+            };
+            let (packet, encoding) = state.load(&request, false).unwrap();
+            let expected_key = session_key.map(|k| &k.key).unwrap_or(&state.key);
+            assert_eq!(*encoding.key, *expected_key, "Request decrypted with the wrong key");
+            runtime.block_on(state.respond(packet, &encoding))
+        };
+
+        match send(call(), None) {
+            socket::Packet::CallError(e) => assert_eq!(e.error.code, ErrorCode::Rejected),
+            _ => panic!("Call without session key was not rejected"),
+        }
+
+        let exchange = KeyExchange::new();
+        let handshake = socket::Packet::Handshake(Handshake { public_key: exchange.public_key() });
+        let session_key = match send(handshake, None) {
+            socket::Packet::HandshakeResponse(response) => SessionKey {
+                id: response.key_id,
+                key: exchange.session_key(&response.public_key, true).expect("Invalid handshake response"),
+            },
+            _ => panic!("Handshake did not respond with HandshakeResponse"),
+        };
+        match send(call(), Some(&session_key)) {
+            socket::Packet::CallResponse(resp) => assert_eq!(resp.response.len(), 1),
+            _ => panic!("Call with session key did not respond with CallResponse"),
+        }
+
+        // a key the back-end doesn't know (any more) fails to load, so the front-end does a new handshake
+        let forgotten = SessionKey {
+            id: usdpl_core::handshake::new_key_id().unwrap(),
+            key: session_key.key.clone(),
+        };
+        let mut request = Vec::new();
+        call().dump_encrypted_with_id(&mut request, &forgotten).unwrap();
+        assert!(
+            matches!(state.load::<socket::Packet>(&request, false), Err(LoadError::DecryptionError)),
+            "Request with an unknown session key was loaded"
+        );
+    }
 }
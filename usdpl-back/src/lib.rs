@@ -13,14 +13,31 @@ mod api_crankshaft;
 #[cfg(all(feature = "decky", not(any(feature = "crankshaft"))))]
 mod api_decky;
 
+mod builder;
 mod callable;
-//mod errors;
+mod context;
+mod errors;
+mod events;
+mod handle;
 mod instance;
-
-pub use callable::{Callable, MutCallable, AsyncCallable};
+mod lifecycle;
+mod registry;
+#[cfg(feature = "encrypt")]
+mod session_keys;
+mod sessions;
+mod typed;
+
+pub use builder::{InstanceBuilder, STEAM_ORIGIN};
+pub use callable::{Callable, MutCallable, AsyncCallable, Concurrency, PanicPolicy};
+pub use callable::{FallibleCallable, FallibleMutCallable, FallibleAsyncCallable};
 pub(crate) use callable::WrappedCallable;
+pub use context::CallContext;
+pub use events::Emitter;
+pub use handle::InstanceHandle;
 pub use instance::Instance;
-//pub use errors::{ServerError, ServerResult};
+pub use lifecycle::ShutdownHandle;
+pub use typed::{ArgumentError, FromPrimitives, IntoPrimitives, TypedCallable, TypedMutCallable, TypedAsyncCallable};
+pub use errors::{ServerError, ServerResult};
 
 /// USDPL backend API.
 /// This contains functionality used exclusively by the back-end.
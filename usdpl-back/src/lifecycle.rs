@@ -0,0 +1,150 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::sync::{watch, OnceCell};
+
+/// Async function run when the instance starts, stops or gets its first client
+pub(crate) type Hook = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+pub(crate) fn hook<F, A>(f: F) -> Hook
+where
+    F: Fn() -> A + Send + Sync + 'static,
+    A: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move || Box::pin(f()))
+}
+
+/// Run hooks one after the other, in the order they were added
+pub(crate) async fn run_hooks(hooks: &[Hook]) {
+    for hook in hooks {
+        hook().await;
+    }
+}
+
+/// Hooks which run before the first request from a front-end is handled
+pub(crate) struct FirstClient {
+    hooks: Vec<Hook>,
+    done: OnceCell<()>,
+}
+
+impl FirstClient {
+    pub fn new(hooks: Vec<Hook>) -> Self {
+        Self {
+            hooks,
+            done: OnceCell::new(),
+        }
+    }
+
+    /// Run the hooks if this is the first request, otherwise wait for them to be done
+    pub async fn connected(&self) {
+        self.done.get_or_init(|| async {
+            log::debug!("First USDPL client connected");
+            run_hooks(&self.hooks).await;
+        }).await;
+    }
+}
+
+/// Handle for stopping a running back-end instance.
+/// This can be cloned and used from any thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> Self {
+        let (requested, _) = watch::channel(false);
+        Self {
+            requested: Arc::new(requested),
+        }
+    }
+
+    /// Stop the instance, like SIGTERM or SIGINT does.
+    /// Requests which are being handled get the grace period to complete, then the shutdown hooks run.
+    pub fn shutdown(&self) {
+        self.requested.send_replace(true);
+    }
+
+    /// Whether the instance was asked to stop
+    pub fn is_shutdown(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Wait until the instance is asked to stop
+    pub async fn wait(&self) {
+        let mut receiver = self.requested.subscribe();
+        while !*receiver.borrow_and_update() {
+            // the sender is kept alive by self, so this can't fail
+            if receiver.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Wait for SIGTERM (sent by the plugin loader to stop the back-end) or SIGINT
+pub(crate) async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => log::info!("Got SIGTERM"),
+                    _ = tokio::signal::ctrl_c() => log::info!("Got SIGINT"),
+                }
+            },
+            Err(e) => {
+                log::warn!("Cannot listen for SIGTERM: {}", e);
+                tokio::signal::ctrl_c().await.ok();
+                log::info!("Got SIGINT");
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            log::info!("Got SIGINT");
+        }
+    }
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn first_client_test() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_c = count.clone();
+        let first_client = FirstClient::new(vec![hook(move || {
+            let count = count_c.clone();
+            async move {
+                count.fetch_add(1, Ordering::SeqCst);
+            }
+        })]);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            first_client.connected().await;
+            first_client.connected().await;
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 1, "First client hook did not run exactly once");
+    }
+
+    #[test]
+    fn shutdown_test() {
+        let handle = ShutdownHandle::new();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        assert!(!handle.is_shutdown());
+        let waiting = runtime.spawn({
+            let handle = handle.clone();
+            async move { handle.wait().await }
+        });
+        handle.clone().shutdown();
+        runtime.block_on(waiting).unwrap();
+        assert!(handle.is_shutdown());
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+use arc_swap::ArcSwap;
+
+use super::WrappedCallable;
+
+/// Functions which can be called from the front-end, by name.
+/// Lookups never block; changes copy the map, since they are rare compared to calls.
+pub(crate) struct Registry {
+    calls: ArcSwap<HashMap<String, WrappedCallable>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            calls: ArcSwap::from_pointee(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<WrappedCallable> {
+        self.calls.load().get(name).cloned()
+    }
+
+    pub fn insert(&self, name: String, callable: WrappedCallable) {
+        self.calls.rcu(|calls| {
+            let mut calls = HashMap::clone(calls);
+            calls.insert(name.clone(), callable.clone());
+            calls
+        });
+    }
+
+    /// Replace the function with this name, returning whether it was registered
+    pub fn update<F: Fn(&WrappedCallable) -> WrappedCallable>(&self, name: &str, f: F) -> bool {
+        let previous = self.calls.rcu(|calls| {
+            let mut calls = HashMap::clone(calls);
+            if let Some(callable) = calls.get_mut(name) {
+                *callable = f(callable);
+            }
+            calls
+        });
+        previous.contains_key(name)
+    }
+
+    /// Returns whether a function with this name was registered
+    pub fn remove(&self, name: &str) -> bool {
+        let previous = self.calls.rcu(|calls| {
+            let mut calls = HashMap::clone(calls);
+            calls.remove(name);
+            calls
+        });
+        previous.contains_key(name)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.calls.load().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usdpl_core::serdes::Primitive;
+
+    #[test]
+    fn registry_test() {
+        let registry = Registry::new();
+        registry.insert("test".into(), WrappedCallable::new_ref(|_: Vec<Primitive>| vec![]));
+        assert!(registry.get("test").is_some(), "Registered function not found");
+        assert_eq!(registry.len(), 1);
+        assert!(registry.remove("test"), "Registered function not removed");
+        assert!(!registry.remove("test"), "Unregistered function removed");
+        assert!(registry.get("test").is_none(), "Unregistered function found");
+    }
+}
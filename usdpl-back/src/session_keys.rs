@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use usdpl_core::handshake::{new_key_id, Handshake, HandshakeResponse, KeyExchange};
+
+/// How long a session key is kept after it was last used
+const SESSION_KEY_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// Most session keys kept at once, the least recently used one is dropped to make room
+const MAX_SESSION_KEYS: usize = 1024;
+/// How often timed out session keys are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct SessionKey {
+    key: Arc<Vec<u8>>,
+    last_used: Instant,
+}
+
+struct KeyMap {
+    keys: HashMap<u64, SessionKey>,
+    last_pruned: Instant,
+}
+
+impl KeyMap {
+    /// Make room for a new key
+    fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.last_pruned) >= PRUNE_INTERVAL {
+            self.keys.retain(|_, k| now.duration_since(k.last_used) < SESSION_KEY_TIMEOUT);
+            self.last_pruned = now;
+        }
+        if self.keys.len() >= MAX_SESSION_KEYS {
+            let oldest = self.keys.iter()
+                .min_by_key(|(_, k)| k.last_used)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                log::debug!("Too many USDPL session keys, dropping key {:x}", oldest);
+                self.keys.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Keys agreed on with front-ends through handshakes, by their id
+pub(crate) struct SessionKeys {
+    keys: Mutex<KeyMap>,
+}
+
+impl SessionKeys {
+    pub fn new() -> Self {
+        Self {
+            keys: Mutex::new(KeyMap {
+                keys: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Complete the front-end's handshake, remembering the new session key
+    pub fn handshake(&self, handshake: Handshake) -> Option<HandshakeResponse> {
+        let exchange = KeyExchange::new();
+        let public_key = exchange.public_key();
+        let key = exchange.session_key(&handshake.public_key, false)?;
+        let now = Instant::now();
+        let mut map = self.keys.lock().expect("Failed to acquire session keys lock");
+        map.prune(now);
+        let key_id = loop {
+            let id = new_key_id().ok()?;
+            if !map.keys.contains_key(&id) {
+                break id;
+            }
+        };
+        map.keys.insert(key_id, SessionKey {
+            key: Arc::new(key),
+            last_used: now,
+        });
+        Some(HandshakeResponse { public_key, key_id })
+    }
+
+    /// The session key with this id, unless it timed out (or was never agreed on)
+    pub fn get(&self, id: u64) -> Option<Arc<Vec<u8>>> {
+        let now = Instant::now();
+        let mut map = self.keys.lock().expect("Failed to acquire session keys lock");
+        let key = map.keys.get_mut(&id)?;
+        if now.duration_since(key.last_used) >= SESSION_KEY_TIMEOUT {
+            map.keys.remove(&id);
+            return None;
+        }
+        key.last_used = now;
+        Some(key.key.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(keys: &SessionKeys) -> (u64, Vec<u8>) {
+        let exchange = KeyExchange::new();
+        let response = keys.handshake(Handshake { public_key: exchange.public_key() }).expect("Handshake failed");
+        (response.key_id, exchange.session_key(&response.public_key, true).unwrap())
+    }
+
+    #[test]
+    fn session_key_test() {
+        let keys = SessionKeys::new();
+        let (first_id, first_key) = handshake(&keys);
+        // more handshakes (e.g. from the CLI) don't make the back-end forget a key which is in use
+        for _ in 0..64 {
+            handshake(&keys);
+        }
+        assert_eq!(keys.get(first_id).as_deref(), Some(&first_key), "Session key was forgotten");
+        assert!(keys.get(first_id ^ 1).is_none(), "Unknown key id was accepted");
+    }
+
+    #[test]
+    fn session_key_limit_test() {
+        let keys = SessionKeys::new();
+        let (first_id, _) = handshake(&keys);
+        for _ in 0..MAX_SESSION_KEYS {
+            handshake(&keys);
+        }
+        assert_eq!(keys.keys.lock().unwrap().keys.len(), MAX_SESSION_KEYS, "Session keys grew past the limit");
+        assert!(keys.get(first_id).is_none(), "Least recently used session key was kept");
+    }
+}
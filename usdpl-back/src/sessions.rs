@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Amount of call ids before the highest one which are still accepted
+const WINDOW_SIZE: u64 = u128::BITS as u64;
+/// How long a session is kept after its last call
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// Most sessions kept at once, the least recently seen one is dropped to make room
+const MAX_SESSIONS: usize = 1024;
+/// How often timed out sessions are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why a call was rejected
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    /// The call id was already used in this session
+    Replayed,
+    /// The call id is too far behind the session's highest call id to tell whether it was used
+    TooOld,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Replayed => write!(f, "call id was already used"),
+            Self::TooOld => write!(f, "call id is too old"),
+        }
+    }
+}
+
+struct Session {
+    /// Highest call id received
+    highest: u64,
+    /// Call ids received, where bit n is `highest - n`
+    received: u128,
+    last_seen: Instant,
+}
+
+impl Session {
+    fn new(id: u64, now: Instant) -> Self {
+        Self {
+            highest: id,
+            received: 1,
+            last_seen: now,
+        }
+    }
+
+    fn accept(&mut self, id: u64, now: Instant) -> Result<(), Rejection> {
+        if id > self.highest {
+            let shift = id - self.highest;
+            self.received = if shift >= WINDOW_SIZE { 0 } else { self.received << shift };
+            self.received |= 1;
+            self.highest = id;
+        } else {
+            let offset = self.highest - id;
+            if offset >= WINDOW_SIZE {
+                return Err(Rejection::TooOld);
+            }
+            if self.received & (1 << offset) != 0 {
+                return Err(Rejection::Replayed);
+            }
+            self.received |= 1 << offset;
+        }
+        self.last_seen = now;
+        Ok(())
+    }
+}
+
+struct SessionMap {
+    sessions: HashMap<u64, Session>,
+    last_pruned: Instant,
+}
+
+impl SessionMap {
+    /// Make room for a new session
+    fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.last_pruned) >= PRUNE_INTERVAL {
+            self.sessions.retain(|_, s| now.duration_since(s.last_seen) < SESSION_TIMEOUT);
+            self.last_pruned = now;
+        }
+        if self.sessions.len() >= MAX_SESSIONS {
+            let oldest = self.sessions.iter()
+                .min_by_key(|(_, s)| s.last_seen)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                log::debug!("Too many USDPL sessions, dropping session {}", oldest);
+                self.sessions.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Call ids received from each front-end, to reject replayed calls
+pub(crate) struct Sessions {
+    sessions: Mutex<SessionMap>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self {
+            sessions: Mutex::new(SessionMap {
+                sessions: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Accept the call if its id has not been used in the session before
+    pub fn accept(&self, session: u64, id: u64) -> Result<(), Rejection> {
+        let now = Instant::now();
+        let mut map = self.sessions.lock().expect("Failed to acquire sessions lock");
+        if let Some(existing) = map.sessions.get_mut(&session) {
+            return existing.accept(id, now);
+        }
+        map.prune(now);
+        log::debug!("New USDPL session {} (sessions: {})", session, map.sessions.len() + 1);
+        map.sessions.insert(session, Session::new(id, now));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window_test() {
+        let sessions = Sessions::new();
+        assert_eq!(sessions.accept(1, 0), Ok(()));
+        assert_eq!(sessions.accept(1, 2), Ok(()));
+        assert_eq!(sessions.accept(1, 1), Ok(()), "Out of order call rejected");
+        assert_eq!(sessions.accept(1, 2), Err(Rejection::Replayed));
+        assert_eq!(sessions.accept(1, 1000), Ok(()));
+        assert_eq!(sessions.accept(1, 1000 - WINDOW_SIZE + 1), Ok(()), "Call at the end of the window rejected");
+        assert_eq!(sessions.accept(1, 1000 - WINDOW_SIZE), Err(Rejection::TooOld));
+    }
+
+    #[test]
+    fn separate_sessions_test() {
+        let sessions = Sessions::new();
+        assert_eq!(sessions.accept(1, 0), Ok(()));
+        assert_eq!(sessions.accept(2, 0), Ok(()), "Call from another session rejected");
+        assert_eq!(sessions.accept(2, 0), Err(Rejection::Replayed));
+        assert_eq!(sessions.accept(2, 1), Ok(()));
+    }
+
+    #[test]
+    fn session_limit_test() {
+        let sessions = Sessions::new();
+        for session in 0..(MAX_SESSIONS as u64 * 2) {
+            assert_eq!(sessions.accept(session, 0), Ok(()));
+        }
+        let map = sessions.sessions.lock().unwrap();
+        assert_eq!(map.sessions.len(), MAX_SESSIONS, "Sessions grew past the limit");
+        assert!(map.sessions.contains_key(&(MAX_SESSIONS as u64 * 2 - 1)), "Newest session was dropped");
+    }
+}
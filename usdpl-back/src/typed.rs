@@ -0,0 +1,323 @@
+use std::marker::PhantomData;
+
+use usdpl_core::serdes::Primitive;
+use usdpl_core::{CallError, ErrorCode};
+
+use super::callable::{CallResult, FallibleAsyncCallable, FallibleCallable, FallibleMutCallable};
+
+/// Errors from converting call parameters into typed function arguments
+#[derive(Debug)]
+pub enum ArgumentError {
+    /// Wrong number of parameters
+    Count {
+        /// Amount of parameters the function takes
+        expected: usize,
+        /// Amount of parameters the front-end sent
+        got: usize,
+    },
+    /// Parameter cannot be converted into the argument's type
+    Type {
+        /// Position of the parameter
+        index: usize,
+        /// Why the conversion failed
+        message: String,
+    },
+}
+
+impl std::fmt::Display for ArgumentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Count { expected, got } => write!(f, "ArgumentError: expected {} parameters, got {}", expected, got),
+            Self::Type { index, message } => write!(f, "ArgumentError: parameter {}: {}", index, message),
+        }
+    }
+}
+
+impl std::error::Error for ArgumentError {}
+
+impl std::convert::From<ArgumentError> for CallError {
+    fn from(err: ArgumentError) -> Self {
+        Self::new(ErrorCode::InvalidParameters, err.to_string())
+    }
+}
+
+/// Function arguments which can be built from the front-end's call parameters
+pub trait FromPrimitives: Sized {
+    /// Convert the parameters into arguments
+    fn from_primitives(params: Vec<Primitive>) -> Result<Self, ArgumentError>;
+}
+
+/// Function results which can be sent back to the front-end
+pub trait IntoPrimitives {
+    /// Convert the result into the response, or the error the function failed with
+    fn into_primitives(self) -> Result<Vec<Primitive>, CallError>;
+}
+
+macro_rules! count {
+    () => { 0usize };
+    ($head:ident $($tail:ident)*) => { 1usize + count!($($tail)*) };
+}
+
+macro_rules! tuple_impl {
+    ($($arg:ident),*) => {
+        impl<$($arg),*> FromPrimitives for ($($arg,)*)
+        where
+            $($arg: TryFrom<Primitive>, <$arg as TryFrom<Primitive>>::Error: std::fmt::Display,)*
+        {
+            #[allow(unused_variables, unused_mut)]
+            fn from_primitives(params: Vec<Primitive>) -> Result<Self, ArgumentError> {
+                let expected = count!($($arg)*);
+                if params.len() != expected {
+                    return Err(ArgumentError::Count { expected, got: params.len() });
+                }
+                let mut params = params.into_iter().enumerate();
+                Ok(($({
+                    let (index, param) = params.next().unwrap();
+                    $arg::try_from(param).map_err(|e| ArgumentError::Type { index, message: e.to_string() })?
+                },)*))
+            }
+        }
+
+        impl<$($arg),*> IntoPrimitives for ($($arg,)*)
+        where
+            $($arg: Into<Primitive>,)*
+        {
+            #[allow(non_snake_case)]
+            fn into_primitives(self) -> Result<Vec<Primitive>, CallError> {
+                let ($($arg,)*) = self;
+                Ok(vec![$($arg.into()),*])
+            }
+        }
+
+        impl<F, R, $($arg),*> TypedCallable<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync,
+            R: IntoPrimitives,
+            ($($arg,)*): FromPrimitives,
+        {
+            #[allow(non_snake_case)]
+            fn call(&self, params: Vec<Primitive>) -> CallResult {
+                let ($($arg,)*) = <($($arg,)*)>::from_primitives(params).map_err(CallError::from)?;
+                (self)($($arg),*).into_primitives()
+            }
+        }
+
+        impl<F, R, $($arg),*> TypedMutCallable<($($arg,)*)> for F
+        where
+            F: FnMut($($arg),*) -> R + Send + Sync,
+            R: IntoPrimitives,
+            ($($arg,)*): FromPrimitives,
+        {
+            #[allow(non_snake_case)]
+            fn call(&mut self, params: Vec<Primitive>) -> CallResult {
+                let ($($arg,)*) = <($($arg,)*)>::from_primitives(params).map_err(CallError::from)?;
+                (self)($($arg),*).into_primitives()
+            }
+        }
+
+        #[async_trait::async_trait]
+        impl<F, A, R, $($arg),*> TypedAsyncCallable<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> A + Send + Sync,
+            A: core::future::Future<Output=R> + Send,
+            R: IntoPrimitives,
+            ($($arg,)*): FromPrimitives,
+            $($arg: Send + 'static,)*
+        {
+            #[allow(non_snake_case)]
+            async fn call(&self, params: Vec<Primitive>) -> CallResult {
+                let ($($arg,)*) = <($($arg,)*)>::from_primitives(params).map_err(CallError::from)?;
+                (self)($($arg),*).await.into_primitives()
+            }
+        }
+    };
+}
+
+/// A function with typed arguments and result which can be called from the front-end (remotely).
+/// This is implemented for functions with up to 8 arguments which convert from `Primitive`.
+pub trait TypedCallable<Args>: Send + Sync {
+    /// Invoke the function, converting the parameters and result
+    fn call(&self, params: Vec<Primitive>) -> CallResult;
+}
+
+/// A mutable function with typed arguments and result which can be called from the front-end (remotely).
+/// This is implemented for functions with up to 8 arguments which convert from `Primitive`.
+pub trait TypedMutCallable<Args>: Send + Sync {
+    /// Invoke the function, converting the parameters and result
+    fn call(&mut self, params: Vec<Primitive>) -> CallResult;
+}
+
+/// An async function with typed arguments and result which can be called from the front-end (remotely).
+/// This is implemented for functions with up to 8 arguments which convert from `Primitive`.
+#[async_trait::async_trait]
+pub trait TypedAsyncCallable<Args>: Send + Sync {
+    /// Invoke the function, converting the parameters and result
+    async fn call(&self, params: Vec<Primitive>) -> CallResult;
+}
+
+tuple_impl! {}
+tuple_impl! {A0}
+tuple_impl! {A0, A1}
+tuple_impl! {A0, A1, A2}
+tuple_impl! {A0, A1, A2, A3}
+tuple_impl! {A0, A1, A2, A3, A4}
+tuple_impl! {A0, A1, A2, A3, A4, A5}
+tuple_impl! {A0, A1, A2, A3, A4, A5, A6}
+tuple_impl! {A0, A1, A2, A3, A4, A5, A6, A7}
+
+macro_rules! into_primitives_impl {
+    ($type:ty) => {
+        impl IntoPrimitives for $type {
+            fn into_primitives(self) -> Result<Vec<Primitive>, CallError> {
+                Ok(vec![self.into()])
+            }
+        }
+    };
+}
+
+into_primitives_impl! {Primitive}
+into_primitives_impl! {String}
+into_primitives_impl! {&str}
+into_primitives_impl! {Vec<u8>}
+into_primitives_impl! {&[u8]}
+into_primitives_impl! {Vec<(String, Primitive)>}
+into_primitives_impl! {std::collections::HashMap<String, Primitive>}
+into_primitives_impl! {bool}
+into_primitives_impl! {u32}
+into_primitives_impl! {u64}
+into_primitives_impl! {i32}
+into_primitives_impl! {i64}
+into_primitives_impl! {f32}
+into_primitives_impl! {f64}
+
+/// Multiple results; use `Primitive::Array` to send a single array instead
+impl IntoPrimitives for Vec<Primitive> {
+    fn into_primitives(self) -> Result<Vec<Primitive>, CallError> {
+        Ok(self)
+    }
+}
+
+/// Any serde type, converted with `Primitive::from_serialize`
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> IntoPrimitives for usdpl_core::serdes::Serde<T> {
+    fn into_primitives(self) -> Result<Vec<Primitive>, CallError> {
+        Primitive::from_serialize(&self.0)
+            .map(|x| vec![x])
+            .map_err(|e| CallError::new(ErrorCode::Internal, e.to_string()))
+    }
+}
+
+impl<T: IntoPrimitives, E: Into<CallError>> IntoPrimitives for Result<T, E> {
+    fn into_primitives(self) -> Result<Vec<Primitive>, CallError> {
+        match self {
+            Ok(x) => x.into_primitives(),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Adapter from a typed callable to a type-erased callable
+pub(crate) struct Typed<F, Args> {
+    f: F,
+    _args: PhantomData<fn(Args)>,
+}
+
+impl<F, Args> Typed<F, Args> {
+    pub fn new(f: F) -> Self {
+        Self {
+            f,
+            _args: PhantomData,
+        }
+    }
+}
+
+impl<F: TypedCallable<Args>, Args> FallibleCallable for Typed<F, Args> {
+    fn call(&self, params: Vec<Primitive>) -> CallResult {
+        self.f.call(params)
+    }
+}
+
+impl<F: TypedMutCallable<Args>, Args> FallibleMutCallable for Typed<F, Args> {
+    fn call(&mut self, params: Vec<Primitive>) -> CallResult {
+        self.f.call(params)
+    }
+}
+
+#[async_trait::async_trait]
+impl<F: TypedAsyncCallable<Args>, Args> FallibleAsyncCallable for Typed<F, Args> {
+    async fn call(&self, params: Vec<Primitive>) -> CallResult {
+        self.f.call(params).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed<Args, F: TypedCallable<Args>>(f: F, params: Vec<Primitive>) -> CallResult {
+        f.call(params)
+    }
+
+    #[test]
+    fn typed_call_test() {
+        let result = typed(|watts: u32, persist: bool| -> Result<String, String> {
+            Ok(format!("{} {}", watts, persist))
+        }, vec![15f64.into(), true.into()]).expect("Call not ok");
+        assert_eq!(result.len(), 1, "Expected single result");
+        if let Primitive::String(s) = &result[0] {
+            assert_eq!(s, "15 true");
+        } else {
+            panic!("Result is not String");
+        }
+    }
+
+    #[test]
+    fn typed_bytes_test() {
+        let result = typed(|data: Vec<u8>| data.into_iter().rev().collect::<Vec<u8>>(), vec![vec![1u8, 2, 3].into()])
+            .expect("Call not ok");
+        if let Primitive::Bytes(b) = &result[0] {
+            assert_eq!(b, &[3, 2, 1]);
+        } else {
+            panic!("Result is not Bytes");
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn typed_serde_test() {
+        use usdpl_core::serdes::Serde;
+        let result = typed(|Serde(limits): Serde<Vec<(String, u8)>>| Serde(limits.into_iter().max_by_key(|l| l.1)), vec![
+            Primitive::Array(vec![
+                Primitive::Array(vec!["low".into(), 1u32.into()]),
+                Primitive::Array(vec!["high".into(), 9u32.into()]),
+            ]),
+        ]).expect("Call not ok");
+        assert!(matches!(&result[0], Primitive::Array(max) if matches!(&max[0], Primitive::String(s) if s == "high")));
+        let err = typed(|_: Serde<Vec<u8>>| (), vec!["not a list".into()]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidParameters);
+    }
+
+    #[test]
+    fn typed_arity_test() {
+        let err = typed(|_: u32| (), vec![]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidParameters);
+        assert_eq!(err.message, "ArgumentError: expected 1 parameters, got 0");
+    }
+
+    #[test]
+    fn typed_type_test() {
+        let err = typed(|_: u32, _: bool| (), vec![1u32.into(), "true".into()]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidParameters);
+        assert_eq!(err.message, "ArgumentError: parameter 1: ConvertError: String cannot be converted into bool");
+    }
+
+    #[test]
+    fn typed_error_result_test() {
+        let err = typed(|| -> Result<(), &str> { Err("nope") }, vec![]).unwrap_err();
+        assert_eq!(err, CallError::new(ErrorCode::Failed, "nope"));
+        let err = typed(|| -> Result<(), CallError> {
+            Err(CallError::new(ErrorCode::Rejected, "not now"))
+        }, vec![]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Rejected, "Expected error code to be kept");
+    }
+}
@@ -0,0 +1,5 @@
+[![Crates.io](https://img.shields.io/crates/v/usdpl-client?style=flat-square)](https://crates.io/crates/usdpl-client)
+
+# {{crate}}
+
+{{readme}}
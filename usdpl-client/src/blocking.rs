@@ -0,0 +1,116 @@
+//! Synchronous client, for code which is not async
+use std::time::Duration;
+
+use usdpl_core::serdes::Primitive;
+use usdpl_core::{socket, CallError};
+
+use super::ClientResult;
+
+/// Client for calling functions on a back-end instance running on this machine, blocking the calling thread.
+/// This must not be used from within an async runtime.
+pub struct Client {
+    inner: super::Client,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl Client {
+    /// Initialise a client for the back-end listening on this port
+    pub fn new(port_usdpl: u16) -> Self {
+        Self {
+            inner: super::Client::new(port_usdpl),
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap(),
+        }
+    }
+
+    /// Encrypt packets with this key, for back-ends built with the `encrypt` feature
+    #[cfg(feature = "encrypt")]
+    pub fn with_key(mut self, key: Vec<u8>) -> Self {
+        self.inner = self.inner.with_key(key);
+        self
+    }
+
+    /// Agree on a session key with the back-end, which is used to encrypt everything afterwards.
+    /// The key given to `with_key` is only used to authenticate the handshake.
+    #[cfg(feature = "encrypt")]
+    pub fn handshake(&self) -> ClientResult<()> {
+        self.runtime.block_on(self.inner.handshake())
+    }
+
+    /// Call a function on the back-end
+    pub fn call<S: Into<String>>(&self, name: S, parameters: Vec<Primitive>) -> ClientResult<Vec<Primitive>> {
+        self.runtime.block_on(self.inner.call(name, parameters))
+    }
+
+    /// Call a function on the back-end, which gives up on the call after the timeout
+    pub fn call_with_timeout<S: Into<String>>(&self, name: S, parameters: Vec<Primitive>, timeout: Duration) -> ClientResult<Vec<Primitive>> {
+        self.runtime.block_on(self.inner.call_with_timeout(name, parameters, timeout))
+    }
+
+    /// Call many functions on the back-end with one request.
+    /// Results are in the same order as the calls.
+    pub fn batch<I, S>(&self, calls: I) -> ClientResult<Vec<Result<Vec<Primitive>, CallError>>>
+    where
+        I: IntoIterator<Item = (S, Vec<Primitive>)>,
+        S: Into<String>,
+    {
+        self.runtime.block_on(self.inner.batch(calls))
+    }
+
+    /// Send a packet to the back-end and receive its response
+    pub fn send(&self, packet: socket::Packet) -> ClientResult<socket::Packet> {
+        self.runtime.block_on(self.inner.send(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usdpl_core::ErrorCode;
+
+    fn client(port: u16) -> Client {
+        let client = Client::new(port);
+        #[cfg(feature = "encrypt")]
+        let client = client.with_key(hex::decode(env!("USDPL_ENCRYPTION_KEY")).unwrap());
+        client
+    }
+
+    #[test]
+    fn call_test() {
+        // the back-end listens on any free port, which it writes to the discovery file
+        let discovery_file = std::env::temp_dir().join(format!("usdpl-client-test-{}.port", std::process::id()));
+        let instance = usdpl_back::Instance::new(0)
+            .with_discovery_file(&discovery_file)
+            .register_typed("add", |a: u32, b: u32| a + b);
+        let shutdown = instance.shutdown_handle();
+        let server = std::thread::spawn(move || instance.run_blocking());
+        let mut port = None;
+        for _ in 0..50 {
+            port = std::fs::read_to_string(&discovery_file).ok().and_then(|x| x.parse().ok());
+            if port.is_some() {
+                break;
+            }
+            // back-end is not listening yet
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        let client = client(port.expect("Back-end did not write its discovery file"));
+        let result = client.call("add", vec![1u32.into(), 2u32.into()]);
+        assert!(matches!(result.expect("Call failed")[..], [Primitive::U32(3)]), "Unexpected call result");
+
+        #[cfg(feature = "encrypt")]
+        client.handshake().expect("Handshake failed");
+
+        let results = client.batch([
+            ("add", vec![40u32.into(), 2u32.into()]),
+            ("missing", vec![]),
+        ]).expect("Batch failed");
+        assert_eq!(results.len(), 2, "Expected a result per call");
+        assert!(matches!(results[0].as_deref(), Ok([Primitive::U32(42)])), "Unexpected first batch result");
+        assert_eq!(results[1].as_ref().unwrap_err().code, ErrorCode::UnknownFunction);
+
+        shutdown.shutdown();
+        server.join().unwrap().expect("Back-end did not stop cleanly");
+    }
+}
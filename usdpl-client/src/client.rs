@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "encrypt")]
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Method, Request};
+
+use usdpl_core::serdes::{Dumpable, Loadable, Primitive};
+use usdpl_core::{socket, CallError, RemoteCall};
+#[cfg(feature = "encrypt")]
+use usdpl_core::handshake::{Handshake, KeyExchange, SessionKey};
+
+use super::{ClientError, ClientResult};
+
+/// Client for calling functions on a back-end instance running on this machine
+pub struct Client {
+    http: hyper::Client<HttpConnector>,
+    port: u16,
+    id: AtomicU64,
+    session: u64,
+    #[cfg(feature = "encrypt")]
+    encryption_key: Option<Vec<u8>>,
+    #[cfg(feature = "encrypt")]
+    session_key: Mutex<Option<SessionKey>>,
+    #[cfg(feature = "encrypt")]
+    replay_guard: usdpl_core::serdes::ReplayGuard,
+}
+
+/// A random session id, so that clients don't share a session
+fn random_session() -> u64 {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).expect("Failed to generate USDPL session id");
+    u64::from_le_bytes(bytes)
+}
+
+impl Client {
+    /// Initialise a client for the back-end listening on this port
+    pub fn new(port_usdpl: u16) -> Self {
+        Self {
+            http: hyper::Client::new(),
+            port: port_usdpl,
+            id: AtomicU64::new(0),
+            session: random_session(),
+            #[cfg(feature = "encrypt")]
+            encryption_key: None,
+            #[cfg(feature = "encrypt")]
+            session_key: Mutex::new(None),
+            #[cfg(feature = "encrypt")]
+            replay_guard: Default::default(),
+        }
+    }
+
+    /// Encrypt packets with this key, for back-ends built with the `encrypt` feature
+    #[cfg(feature = "encrypt")]
+    pub fn with_key(mut self, key: Vec<u8>) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Agree on a session key with the back-end, which is used to encrypt everything afterwards.
+    /// The key given to `with_key` is only used to authenticate the handshake.
+    #[cfg(feature = "encrypt")]
+    pub async fn handshake(&self) -> ClientResult<()> {
+        let exchange = KeyExchange::new();
+        let key = self.encryption_key.clone().map(|key| Key { id: None, key });
+        let response = self.send_internal(
+            socket::Packet::Handshake(Handshake { public_key: exchange.public_key() }),
+            key.as_ref(),
+        ).await?;
+        match response {
+            socket::Packet::HandshakeResponse(response) => {
+                let key = exchange.session_key(&response.public_key, true).ok_or(ClientError::UnexpectedResponse)?;
+                *self.session_key.lock().expect("Failed to acquire session key lock") = Some(SessionKey {
+                    id: response.key_id,
+                    key,
+                });
+                Ok(())
+            },
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Call a function on the back-end
+    pub async fn call<S: Into<String>>(&self, name: S, parameters: Vec<Primitive>) -> ClientResult<Vec<Primitive>> {
+        self.call_until(name.into(), parameters, 0).await
+    }
+
+    /// Call a function on the back-end, which gives up on the call after the timeout
+    pub async fn call_with_timeout<S: Into<String>>(&self, name: S, parameters: Vec<Primitive>, timeout: Duration) -> ClientResult<Vec<Primitive>> {
+        let deadline = SystemTime::now() + timeout;
+        let deadline = deadline.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        self.call_until(name.into(), parameters, deadline).await
+    }
+
+    async fn call_until(&self, function: String, parameters: Vec<Primitive>, deadline: u64) -> ClientResult<Vec<Primitive>> {
+        let id = self.next_id();
+        let response = self.send(socket::Packet::Call(RemoteCall {
+            id,
+            session: self.session,
+            deadline,
+            function,
+            parameters,
+        })).await?;
+        call_result(id, response)
+    }
+
+    /// Call many functions on the back-end with one request.
+    /// Results are in the same order as the calls.
+    pub async fn batch<I, S>(&self, calls: I) -> ClientResult<Vec<Result<Vec<Primitive>, CallError>>>
+    where
+        I: IntoIterator<Item = (S, Vec<Primitive>)>,
+        S: Into<String>,
+    {
+        let calls: Vec<RemoteCall> = calls.into_iter()
+            .map(|(name, parameters)| RemoteCall {
+                id: self.next_id(),
+                session: self.session,
+                deadline: 0,
+                function: name.into(),
+                parameters,
+            })
+            .collect();
+        let ids: Vec<u64> = calls.iter().map(|c| c.id).collect();
+        let response = self.send(socket::Packet::Many(calls.into_iter().map(socket::Packet::Call).collect())).await?;
+        match response {
+            socket::Packet::Many(responses) if responses.len() == ids.len() => ids.into_iter()
+                .zip(responses)
+                .map(|(id, response)| match call_result(id, response) {
+                    Ok(result) => Ok(Ok(result)),
+                    Err(ClientError::Call(e)) => Ok(Err(e)),
+                    Err(e) => Err(e),
+                })
+                .collect(),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Send a packet to the back-end and receive its response
+    pub async fn send(&self, packet: socket::Packet) -> ClientResult<socket::Packet> {
+        #[cfg(feature = "encrypt")]
+        let key = self.session_key
+            .lock()
+            .expect("Failed to acquire session key lock")
+            .clone()
+            .map(|session_key| Key { id: Some(session_key.id), key: session_key.key })
+            .or_else(|| self.encryption_key.clone().map(|key| Key { id: None, key }));
+        self.send_internal(packet, #[cfg(feature = "encrypt")] key.as_ref()).await
+    }
+
+    async fn send_internal(
+        &self,
+        packet: socket::Packet,
+        #[cfg(feature = "encrypt")]
+        key: Option<&Key>,
+    ) -> ClientResult<socket::Packet> {
+        let url = format!("http://{}/usdpl/call", socket::socket_addr(self.port));
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .body(Body::from(self.dump(&packet, #[cfg(feature = "encrypt")] key)?))
+            .expect("Invalid USDPL request");
+        let response = self.http.request(request).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            return Err(ClientError::Status(status.as_u16(), String::from_utf8_lossy(&body).into()));
+        }
+        self.load(&body, #[cfg(feature = "encrypt")] key)
+    }
+
+    fn dump(
+        &self,
+        packet: &socket::Packet,
+        #[cfg(feature = "encrypt")]
+        key: Option<&Key>,
+    ) -> ClientResult<Vec<u8>> {
+        #[cfg(feature = "encrypt")]
+        if let Some(key) = key {
+            let mut buffer = Vec::with_capacity(socket::PACKET_BUFFER_SIZE);
+            // session keys are sent with their id, so the back-end knows which key to decrypt with
+            let len = match key.id {
+                Some(id) => packet.dump_encrypted_with_id(&mut buffer, &SessionKey { id, key: key.key.clone() })?,
+                None => packet.dump_encrypted(&mut buffer, &key.key)?,
+            };
+            buffer.truncate(len);
+            return Ok(buffer);
+        }
+        let mut buffer = String::with_capacity(socket::PACKET_BUFFER_SIZE);
+        packet.dump_base64(&mut buffer)?;
+        Ok(buffer.into_bytes())
+    }
+
+    fn load(
+        &self,
+        data: &[u8],
+        #[cfg(feature = "encrypt")]
+        key: Option<&Key>,
+    ) -> ClientResult<socket::Packet> {
+        #[cfg(feature = "encrypt")]
+        if let Some(key) = key {
+            return Ok(socket::Packet::load_encrypted(data, &key.key, &self.replay_guard)?.0);
+        }
+        Ok(socket::Packet::load_base64(data)?.0)
+    }
+}
+
+/// Key to encrypt a request with, and its id when it's a session key
+#[cfg(feature = "encrypt")]
+struct Key {
+    id: Option<u64>,
+    key: Vec<u8>,
+}
+
+/// Unpack the response to the call with this id
+fn call_result(id: u64, response: socket::Packet) -> ClientResult<Vec<Primitive>> {
+    match response {
+        socket::Packet::CallResponse(resp) if resp.id == id => Ok(resp.response),
+        socket::Packet::CallError(resp) if resp.id == id => Err(ClientError::Call(resp.error)),
+        _ => Err(ClientError::UnexpectedResponse),
+    }
+}
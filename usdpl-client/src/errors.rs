@@ -0,0 +1,61 @@
+use usdpl_core::serdes::{DumpError, LoadError};
+use usdpl_core::CallError;
+
+/// Errors from talking to the back-end
+#[derive(Debug)]
+pub enum ClientError {
+    /// HTTP request failed
+    Http(hyper::Error),
+    /// Back-end responded with an unsuccessful HTTP status
+    Status(u16, String),
+    /// Request packet cannot be encoded
+    Dump(DumpError),
+    /// Response packet cannot be decoded
+    Load(LoadError),
+    /// Back-end responded with a different packet than expected
+    UnexpectedResponse,
+    /// Remote call failed
+    Call(CallError),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "ClientError: Http({})", err),
+            Self::Status(code, body) => write!(f, "ClientError: Status {} ({})", code, body),
+            Self::Dump(err) => write!(f, "ClientError: Dump({})", err),
+            Self::Load(err) => write!(f, "ClientError: Load({})", err),
+            Self::UnexpectedResponse => write!(f, "ClientError: UnexpectedResponse"),
+            Self::Call(err) => write!(f, "ClientError: Call({})", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl std::convert::From<hyper::Error> for ClientError {
+    fn from(err: hyper::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+impl std::convert::From<DumpError> for ClientError {
+    fn from(err: DumpError) -> Self {
+        Self::Dump(err)
+    }
+}
+
+impl std::convert::From<LoadError> for ClientError {
+    fn from(err: LoadError) -> Self {
+        Self::Load(err)
+    }
+}
+
+impl std::convert::From<CallError> for ClientError {
+    fn from(err: CallError) -> Self {
+        Self::Call(err)
+    }
+}
+
+/// Result of talking to the back-end
+pub type ClientResult<T> = Result<T, ClientError>;
@@ -0,0 +1,14 @@
+//! Native client library for talking to a plugin back-end.
+//! Targets x86_64 (native Steam Deck ISA).
+//!
+//! This is useful for integration tests and developer tools, where the WASM front-end is not available.
+//!
+#![warn(missing_docs)]
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod client;
+mod errors;
+
+pub use client::Client;
+pub use errors::{ClientError, ClientResult};
@@ -0,0 +1,154 @@
+//! Session key exchange.
+//! The front-end and back-end agree on a session key with an ephemeral X25519 key exchange.
+//! Handshake packets are encrypted with the compile-time key, which authenticates both sides.
+//!
+//! The back-end gives every session key an id, which is sent before each message encrypted with it.
+//! Key ids have their highest bit set, while messages encrypted with the compile-time key start with
+//! the timestamp of their nonce, which never has it set. So the back-end can tell which key a message
+//! is encrypted with, without trying every key.
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::serdes::{Dumpable, Loadable};
+
+/// Size of a public key, in bytes
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+/// Size of a session key id, in bytes
+pub const KEY_ID_SIZE: usize = 8;
+
+/// Context string for deriving session keys
+const SESSION_KEY_CONTEXT: &[u8] = b"usdpl-session-key";
+/// Set in every session key id
+const KEY_ID_FLAG: u64 = 1 << 63;
+
+/// Request to start a session, sent by the front-end
+#[derive(Dumpable, Loadable, Debug)]
+pub struct Handshake {
+    /// The front-end's ephemeral public key
+    pub public_key: Vec<u8>,
+}
+
+/// Response to a handshake, sent by the back-end
+#[derive(Dumpable, Loadable, Debug)]
+pub struct HandshakeResponse {
+    /// The back-end's ephemeral public key
+    pub public_key: Vec<u8>,
+    /// Id of the new session key (see `new_key_id`)
+    pub key_id: u64,
+}
+
+/// Key agreed on through a handshake, with the id the back-end knows it by
+#[derive(Clone, Debug)]
+pub struct SessionKey {
+    /// Sent before every message encrypted with the key
+    pub id: u64,
+    /// The AES-256 key
+    pub key: Vec<u8>,
+}
+
+/// Generate a random session key id
+pub fn new_key_id() -> Result<u64, getrandom::Error> {
+    let mut id = [0u8; KEY_ID_SIZE];
+    getrandom::getrandom(&mut id)?;
+    Ok(u64::from_le_bytes(id) | KEY_ID_FLAG)
+}
+
+/// Split the session key id off the start of an encrypted message.
+/// Returns None as the id when the message is encrypted with the compile-time key.
+pub fn split_key_id(buffer: &[u8]) -> (Option<u64>, &[u8]) {
+    if buffer.len() < KEY_ID_SIZE {
+        return (None, buffer);
+    }
+    let (id, rest) = buffer.split_at(KEY_ID_SIZE);
+    let id = u64::from_le_bytes(id.try_into().unwrap());
+    match id & KEY_ID_FLAG {
+        0 => (None, buffer),
+        _ => (Some(id), rest),
+    }
+}
+
+/// One side of a key exchange.
+/// This can only be used for a single session key.
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public_key: PublicKey,
+}
+
+impl KeyExchange {
+    /// Generate a new ephemeral key pair
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random();
+        let public_key = PublicKey::from(&secret);
+        Self { secret, public_key }
+    }
+
+    /// The public key to send to the other side
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.as_bytes().to_vec()
+    }
+
+    /// Derive the session key from the other side's public key.
+    /// `initiator` is true for the side which sent the handshake (the front-end).
+    /// Returns None if the other side's public key is invalid.
+    pub fn session_key(self, their_public_key: &[u8], initiator: bool) -> Option<Vec<u8>> {
+        let their_public_key: [u8; PUBLIC_KEY_SIZE] = their_public_key.try_into().ok()?;
+        let their_public_key = PublicKey::from(their_public_key);
+        let (initiator_key, responder_key) = if initiator {
+            (self.public_key, their_public_key)
+        } else {
+            (their_public_key, self.public_key)
+        };
+        let shared = self.secret.diffie_hellman(&their_public_key);
+        if !shared.was_contributory() {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(SESSION_KEY_CONTEXT);
+        hasher.update(shared.as_bytes());
+        hasher.update(initiator_key.as_bytes());
+        hasher.update(responder_key.as_bytes());
+        Some(hasher.finalize().to_vec())
+    }
+}
+
+impl std::default::Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_exchange_test() {
+        let front = KeyExchange::new();
+        let back = KeyExchange::new();
+        let front_public = front.public_key();
+        let back_public = back.public_key();
+        let front_key = front.session_key(&back_public, true).expect("Front-end key exchange failed");
+        let back_key = back.session_key(&front_public, false).expect("Back-end key exchange failed");
+        assert_eq!(front_key, back_key, "Session keys do not match");
+        assert_eq!(front_key.len(), 32, "Session key is not an AES-256 key");
+    }
+
+    #[test]
+    fn key_id_test() {
+        let id = new_key_id().unwrap();
+        let mut buffer = id.to_le_bytes().to_vec();
+        buffer.extend_from_slice(b"message");
+        assert_eq!(split_key_id(&buffer), (Some(id), &b"message"[..]), "Key id was not split off");
+        // a nonce starts with the current time, which must not look like a key id
+        let mut nonce = 1_700_000_000_000u64.to_le_bytes().to_vec();
+        nonce.extend_from_slice(b"message");
+        assert_eq!(split_key_id(&nonce), (None, &nonce[..]), "Nonce was taken for a key id");
+    }
+
+    #[test]
+    fn key_exchange_invalid_test() {
+        assert!(KeyExchange::new().session_key(&[0u8; PUBLIC_KEY_SIZE], true).is_none(), "Low-order key accepted");
+        assert!(KeyExchange::new().session_key(&[1u8; 3], true).is_none(), "Short key accepted");
+    }
+}
@@ -2,7 +2,11 @@
 //! This contains serialization functionality and networking datatypes.
 #![warn(missing_docs)]
 
+// allow derive macros to refer to this crate by name
+extern crate self as usdpl_core;
+
 mod remote_call;
+mod remote_event;
 
 #[cfg(not(any(feature = "decky", feature = "crankshaft")))]
 mod api_any;
@@ -12,19 +16,24 @@ mod api_crankshaft;
 #[cfg(all(feature = "decky", not(any(feature = "crankshaft"))))]
 mod api_decky;
 
+#[cfg(feature = "encrypt")]
+pub mod handshake;
 pub mod serdes;
 pub mod socket;
 
-pub use remote_call::{RemoteCall, RemoteCallResponse};
+pub use remote_call::{CallError, ErrorCode, RemoteCall, RemoteCallError, RemoteCallResponse};
+pub use remote_event::{RemoteEvent, RemoteEvents};
 
 /// USDPL core API.
 /// This contains functionality used in both the back-end and front-end.
 pub mod api {
     #[cfg(not(any(feature = "decky", feature = "crankshaft")))]
+    #[allow(unused_imports)]
     pub use super::api_any::*;
     pub use super::api_common::*;
     #[cfg(all(feature = "crankshaft", not(any(feature = "decky"))))]
     pub use super::api_crankshaft::*;
     #[cfg(all(feature = "decky", not(any(feature = "crankshaft"))))]
+    #[allow(unused_imports)]
     pub use super::api_decky::*;
 }
@@ -3,33 +3,45 @@ use std::io::{Read, Write};
 use crate::serdes::{DumpError, Dumpable, LoadError, Loadable, Primitive};
 
 /// Remote call packet representing a function to call on the back-end, sent from the front-end
+#[derive(Dumpable, Loadable, Debug, Clone)]
 pub struct RemoteCall {
-    /// The call id assigned by the front-end
+    /// The call id assigned by the front-end, which must not be reused in the session
     pub id: u64,
+    /// The front-end's session, chosen when it is initialised
+    pub session: u64,
+    /// When the front-end stops waiting for the response, in milliseconds since the Unix epoch (0 for never)
+    pub deadline: u64,
     /// The function's name
     pub function: String,
     /// The function's input parameters
     pub parameters: Vec<Primitive>,
 }
 
-impl Loadable for RemoteCall {
-    fn load(buffer: &mut dyn Read) -> Result<(Self, usize), LoadError> {
-        let (id_num, len0) = u64::load(buffer)?;
-        let (function_name, len1) = String::load(buffer)?;
-        let (params, len2) = Vec::<Primitive>::load(buffer)?;
+impl RemoteCall {
+    /// Whether the call can be sent in the layout which front-ends before sessions and deadlines used
+    pub(crate) fn is_legacy(&self) -> bool {
+        self.session == 0 && self.deadline == 0
+    }
+
+    /// Load a call without session and deadline, as sent by older front-ends
+    pub(crate) fn load_legacy(buffer: &mut dyn Read) -> Result<(Self, usize), LoadError> {
+        let (id, len0) = u64::load(buffer)?;
+        let (function, len1) = String::load(buffer)?;
+        let (parameters, len2) = Vec::<Primitive>::load(buffer)?;
         Ok((
             Self {
-                id: id_num,
-                function: function_name,
-                parameters: params,
+                id,
+                session: 0,
+                deadline: 0,
+                function,
+                parameters,
             },
             len0 + len1 + len2,
         ))
     }
-}
 
-impl Dumpable for RemoteCall {
-    fn dump(&self, buffer: &mut dyn Write) -> Result<usize, DumpError> {
+    /// Dump a call without session and deadline, which older back-ends understand
+    pub(crate) fn dump_legacy(&self, buffer: &mut dyn Write) -> Result<usize, DumpError> {
         let len0 = self.id.dump(buffer)?;
         let len1 = self.function.dump(buffer)?;
         let len2 = self.parameters.dump(buffer)?;
@@ -38,6 +50,7 @@ impl Dumpable for RemoteCall {
 }
 
 /// Remote call response packet representing the response from a remote call after the back-end has executed it.
+#[derive(Dumpable, Loadable, Debug)]
 pub struct RemoteCallResponse {
     /// The call id from the RemoteCall
     pub id: u64,
@@ -45,25 +58,97 @@ pub struct RemoteCallResponse {
     pub response: Vec<Primitive>,
 }
 
-impl Loadable for RemoteCallResponse {
-    fn load(buffer: &mut dyn Read) -> Result<(Self, usize), LoadError> {
-        let (id_num, len0) = u64::load(buffer)?;
-        let (response_var, len1) = Vec::<Primitive>::load(buffer)?;
-        Ok((
-            Self {
-                id: id_num,
-                response: response_var,
-            },
-            len0 + len1,
-        ))
+/// Remote call error packet representing a remote call which the back-end could not (successfully) execute.
+#[derive(Dumpable, Loadable, Debug)]
+pub struct RemoteCallError {
+    /// The call id from the RemoteCall
+    pub id: u64,
+    /// What went wrong
+    pub error: CallError,
+}
+
+/// Category of call failure
+#[derive(Dumpable, Loadable, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// No function is registered with the called name
+    UnknownFunction,
+    /// The call was refused before it was executed (e.g. a suspicious call ID)
+    Rejected,
+    /// The parameters do not match what the function expects
+    InvalidParameters,
+    /// The function returned an error
+    Failed,
+    /// Something went wrong in the back-end while handling the call
+    Internal,
+    /// The call could not be sent or the response could not be understood
+    Transport,
+    /// The function did not complete before the call's deadline or timeout
+    Timeout,
+}
+
+impl ErrorCode {
+    /// Name of the error code, as exposed to Javascript
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::UnknownFunction => "UnknownFunction",
+            Self::Rejected => "Rejected",
+            Self::InvalidParameters => "InvalidParameters",
+            Self::Failed => "Failed",
+            Self::Internal => "Internal",
+            Self::Transport => "Transport",
+            Self::Timeout => "Timeout",
+        }
     }
 }
 
-impl Dumpable for RemoteCallResponse {
-    fn dump(&self, buffer: &mut dyn Write) -> Result<usize, DumpError> {
-        let len0 = self.id.dump(buffer)?;
-        let len1 = self.response.dump(buffer)?;
-        Ok(len0 + len1)
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Error from a remote call
+#[derive(Dumpable, Loadable, Debug, Clone, PartialEq)]
+pub struct CallError {
+    /// Category of the failure
+    pub code: ErrorCode,
+    /// Human-readable description of the failure
+    pub message: String,
+}
+
+impl CallError {
+    /// Build an error from its parts
+    pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CallError: {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for CallError {}
+
+impl std::convert::From<String> for CallError {
+    fn from(message: String) -> Self {
+        Self::new(ErrorCode::Failed, message)
+    }
+}
+
+impl std::convert::From<&str> for CallError {
+    fn from(message: &str) -> Self {
+        Self::new(ErrorCode::Failed, message)
+    }
+}
+
+impl std::convert::From<crate::serdes::ConvertError> for CallError {
+    fn from(err: crate::serdes::ConvertError) -> Self {
+        Self::new(ErrorCode::InvalidParameters, err.to_string())
     }
 }
 
@@ -75,6 +160,8 @@ mod tests {
     fn remote_call_idempotence_test() {
         let call = RemoteCall{
             id: 42,
+            session: 7,
+            deadline: 1_700_000_000_000,
             function: "something very long just in case this causes unexpected issues".into(),
             parameters: vec!["param1".into(), 42f64.into()],
         };
@@ -88,6 +175,8 @@ mod tests {
         assert_eq!(len, loaded_len, "Expected load and dump lengths to match");
 
         assert_eq!(loaded_call.id, call.id, "RemoteCall.id does not match");
+        assert_eq!(loaded_call.session, call.session, "RemoteCall.session does not match");
+        assert_eq!(loaded_call.deadline, call.deadline, "RemoteCall.deadline does not match");
         assert_eq!(loaded_call.function, call.function, "RemoteCall.function does not match");
         if let Primitive::String(loaded) = &loaded_call.parameters[0] {
             if let Primitive::String(original) = &call.parameters[0] {
@@ -108,4 +197,20 @@ mod tests {
             panic!("Loaded call parameter 1 is not f64")
         }
     }
+
+    #[test]
+    fn remote_call_error_idempotence_test() {
+        let error = RemoteCallError {
+            id: 42,
+            error: CallError::new(ErrorCode::UnknownFunction, "no function named `test`"),
+        };
+
+        let mut buffer = String::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
+        let len = error.dump_base64(&mut buffer).unwrap();
+        let (loaded_error, loaded_len) = RemoteCallError::load_base64(buffer.as_bytes()).unwrap();
+        assert_eq!(len, loaded_len, "Expected load and dump lengths to match");
+
+        assert_eq!(loaded_error.id, error.id, "RemoteCallError.id does not match");
+        assert_eq!(loaded_error.error, error.error, "RemoteCallError.error does not match");
+    }
 }
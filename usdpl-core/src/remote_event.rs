@@ -0,0 +1,44 @@
+use crate::serdes::{Dumpable, Loadable, Primitive};
+
+/// Event pushed from the back-end to the front-end
+#[derive(Dumpable, Loadable, Clone, Debug)]
+pub struct RemoteEvent {
+    /// The event's topic, which front-end listeners subscribe to
+    pub topic: String,
+    /// The event's data
+    pub data: Vec<Primitive>,
+}
+
+/// Events emitted by the back-end, sent in response to an event poll
+#[derive(Dumpable, Loadable, Debug)]
+pub struct RemoteEvents {
+    /// The id of the latest event emitted by the back-end, to poll from next
+    pub last_id: u64,
+    /// Events emitted since the polled id, oldest first
+    pub events: Vec<RemoteEvent>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_events_idempotence_test() {
+        let events = RemoteEvents {
+            last_id: 42,
+            events: vec![RemoteEvent {
+                topic: "battery".into(),
+                data: vec![42u32.into()],
+            }],
+        };
+
+        let mut buffer = String::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
+        let len = events.dump_base64(&mut buffer).unwrap();
+        let (loaded, loaded_len) = RemoteEvents::load_base64(buffer.as_bytes()).unwrap();
+        assert_eq!(len, loaded_len, "Expected load and dump lengths to match");
+
+        assert_eq!(loaded.last_id, events.last_id, "RemoteEvents.last_id does not match");
+        assert_eq!(loaded.events.len(), 1, "RemoteEvents.events length does not match");
+        assert_eq!(loaded.events[0].topic, events.events[0].topic, "RemoteEvent.topic does not match");
+    }
+}
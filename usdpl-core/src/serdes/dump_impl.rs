@@ -7,7 +7,7 @@ impl Dumpable for String {
         let str_bytes = self.as_bytes();
         let len_bytes = (str_bytes.len() as u32).to_le_bytes();
         let size1 = buffer.write(&len_bytes).map_err(DumpError::Io)?;
-        let size2 = buffer.write(&str_bytes).map_err(DumpError::Io)?;
+        let size2 = buffer.write(str_bytes).map_err(DumpError::Io)?;
         Ok(size1 + size2)
     }
 }
@@ -0,0 +1,124 @@
+use std::cell::Cell;
+use std::io::Read;
+
+use super::LoadError;
+
+/// Vectors don't allocate space for more items than this up front,
+/// so a large length prefix only costs memory once the items actually arrive
+const MAX_PREALLOCATION: usize = 4096;
+
+/// Limits on data loaded with `Loadable`, so a malformed packet can't make the loader
+/// allocate huge amounts of memory or overflow the stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadLimits {
+    /// Maximum length of a string (in bytes) or vector (in items)
+    pub max_length: usize,
+    /// Maximum amount of bytes read in total
+    pub max_bytes: usize,
+    /// Maximum amount of vectors nested in each other (this includes arrays, maps and batches of packets)
+    pub max_depth: usize,
+}
+
+impl LoadLimits {
+    /// Limits used when no others are set: 16 MiB strings and vectors, any amount of bytes and 128 levels of nesting
+    pub const DEFAULT: Self = Self {
+        max_length: 1 << 24,
+        max_bytes: usize::MAX,
+        max_depth: 128,
+    };
+
+    /// Use these limits for everything loaded on this thread while `load` runs
+    pub fn scope<T, F: FnOnce() -> T>(self, load: F) -> T {
+        let previous = LIMITS.with(|l| l.replace(self));
+        // restore even when load panics, since the thread is reused
+        struct Restore(LoadLimits);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                LIMITS.with(|l| l.set(self.0));
+            }
+        }
+        let _restore = Restore(previous);
+        load()
+    }
+
+    /// The limits in use on this thread
+    pub fn current() -> Self {
+        LIMITS.with(|l| l.get())
+    }
+}
+
+impl Default for LoadLimits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+thread_local! {
+    static LIMITS: Cell<LoadLimits> = const { Cell::new(LoadLimits::DEFAULT) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Fail when a length prefix is larger than allowed.
+/// Returns how many items may be allocated up front.
+pub(crate) fn check_length(length: usize) -> Result<usize, LoadError> {
+    if length > LoadLimits::current().max_length {
+        return Err(LoadError::TooLong(length));
+    }
+    Ok(length.min(MAX_PREALLOCATION))
+}
+
+/// Load a vector, failing when vectors are nested too deep
+pub(crate) fn nested<T, E: From<LoadError>, F: FnOnce() -> Result<T, E>>(load: F) -> Result<T, E> {
+    let depth = DEPTH.with(|d| d.get());
+    if depth >= LoadLimits::current().max_depth {
+        return Err(LoadError::TooDeep.into());
+    }
+    DEPTH.with(|d| d.set(depth + 1));
+    // restore even when load panics, since the thread is reused
+    struct Restore(usize);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            DEPTH.with(|d| d.set(self.0));
+        }
+    }
+    let _restore = Restore(depth);
+    load()
+}
+
+/// Reader which fails once more than the allowed amount of bytes are read
+pub(crate) struct LimitedReader<'a> {
+    inner: &'a mut dyn Read,
+    remaining: usize,
+    exceeded: bool,
+}
+
+impl<'a> LimitedReader<'a> {
+    pub fn new(inner: &'a mut dyn Read, max_bytes: usize) -> Self {
+        Self {
+            inner,
+            remaining: max_bytes,
+            exceeded: false,
+        }
+    }
+
+    /// Replace the read error with `LoadError::TooManyBytes` when it was caused by the limit
+    pub fn check<T>(&self, result: Result<T, LoadError>) -> Result<T, LoadError> {
+        match result {
+            Err(_) if self.exceeded => Err(LoadError::TooManyBytes),
+            result => result,
+        }
+    }
+}
+
+impl Read for LimitedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 && !buf.is_empty() {
+            self.exceeded = true;
+            return Err(std::io::Error::other("Load limit reached"));
+        }
+        let max = buf.len().min(self.remaining);
+        let len = self.inner.read(&mut buf[..max])?;
+        self.remaining -= len;
+        Ok(len)
+    }
+}
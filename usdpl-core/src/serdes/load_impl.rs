@@ -1,5 +1,6 @@
 use std::io::Read;
 
+use super::limits::{check_length, nested};
 use super::{LoadError, Loadable};
 
 impl Loadable for String {
@@ -8,7 +9,7 @@ impl Loadable for String {
         buffer.read_exact(&mut u32_bytes).map_err(LoadError::Io)?;
         let str_size = u32::from_le_bytes(u32_bytes) as usize;
         //let mut str_buf = String::with_capacity(str_size);
-        let mut str_buf = Vec::with_capacity(str_size);
+        let mut str_buf = Vec::with_capacity(check_length(str_size)?);
         let mut byte_buf = [u8::MAX; 1];
         for _ in 0..str_size {
             buffer.read_exact(&mut byte_buf).map_err(LoadError::Io)?;
@@ -28,12 +29,15 @@ impl<T: Loadable> Loadable for Vec<T> {
         buffer.read_exact(&mut u32_bytes).map_err(LoadError::Io)?;
         let count = u32::from_le_bytes(u32_bytes) as usize;
         let mut cursor = 4;
-        let mut items = Vec::with_capacity(count);
-        for _ in 0..count {
-            let (obj, len) = T::load(buffer)?;
-            cursor += len;
-            items.push(obj);
-        }
+        let mut items = Vec::with_capacity(check_length(count)?);
+        nested(|| {
+            for _ in 0..count {
+                let (obj, len) = T::load(buffer)?;
+                cursor += len;
+                items.push(obj);
+            }
+            Ok(())
+        })?;
         Ok((items, cursor))
     }
 }
@@ -141,6 +145,7 @@ int_impl! {f64, 8}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::serdes::LoadLimits;
     use std::io::Cursor;
 
     macro_rules! test_impl {
@@ -158,6 +163,61 @@ mod tests {
         };
     }
 
+    #[test]
+    fn length_limit_test() {
+        // claims 4 GiB, which must fail before anything is allocated
+        let result = Vec::<u64>::load(&mut Cursor::new([u8::MAX; 4]));
+        assert!(matches!(result, Err(LoadError::TooLong(len)) if len == u32::MAX as usize), "Huge length was accepted");
+        let limits = LoadLimits {
+            max_length: 3,
+            ..LoadLimits::DEFAULT
+        };
+        let result = String::load_limited(&mut Cursor::new([4u8, 0, 0, 0, 116, 101, 115, 116]), limits);
+        assert!(matches!(result, Err(LoadError::TooLong(4))), "String longer than the limit was loaded");
+        // claims more than there is, which fails when the data runs out
+        let result = String::load(&mut Cursor::new([0u8, 0, 0, 1, 116]));
+        assert!(matches!(result, Err(LoadError::Io(_))), "Truncated string was loaded");
+    }
+
+    #[test]
+    fn bytes_limit_test() {
+        let data = [4u8, 0, 0, 0, 116, 101, 115, 116];
+        let limits = LoadLimits {
+            max_bytes: data.len() - 1,
+            ..LoadLimits::DEFAULT
+        };
+        let result = String::load_limited(&mut Cursor::new(data), limits);
+        assert!(matches!(result, Err(LoadError::TooManyBytes)), "More bytes than the limit were loaded");
+        let limits = LoadLimits {
+            max_bytes: data.len(),
+            ..LoadLimits::DEFAULT
+        };
+        let (obj, len) = String::load_limited(&mut Cursor::new(data), limits).expect("Load to the limit not ok");
+        assert_eq!((obj.as_str(), len), ("test", data.len()));
+        assert_eq!(LoadLimits::current(), LoadLimits::DEFAULT, "Limits were not restored");
+    }
+
+    #[test]
+    fn limits_restored_on_panic_test() {
+        let limits = LoadLimits {
+            max_length: 3,
+            ..LoadLimits::DEFAULT
+        };
+        let result = std::panic::catch_unwind(|| limits.scope(|| panic!("load panicked")));
+        assert!(result.is_err());
+        assert_eq!(LoadLimits::current(), LoadLimits::DEFAULT, "Limits were not restored after a panic");
+        let limits = LoadLimits {
+            max_depth: 2,
+            ..LoadLimits::DEFAULT
+        };
+        let result = std::panic::catch_unwind(|| nested::<(), LoadError, _>(|| panic!("load panicked")));
+        assert!(result.is_err());
+        // one vector in another only fits when the depth was restored
+        let data = [1u8, 0, 0, 0, 0, 0, 0, 0];
+        let result = Vec::<Vec<u8>>::load_limited(&mut Cursor::new(data), limits);
+        assert!(result.is_ok(), "Depth was not restored after a panic");
+    }
+
     test_impl! {string_load_test, [4u8, 0, 0, 0, 116, 101, 115, 116, 0, 128], String, 8, "test"}
     test_impl! {
         vec_load_test,
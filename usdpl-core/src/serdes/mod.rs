@@ -2,9 +2,120 @@
 //! Little endian is preferred.
 
 mod dump_impl;
+mod limits;
 mod load_impl;
+#[cfg(feature = "encrypt")]
+mod nonce;
 mod primitive;
+#[cfg(feature = "serde")]
+mod serde_error;
+#[cfg(feature = "serde")]
+mod serde_primitive;
+#[cfg(feature = "serde")]
+mod serde_wire;
 mod traits;
 
-pub use primitive::Primitive;
+pub use limits::LoadLimits;
+#[cfg(feature = "encrypt")]
+pub use nonce::ReplayGuard;
+pub use primitive::{ConvertError, Primitive};
+#[cfg(feature = "serde")]
+pub use serde_error::SerdeError;
+#[cfg(feature = "serde")]
+pub use serde_primitive::Serde;
+#[cfg(feature = "serde")]
+pub use serde_wire::{from_bytes, to_bytes, Deserializer, Serializer};
 pub use traits::{DumpError, Dumpable, LoadError, Loadable};
+pub use usdpl_derive::{Dumpable, Loadable};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Dumpable, Loadable, Debug, PartialEq)]
+    struct NamedStruct {
+        a: u8,
+        b: String,
+        c: Vec<u16>,
+    }
+
+    #[derive(Dumpable, Loadable, Debug, PartialEq)]
+    struct TupleStruct(bool, i32);
+
+    #[derive(Dumpable, Loadable, Debug, PartialEq)]
+    struct UnitStruct;
+
+    #[derive(Dumpable, Loadable, Debug, PartialEq)]
+    #[usdpl(crate = "crate")]
+    struct GenericStruct<T> {
+        inner: T,
+    }
+
+    #[derive(Dumpable, Loadable, Debug, PartialEq)]
+    enum TestEnum {
+        Unit,
+        Tuple(u8, String),
+        Named { x: u32 },
+        #[usdpl(discriminant = 42)]
+        Explicit,
+        AfterExplicit(TupleStruct),
+    }
+
+    #[test]
+    fn derive_struct_dump_test() {
+        let data = NamedStruct {
+            a: 1,
+            b: "test".into(),
+            c: vec![2],
+        };
+        let mut buffer = Vec::with_capacity(128);
+        let len = data.dump(&mut buffer).expect("Dump not ok");
+        assert_eq!(len, 15, "Wrong amount written");
+        assert_eq!(buffer, [1, 4, 0, 0, 0, 116, 101, 115, 116, 1, 0, 0, 0, 2, 0]);
+    }
+
+    #[test]
+    fn derive_enum_discriminant_test() {
+        let cases = [
+            (TestEnum::Unit, 1u8),
+            (TestEnum::Tuple(0, "".into()), 2),
+            (TestEnum::Named { x: 0 }, 3),
+            (TestEnum::Explicit, 42),
+            (TestEnum::AfterExplicit(TupleStruct(false, 0)), 43),
+        ];
+        for (data, discriminant) in cases {
+            let mut buffer = Vec::with_capacity(128);
+            data.dump(&mut buffer).expect("Dump not ok");
+            assert_eq!(buffer[0], discriminant, "Wrong discriminant for {:?}", data);
+        }
+    }
+
+    #[test]
+    fn derive_unknown_discriminant_test() {
+        let result = TestEnum::load(&mut std::io::Cursor::new([4u8]));
+        assert!(matches!(result, Err(LoadError::InvalidData)), "Expected unknown discriminant to be invalid");
+    }
+
+    #[test]
+    fn derive_base64_idempotence_test() {
+        let data = vec![
+            TestEnum::Unit,
+            TestEnum::Tuple(42, "something".into()),
+            TestEnum::Named { x: u32::MAX },
+            TestEnum::Explicit,
+            TestEnum::AfterExplicit(TupleStruct(true, -1)),
+        ];
+        let mut buffer = String::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
+        let len = data.dump_base64(&mut buffer).unwrap();
+        let (loaded, loaded_len) = Vec::<TestEnum>::load_base64(buffer.as_bytes()).unwrap();
+        assert_eq!(len, loaded_len, "Expected load and dump lengths to match");
+        assert_eq!(data, loaded, "Data written and read does not match");
+
+        let data = (UnitStruct, GenericStruct { inner: "generic".to_string() });
+        let mut buffer = String::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
+        let len = data.dump_base64(&mut buffer).unwrap();
+        let (loaded, loaded_len) = <(UnitStruct, GenericStruct<String>)>::load_base64(buffer.as_bytes()).unwrap();
+        assert_eq!(len, loaded_len, "Expected load and dump lengths to match");
+        assert_eq!(data, loaded, "Data written and read does not match");
+    }
+}
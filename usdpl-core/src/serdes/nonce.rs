@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::socket::NONCE_SIZE;
+
+use super::LoadError;
+
+/// Size of the timestamp at the start of a nonce
+const TIMESTAMP_SIZE: usize = 8;
+/// Default maximum age of an encrypted message
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn now_millis() -> u64 {
+    js_sys::Date::now() as u64
+}
+
+/// Generate a fresh nonce: the current time in milliseconds (little endian) followed by random bytes.
+/// The timestamp is authenticated along with the message, since the nonce is an input to the cipher.
+pub(super) fn generate() -> Result<[u8; NONCE_SIZE], getrandom::Error> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..TIMESTAMP_SIZE].copy_from_slice(&now_millis().to_le_bytes());
+    getrandom::getrandom(&mut nonce[TIMESTAMP_SIZE..])?;
+    Ok(nonce)
+}
+
+fn timestamp(nonce: &[u8; NONCE_SIZE]) -> u64 {
+    let mut bytes = [0u8; TIMESTAMP_SIZE];
+    bytes.copy_from_slice(&nonce[..TIMESTAMP_SIZE]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Rejects encrypted messages which were received before or are too old.
+/// Every receiver of encrypted messages should keep one of these for as long as it runs.
+pub struct ReplayGuard {
+    max_age_millis: u128,
+    /// Nonces seen within the max age, with their timestamps
+    seen: Mutex<HashMap<[u8; NONCE_SIZE], u64>>,
+}
+
+impl ReplayGuard {
+    /// Reject messages with a timestamp further than `max_age` from now
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age_millis: max_age.as_millis(),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accept the nonce if it is recent and has not been accepted before
+    pub(super) fn check(&self, nonce: &[u8; NONCE_SIZE]) -> Result<(), LoadError> {
+        let now = now_millis();
+        let sent = timestamp(nonce);
+        if now.abs_diff(sent) as u128 > self.max_age_millis {
+            return Err(LoadError::Replayed);
+        }
+        let mut seen = self.seen.lock().expect("Failed to acquire replay guard lock");
+        // anything older than the max age is rejected by its timestamp, so it doesn't need remembering
+        let max_age_millis = self.max_age_millis;
+        seen.retain(|_, sent| (now.abs_diff(*sent) as u128) <= max_age_millis);
+        if seen.insert(*nonce, sent).is_some() {
+            return Err(LoadError::Replayed);
+        }
+        Ok(())
+    }
+}
+
+impl std::default::Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_AGE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_guard_test() {
+        let guard = ReplayGuard::default();
+        let nonce = generate().unwrap();
+        assert!(guard.check(&nonce).is_ok(), "Fresh nonce rejected");
+        assert!(matches!(guard.check(&nonce), Err(LoadError::Replayed)), "Replayed nonce accepted");
+        assert!(guard.check(&generate().unwrap()).is_ok(), "Second fresh nonce rejected");
+
+        let mut old = generate().unwrap();
+        old[..TIMESTAMP_SIZE].copy_from_slice(&(now_millis() - 120_000).to_le_bytes());
+        assert!(matches!(guard.check(&old), Err(LoadError::Replayed)), "Old nonce accepted");
+    }
+}
@@ -1,8 +1,10 @@
+use std::collections::HashMap;
 use std::io::{Read, Write};
 use super::{DumpError, Dumpable, LoadError, Loadable};
 
 /// Primitive types supported for communication between the USDPL back- and front-end.
 /// These are used for sending over the TCP connection.
+#[derive(Debug, Clone)]
 pub enum Primitive {
     /// Null or unsupported object
     Empty,
@@ -24,6 +26,12 @@ pub enum Primitive {
     Bool(bool),
     /// Non-primitive in Json format
     Json(String),
+    /// Raw bytes
+    Bytes(Vec<u8>),
+    /// Array of primitives, which may be nested
+    Array(Vec<Primitive>),
+    /// Map of names to primitives (like a Javascript object), in order
+    Map(Vec<(String, Primitive)>),
 }
 
 impl Primitive {
@@ -40,10 +48,58 @@ impl Primitive {
             Self::I64(_) => 8,
             Self::Bool(_) => 9,
             Self::Json(_) => 10,
+            Self::Bytes(_) => 11,
+            Self::Array(_) => 12,
+            Self::Map(_) => 13,
         }
     }
+
+    /// Human-readable name of the variant, for error messages
+    pub const fn type_name(&self) -> &'static str {
+        match self {
+            Self::Empty => "Empty",
+            Self::String(_) => "String",
+            Self::F32(_) => "F32",
+            Self::F64(_) => "F64",
+            Self::U32(_) => "U32",
+            Self::U64(_) => "U64",
+            Self::I32(_) => "I32",
+            Self::I64(_) => "I64",
+            Self::Bool(_) => "Bool",
+            Self::Json(_) => "Json",
+            Self::Bytes(_) => "Bytes",
+            Self::Array(_) => "Array",
+            Self::Map(_) => "Map",
+        }
+    }
+}
+
+/// Errors from converting a Primitive into a Rust type
+#[derive(Debug)]
+pub struct ConvertError {
+    /// Name of the Primitive variant
+    pub from: &'static str,
+    /// Name of the type it could not be converted into
+    pub into: &'static str,
+}
+
+impl ConvertError {
+    fn new(from: &Primitive, into: &'static str) -> Self {
+        Self {
+            from: from.type_name(),
+            into,
+        }
+    }
+}
+
+impl std::fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ConvertError: {} cannot be converted into {}", self.from, self.into)
+    }
 }
 
+impl std::error::Error for ConvertError {}
+
 impl Loadable for Primitive {
     fn load(buf: &mut dyn Read) -> Result<(Self, usize), LoadError> {
         let mut discriminant_buf = [u8::MAX; 1];
@@ -60,6 +116,9 @@ impl Loadable for Primitive {
             8 => i64::load(buf).map(|(obj, len)| (Self::I64(obj), len))?,
             9 => bool::load(buf).map(|(obj, len)| (Self::Bool(obj), len))?,
             10 => String::load(buf).map(|(obj, len)| (Self::Json(obj), len))?,
+            11 => Vec::<u8>::load(buf).map(|(obj, len)| (Self::Bytes(obj), len))?,
+            12 => Vec::<Primitive>::load(buf).map(|(obj, len)| (Self::Array(obj), len))?,
+            13 => Vec::<(String, Primitive)>::load(buf).map(|(obj, len)| (Self::Map(obj), len))?,
             _ => return Err(LoadError::InvalidData),
         };
         result.1 += 1;
@@ -81,28 +140,37 @@ impl Dumpable for Primitive {
             Self::I64(x) => x.dump(buf),
             Self::Bool(x) => x.dump(buf),
             Self::Json(x) => x.dump(buf),
+            Self::Bytes(x) => x.dump(buf),
+            Self::Array(x) => x.dump(buf),
+            Self::Map(x) => x.dump(buf),
         }?;
         Ok(size1 + result)
     }
 }
 
-impl std::convert::Into<Primitive> for &str {
-    fn into(self) -> Primitive {
-        Primitive::String(self.to_string())
+impl std::convert::From<&str> for Primitive {
+    fn from(other: &str) -> Self {
+        Primitive::String(other.to_string())
+    }
+}
+
+impl std::convert::From<&[u8]> for Primitive {
+    fn from(other: &[u8]) -> Self {
+        Primitive::Bytes(other.to_vec())
     }
 }
 
-impl std::convert::Into<Primitive> for () {
-    fn into(self) -> Primitive {
+impl std::convert::From<()> for Primitive {
+    fn from(_: ()) -> Self {
         Primitive::Empty
     }
 }
 
 macro_rules! into_impl {
     ($type:ty, $variant:ident) => {
-        impl std::convert::Into<Primitive> for $type {
-            fn into(self) -> Primitive {
-                Primitive::$variant(self)
+        impl std::convert::From<$type> for Primitive {
+            fn from(other: $type) -> Self {
+                Primitive::$variant(other)
             }
         }
     }
@@ -110,6 +178,15 @@ macro_rules! into_impl {
 
 into_impl! {String, String}
 into_impl! {bool, Bool}
+into_impl! {Vec<u8>, Bytes}
+into_impl! {Vec<Primitive>, Array}
+into_impl! {Vec<(String, Primitive)>, Map}
+
+impl std::convert::From<HashMap<String, Primitive>> for Primitive {
+    fn from(other: HashMap<String, Primitive>) -> Self {
+        Primitive::Map(other.into_iter().collect())
+    }
+}
 
 into_impl! {u32, U32}
 into_impl! {u64, U64}
@@ -120,9 +197,161 @@ into_impl! {i64, I64}
 into_impl! {f32, F32}
 into_impl! {f64, F64}
 
+impl std::convert::TryFrom<Primitive> for String {
+    type Error = ConvertError;
+
+    fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+        match other {
+            Primitive::String(s) => Ok(s),
+            _ => Err(ConvertError::new(&other, "String")),
+        }
+    }
+}
+
+impl std::convert::TryFrom<Primitive> for bool {
+    type Error = ConvertError;
+
+    fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+        match other {
+            Primitive::Bool(b) => Ok(b),
+            _ => Err(ConvertError::new(&other, "bool")),
+        }
+    }
+}
+
+impl std::convert::TryFrom<Primitive> for Vec<u8> {
+    type Error = ConvertError;
+
+    fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+        match other {
+            Primitive::Bytes(b) => Ok(b),
+            _ => Err(ConvertError::new(&other, "Vec<u8>")),
+        }
+    }
+}
+
+impl std::convert::TryFrom<Primitive> for Vec<Primitive> {
+    type Error = ConvertError;
+
+    fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+        match other {
+            Primitive::Array(a) => Ok(a),
+            _ => Err(ConvertError::new(&other, "Vec<Primitive>")),
+        }
+    }
+}
+
+impl std::convert::TryFrom<Primitive> for Vec<(String, Primitive)> {
+    type Error = ConvertError;
+
+    fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+        match other {
+            Primitive::Map(m) => Ok(m),
+            _ => Err(ConvertError::new(&other, "Vec<(String, Primitive)>")),
+        }
+    }
+}
+
+impl std::convert::TryFrom<Primitive> for HashMap<String, Primitive> {
+    type Error = ConvertError;
+
+    fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+        match other {
+            Primitive::Map(m) => Ok(m.into_iter().collect()),
+            _ => Err(ConvertError::new(&other, "HashMap<String, Primitive>")),
+        }
+    }
+}
+
+impl std::convert::TryFrom<Primitive> for () {
+    type Error = ConvertError;
+
+    fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+        match other {
+            Primitive::Empty => Ok(()),
+            _ => Err(ConvertError::new(&other, "()")),
+        }
+    }
+}
+
+/// Integers can be converted from any numeric Primitive, as long as the value fits exactly.
+/// Older front-ends send every Javascript number as F64, so whole floats are accepted too.
+macro_rules! try_from_int_impl {
+    ($type:ty) => {
+        impl std::convert::TryFrom<Primitive> for $type {
+            type Error = ConvertError;
+
+            fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+                let converted = match &other {
+                    Primitive::U32(x) => <$type>::try_from(*x).ok(),
+                    Primitive::U64(x) => <$type>::try_from(*x).ok(),
+                    Primitive::I32(x) => <$type>::try_from(*x).ok(),
+                    Primitive::I64(x) => <$type>::try_from(*x).ok(),
+                    Primitive::F32(x) => float_to_int(*x as f64).and_then(|i| <$type>::try_from(i).ok()),
+                    Primitive::F64(x) => float_to_int(*x).and_then(|i| <$type>::try_from(i).ok()),
+                    _ => None,
+                };
+                converted.ok_or_else(|| ConvertError::new(&other, stringify!($type)))
+            }
+        }
+    };
+}
+
+/// Largest integer a float holds exactly, like Javascript's `Number.MAX_SAFE_INTEGER`
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+/// The float as an integer, if it has no fractional part and was not rounded.
+/// Larger floats are whole numbers too, but may have been rounded from a different integer.
+fn float_to_int(x: f64) -> Option<i128> {
+    if x.abs() > MAX_SAFE_INTEGER {
+        return None;
+    }
+    let i = x as i128; // NaN becomes 0
+    if i as f64 == x {
+        Some(i)
+    } else {
+        None
+    }
+}
+
+try_from_int_impl! {u8}
+try_from_int_impl! {u16}
+try_from_int_impl! {u32}
+try_from_int_impl! {u64}
+
+try_from_int_impl! {i8}
+try_from_int_impl! {i16}
+try_from_int_impl! {i32}
+try_from_int_impl! {i64}
+
+/// Floats can be converted from any numeric Primitive
+macro_rules! try_from_float_impl {
+    ($type:ty) => {
+        impl std::convert::TryFrom<Primitive> for $type {
+            type Error = ConvertError;
+
+            fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+                match other {
+                    Primitive::F32(x) => Ok(x as _),
+                    Primitive::F64(x) => Ok(x as _),
+                    Primitive::U32(x) => Ok(x as _),
+                    Primitive::U64(x) => Ok(x as _),
+                    Primitive::I32(x) => Ok(x as _),
+                    Primitive::I64(x) => Ok(x as _),
+                    _ => Err(ConvertError::new(&other, stringify!($type))),
+                }
+            }
+        }
+    };
+}
+
+try_from_float_impl! {f32}
+try_from_float_impl! {f64}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::serdes::LoadLimits;
     use std::io::Cursor;
 
     #[test]
@@ -159,4 +388,83 @@ mod tests {
             panic!("Read non-string primitive");
         }
     }
+
+    #[test]
+    fn bytes_idempotence_test() {
+        let data = vec![0u8, 1, 2, 254, 255];
+        let primitive = Primitive::from(data.as_slice());
+        let mut buffer = Vec::with_capacity(128);
+        let write_len = primitive.dump(&mut buffer).expect("Dump not ok");
+        assert_eq!(buffer[0], 11, "Wrong discriminant for Bytes");
+        let (obj, read_len) = Primitive::load(&mut Cursor::new(buffer)).expect("Load not ok");
+        assert_eq!(
+            write_len, read_len,
+            "Amount written and amount read do not match"
+        );
+        assert_eq!(Vec::<u8>::try_from(obj).unwrap(), data, "Data written and read does not match");
+        assert!(Vec::<u8>::try_from(Primitive::String("bytes".into())).is_err(), "String converted to bytes");
+    }
+
+    #[test]
+    fn nested_idempotence_test() {
+        let primitive = Primitive::Map(vec![
+            ("name".into(), "test".into()),
+            ("values".into(), Primitive::Array(vec![1u32.into(), Primitive::Array(vec![true.into()])])),
+        ]);
+        let mut buffer = Vec::with_capacity(128);
+        let write_len = primitive.dump(&mut buffer).expect("Dump not ok");
+        assert_eq!(buffer[0], 13, "Wrong discriminant for Map");
+        let (obj, read_len) = Primitive::load(&mut Cursor::new(buffer)).expect("Load not ok");
+        assert_eq!(
+            write_len, read_len,
+            "Amount written and amount read do not match"
+        );
+        let map = HashMap::<String, Primitive>::try_from(obj).expect("Read non-map primitive");
+        assert!(matches!(&map["name"], Primitive::String(s) if s == "test"), "Map value does not match");
+        let values = Vec::<Primitive>::try_from(map["values"].clone()).expect("Read non-array value");
+        assert_eq!(values.len(), 2, "Array length does not match");
+        assert!(matches!(&values[1], Primitive::Array(a) if matches!(a[..], [Primitive::Bool(true)])));
+    }
+
+    #[test]
+    fn nesting_limit_test() {
+        let mut primitive = Primitive::Empty;
+        for _ in 0..LoadLimits::DEFAULT.max_depth + 1 {
+            primitive = Primitive::Array(vec![primitive]);
+        }
+        let mut buffer = Vec::with_capacity(1024);
+        primitive.dump(&mut buffer).expect("Dump not ok");
+        assert!(
+            matches!(Primitive::load(&mut Cursor::new(&buffer)), Err(LoadError::TooDeep)),
+            "Data nested too deep was loaded"
+        );
+        assert!(Primitive::load(&mut Cursor::new(&buffer[5..])).is_ok(), "Data nested to the limit was not loaded");
+    }
+
+    #[test]
+    fn try_from_int_test() {
+        assert_eq!(u32::try_from(Primitive::U32(42)).unwrap(), 42);
+        assert_eq!(u32::try_from(Primitive::I64(42)).unwrap(), 42);
+        assert_eq!(u32::try_from(Primitive::F64(42.0)).unwrap(), 42);
+        assert_eq!(i64::try_from(Primitive::F64(-42.0)).unwrap(), -42);
+        assert!(u32::try_from(Primitive::I32(-1)).is_err(), "Negative number converted to unsigned");
+        assert!(u8::try_from(Primitive::U32(256)).is_err(), "Out of range number converted");
+        assert!(u32::try_from(Primitive::F64(4.2)).is_err(), "Fractional number converted to integer");
+        assert!(u32::try_from(Primitive::F64(f64::NAN)).is_err(), "NaN converted to integer");
+        assert_eq!(u64::try_from(Primitive::F64(9007199254740991.0)).unwrap(), 9007199254740991);
+        // e.g. a Steam ID sent as a Javascript number, which was already rounded
+        assert!(u64::try_from(Primitive::F64(76561197960287930.0)).is_err(), "Unsafe integer converted exactly");
+        assert!(i64::try_from(Primitive::F64(-9007199254740992.0)).is_err(), "Unsafe integer converted exactly");
+        assert!(u32::try_from(Primitive::String("42".into())).is_err(), "String converted to integer");
+    }
+
+    #[test]
+    fn try_from_other_test() {
+        assert_eq!(f32::try_from(Primitive::F64(0.5)).unwrap(), 0.5);
+        assert_eq!(f64::try_from(Primitive::U64(42)).unwrap(), 42.0);
+        assert!(bool::try_from(Primitive::Bool(true)).unwrap());
+        assert_eq!(String::try_from(Primitive::String("test".into())).unwrap(), "test");
+        let err = String::try_from(Primitive::Bool(false)).unwrap_err();
+        assert_eq!(err.to_string(), "ConvertError: Bool cannot be converted into String");
+    }
 }
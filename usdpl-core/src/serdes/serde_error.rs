@@ -0,0 +1,51 @@
+use super::{DumpError, LoadError};
+
+/// Errors from converting serde types to and from USDPL data
+#[derive(Debug)]
+pub enum SerdeError {
+    /// Error reported by the type being (de)serialized
+    Custom(String),
+    /// The type uses something this format cannot represent
+    Unsupported(&'static str),
+    /// Writing failed
+    Dump(DumpError),
+    /// Reading failed
+    Load(LoadError),
+}
+
+impl std::fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Custom(msg) => write!(f, "SerdeError: {}", msg),
+            Self::Unsupported(what) => write!(f, "SerdeError: {} is not supported", what),
+            Self::Dump(err) => write!(f, "SerdeError: {}", err),
+            Self::Load(err) => write!(f, "SerdeError: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+impl serde::ser::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+impl serde::de::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+impl std::convert::From<DumpError> for SerdeError {
+    fn from(err: DumpError) -> Self {
+        Self::Dump(err)
+    }
+}
+
+impl std::convert::From<LoadError> for SerdeError {
+    fn from(err: LoadError) -> Self {
+        Self::Load(err)
+    }
+}
@@ -0,0 +1,695 @@
+//! serde support for Primitive.
+//! Structs and maps become `Primitive::Map`, sequences and tuples become `Primitive::Array`,
+//! unit variants become their name and other variants become a Map with only their name in it
+//! (like serde_json does it), so the front-end gets ordinary Javascript values.
+
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, EnumAccess, Error as _, IntoDeserializer, MapAccess, SeqAccess, VariantAccess,
+    Visitor,
+};
+use serde::ser::{
+    SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple, SerializeTupleStruct,
+    SerializeTupleVariant,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{Primitive, SerdeError};
+
+impl Primitive {
+    /// Convert any serde type into a Primitive
+    pub fn from_serialize<T: Serialize + ?Sized>(value: &T) -> Result<Self, SerdeError> {
+        value.serialize(PrimitiveSerializer)
+    }
+
+    /// Convert the Primitive into any serde type.
+    /// `Primitive::Json` is parsed as Json, so this also works on data from older front-ends.
+    pub fn deserialize_into<T: DeserializeOwned>(self) -> Result<T, SerdeError> {
+        T::deserialize(self)
+    }
+}
+
+/// Wrapper for sending serde types as call parameters or results,
+/// converted with `Primitive::from_serialize` and `Primitive::deserialize_into`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Serde<T>(pub T);
+
+impl<T: DeserializeOwned> std::convert::TryFrom<Primitive> for Serde<T> {
+    type Error = SerdeError;
+
+    fn try_from(other: Primitive) -> Result<Self, Self::Error> {
+        other.deserialize_into().map(Serde)
+    }
+}
+
+fn to_i64(v: i128) -> Result<Primitive, SerdeError> {
+    i64::try_from(v)
+        .map(Primitive::I64)
+        .map_err(|_| SerdeError::Unsupported("Integer larger than 64 bits"))
+}
+
+fn to_u64(v: u128) -> Result<Primitive, SerdeError> {
+    u64::try_from(v)
+        .map(Primitive::U64)
+        .map_err(|_| SerdeError::Unsupported("Integer larger than 64 bits"))
+}
+
+/// Enum variant with content, as a map with only the variant name in it
+fn variant(name: &'static str, content: Primitive) -> Primitive {
+    Primitive::Map(vec![(name.to_owned(), content)])
+}
+
+struct PrimitiveSerializer;
+
+impl serde::Serializer for PrimitiveSerializer {
+    type Ok = Primitive;
+    type Error = SerdeError;
+    type SerializeSeq = ArrayBuilder;
+    type SerializeTuple = ArrayBuilder;
+    type SerializeTupleStruct = ArrayBuilder;
+    type SerializeTupleVariant = ArrayBuilder;
+    type SerializeMap = MapBuilder;
+    type SerializeStruct = MapBuilder;
+    type SerializeStructVariant = MapBuilder;
+
+    fn serialize_bool(self, v: bool) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::I32(v as _))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::I32(v as _))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::I32(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::I64(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Primitive, SerdeError> {
+        to_i64(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::U32(v as _))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::U32(v as _))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::U32(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::U64(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Primitive, SerdeError> {
+        to_u64(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::F32(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::String(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::Bytes(v.to_owned()))
+    }
+
+    fn serialize_none(self) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::Empty)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Primitive, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::Empty)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::Empty)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str) -> Result<Primitive, SerdeError> {
+        Ok(Primitive::String(variant.to_owned()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Primitive, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant_name: &'static str,
+        value: &T,
+    ) -> Result<Primitive, SerdeError> {
+        Ok(variant(variant_name, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ArrayBuilder, SerdeError> {
+        Ok(ArrayBuilder::new(None, len.unwrap_or(0)))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ArrayBuilder, SerdeError> {
+        Ok(ArrayBuilder::new(None, len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<ArrayBuilder, SerdeError> {
+        Ok(ArrayBuilder::new(None, len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<ArrayBuilder, SerdeError> {
+        Ok(ArrayBuilder::new(Some(variant), len))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<MapBuilder, SerdeError> {
+        Ok(MapBuilder::new(None, len.unwrap_or(0)))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<MapBuilder, SerdeError> {
+        Ok(MapBuilder::new(None, len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<MapBuilder, SerdeError> {
+        Ok(MapBuilder::new(Some(variant), len))
+    }
+}
+
+/// Sequence, tuple or tuple variant being serialized
+struct ArrayBuilder {
+    variant: Option<&'static str>,
+    items: Vec<Primitive>,
+}
+
+impl ArrayBuilder {
+    fn new(variant: Option<&'static str>, len: usize) -> Self {
+        Self {
+            variant,
+            items: Vec::with_capacity(len),
+        }
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.items.push(value.serialize(PrimitiveSerializer)?);
+        Ok(())
+    }
+
+    fn build(self) -> Result<Primitive, SerdeError> {
+        let array = Primitive::Array(self.items);
+        Ok(match self.variant {
+            Some(name) => variant(name, array),
+            None => array,
+        })
+    }
+}
+
+macro_rules! array_builder_impl {
+    ($trait:ident, $fn:ident) => {
+        impl $trait for ArrayBuilder {
+            type Ok = Primitive;
+            type Error = SerdeError;
+
+            fn $fn<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+                self.push(value)
+            }
+
+            fn end(self) -> Result<Primitive, SerdeError> {
+                self.build()
+            }
+        }
+    };
+}
+
+array_builder_impl! {SerializeSeq, serialize_element}
+array_builder_impl! {SerializeTuple, serialize_element}
+array_builder_impl! {SerializeTupleStruct, serialize_field}
+array_builder_impl! {SerializeTupleVariant, serialize_field}
+
+/// Map, struct or struct variant being serialized
+struct MapBuilder {
+    variant: Option<&'static str>,
+    entries: Vec<(String, Primitive)>,
+    key: Option<String>,
+}
+
+impl MapBuilder {
+    fn new(variant: Option<&'static str>, len: usize) -> Self {
+        Self {
+            variant,
+            entries: Vec::with_capacity(len),
+            key: None,
+        }
+    }
+
+    fn insert<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<(), SerdeError> {
+        self.entries.push((key, value.serialize(PrimitiveSerializer)?));
+        Ok(())
+    }
+
+    fn build(self) -> Result<Primitive, SerdeError> {
+        let map = Primitive::Map(self.entries);
+        Ok(match self.variant {
+            Some(name) => variant(name, map),
+            None => map,
+        })
+    }
+}
+
+impl SerializeMap for MapBuilder {
+    type Ok = Primitive;
+    type Error = SerdeError;
+
+    /// Keys must be strings, or something which can be written as one (like numbers)
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        let key = match key.serialize(PrimitiveSerializer)? {
+            Primitive::String(s) => s,
+            Primitive::U32(x) => x.to_string(),
+            Primitive::U64(x) => x.to_string(),
+            Primitive::I32(x) => x.to_string(),
+            Primitive::I64(x) => x.to_string(),
+            Primitive::Bool(x) => x.to_string(),
+            _ => return Err(SerdeError::Unsupported("Map key which is not a string or number")),
+        };
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = self.key.take().ok_or_else(|| SerdeError::Custom("Map value without a key".to_owned()))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Primitive, SerdeError> {
+        self.build()
+    }
+}
+
+macro_rules! map_builder_impl {
+    ($trait:ident) => {
+        impl $trait for MapBuilder {
+            type Ok = Primitive;
+            type Error = SerdeError;
+
+            fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+                self.insert(key.to_owned(), value)
+            }
+
+            fn end(self) -> Result<Primitive, SerdeError> {
+                self.build()
+            }
+        }
+    };
+}
+
+map_builder_impl! {SerializeStruct}
+map_builder_impl! {SerializeStructVariant}
+
+impl<'de> IntoDeserializer<'de, SerdeError> for Primitive {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+fn json_error(err: serde_json::Error) -> SerdeError {
+    SerdeError::Custom(err.to_string())
+}
+
+/// Json primitives are parsed first, since the deserialized data may not borrow from them
+fn json(s: &str) -> Result<serde_json::Value, SerdeError> {
+    serde_json::from_str(s).map_err(json_error)
+}
+
+/// Numbers are converted like `TryFrom<Primitive>` does it,
+/// so whole F64 values from older front-ends are accepted as integers
+macro_rules! deserialize_number_impl {
+    ($fn:ident, $visit:ident, $type:ty) => {
+        fn $fn<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+            match self {
+                Primitive::Json(s) => json(&s)?.$fn(visitor).map_err(json_error),
+                other => visitor.$visit(<$type>::try_from(other).map_err(SerdeError::custom)?),
+            }
+        }
+    };
+}
+
+impl<'de> serde::Deserializer<'de> for Primitive {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self {
+            Primitive::Empty => visitor.visit_unit(),
+            Primitive::String(s) => visitor.visit_string(s),
+            Primitive::F32(x) => visitor.visit_f32(x),
+            Primitive::F64(x) => visitor.visit_f64(x),
+            Primitive::U32(x) => visitor.visit_u32(x),
+            Primitive::U64(x) => visitor.visit_u64(x),
+            Primitive::I32(x) => visitor.visit_i32(x),
+            Primitive::I64(x) => visitor.visit_i64(x),
+            Primitive::Bool(x) => visitor.visit_bool(x),
+            Primitive::Json(s) => json(&s)?.deserialize_any(visitor).map_err(json_error),
+            Primitive::Bytes(b) => visitor.visit_byte_buf(b),
+            Primitive::Array(items) => {
+                let mut seq = SeqDeserializer::new(items.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Primitive::Map(entries) => {
+                let mut map = MapDeserializer::new(entries.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+        }
+    }
+
+    deserialize_number_impl! {deserialize_i8, visit_i8, i8}
+    deserialize_number_impl! {deserialize_i16, visit_i16, i16}
+    deserialize_number_impl! {deserialize_i32, visit_i32, i32}
+    deserialize_number_impl! {deserialize_i64, visit_i64, i64}
+    deserialize_number_impl! {deserialize_u8, visit_u8, u8}
+    deserialize_number_impl! {deserialize_u16, visit_u16, u16}
+    deserialize_number_impl! {deserialize_u32, visit_u32, u32}
+    deserialize_number_impl! {deserialize_u64, visit_u64, u64}
+    deserialize_number_impl! {deserialize_f32, visit_f32, f32}
+    deserialize_number_impl! {deserialize_f64, visit_f64, f64}
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self {
+            // a Uint8Array is also a sequence of numbers, like for Vec<u8>
+            Primitive::Bytes(b) => {
+                let mut seq = SeqDeserializer::<_, SerdeError>::new(b.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self {
+            Primitive::Empty => visitor.visit_none(),
+            Primitive::Json(s) => json(&s)?.deserialize_option(visitor).map_err(json_error),
+            other => visitor.visit_some(other),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self {
+            Primitive::String(variant) => visitor.visit_enum(IntoDeserializer::<SerdeError>::into_deserializer(variant)),
+            Primitive::Map(mut entries) if entries.len() == 1 => {
+                let (variant, content) = entries.pop().unwrap();
+                visitor.visit_enum(VariantDeserializer { variant, content })
+            }
+            Primitive::Json(s) => json(&s)?
+                .deserialize_enum(name, variants, visitor)
+                .map_err(json_error),
+            other => Err(SerdeError::Custom(format!("{} cannot be converted into enum {}", other.type_name(), name))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i128 u128 char str string bytes byte_buf unit unit_struct
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Enum variant with content
+struct VariantDeserializer {
+    variant: String,
+    content: Primitive,
+}
+
+impl<'de> EnumAccess<'de> for VariantDeserializer {
+    type Error = SerdeError;
+    type Variant = Primitive;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Primitive), SerdeError> {
+        let variant = seed.deserialize(IntoDeserializer::<SerdeError>::into_deserializer(self.variant))?;
+        Ok((variant, self.content))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Primitive {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        <()>::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, SerdeError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        serde::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+        serde::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+/// Json primitives are written as the data they contain
+impl Serialize for Primitive {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Primitive::Empty => serializer.serialize_unit(),
+            Primitive::String(s) => serializer.serialize_str(s),
+            Primitive::F32(x) => serializer.serialize_f32(*x),
+            Primitive::F64(x) => serializer.serialize_f64(*x),
+            Primitive::U32(x) => serializer.serialize_u32(*x),
+            Primitive::U64(x) => serializer.serialize_u64(*x),
+            Primitive::I32(x) => serializer.serialize_i32(*x),
+            Primitive::I64(x) => serializer.serialize_i64(*x),
+            Primitive::Bool(x) => serializer.serialize_bool(*x),
+            Primitive::Json(s) => serde_json::from_str::<serde_json::Value>(s)
+                .map_err(serde::ser::Error::custom)?
+                .serialize(serializer),
+            Primitive::Bytes(b) => serializer.serialize_bytes(b),
+            Primitive::Array(items) => serializer.collect_seq(items),
+            Primitive::Map(entries) => serializer.collect_map(entries.iter().map(|(k, v)| (k, v))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Primitive {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(PrimitiveVisitor)
+    }
+}
+
+/// Builds the Primitive which fits the data best, like the front-end does for Javascript values
+struct PrimitiveVisitor;
+
+impl<'de> Visitor<'de> for PrimitiveVisitor {
+    type Value = Primitive;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "any value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Primitive, E> {
+        Ok(Primitive::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Primitive, E> {
+        Ok(i32::try_from(v).map(Primitive::I32).unwrap_or(Primitive::I64(v)))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Primitive, E> {
+        Ok(u32::try_from(v).map(Primitive::U32).unwrap_or(Primitive::U64(v)))
+    }
+
+    fn visit_i128<E: serde::de::Error>(self, v: i128) -> Result<Primitive, E> {
+        to_i64(v).map_err(E::custom)
+    }
+
+    fn visit_u128<E: serde::de::Error>(self, v: u128) -> Result<Primitive, E> {
+        to_u64(v).map_err(E::custom)
+    }
+
+    fn visit_f32<E>(self, v: f32) -> Result<Primitive, E> {
+        Ok(Primitive::F32(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Primitive, E> {
+        Ok(Primitive::F64(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Primitive, E> {
+        Ok(Primitive::String(v.to_owned()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Primitive, E> {
+        Ok(Primitive::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Primitive, E> {
+        Ok(Primitive::Bytes(v.to_owned()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Primitive, E> {
+        Ok(Primitive::Bytes(v))
+    }
+
+    fn visit_none<E>(self) -> Result<Primitive, E> {
+        Ok(Primitive::Empty)
+    }
+
+    fn visit_some<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<Primitive, D::Error> {
+        Primitive::deserialize(deserializer)
+    }
+
+    fn visit_unit<E>(self) -> Result<Primitive, E> {
+        Ok(Primitive::Empty)
+    }
+
+    fn visit_newtype_struct<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<Primitive, D::Error> {
+        Primitive::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Primitive, A::Error> {
+        let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Primitive::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Primitive, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0).min(4096));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Primitive::Map(entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Settings {
+        name: String,
+        limits: Vec<u16>,
+        nickname: Option<String>,
+        modes: Vec<Mode>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Mode {
+        Off,
+        Fixed(u32),
+        Range { min: i64, max: i64 },
+    }
+
+    #[test]
+    fn serde_primitive_idempotence_test() {
+        let settings = Settings {
+            name: "test".into(),
+            limits: vec![1, 2, 3],
+            nickname: None,
+            modes: vec![Mode::Off, Mode::Fixed(42), Mode::Range { min: -1, max: 1 }],
+        };
+        let primitive = Primitive::from_serialize(&settings).unwrap();
+        match &primitive {
+            Primitive::Map(entries) => {
+                assert_eq!(entries[0].0, "name");
+                assert!(matches!(&entries[2].1, Primitive::Empty), "None was not converted into Empty");
+                assert!(matches!(&entries[3].1, Primitive::Array(modes) if matches!(&modes[0], Primitive::String(s) if s == "Off")));
+            }
+            _ => panic!("Struct was not converted into Map"),
+        }
+        let loaded: Settings = primitive.deserialize_into().unwrap();
+        assert_eq!(loaded, settings, "Data converted to and from Primitive does not match");
+    }
+
+    #[test]
+    fn serde_primitive_json_test() {
+        let json = Primitive::Json(r#"{"name":"json","limits":[4],"nickname":"j","modes":[{"Fixed":1}]}"#.into());
+        let loaded: Settings = json.clone().deserialize_into().unwrap();
+        assert_eq!(loaded.nickname.as_deref(), Some("j"));
+        assert_eq!(loaded.modes, vec![Mode::Fixed(1)]);
+
+        // older front-ends send numbers as F64
+        assert_eq!(Primitive::F64(3.0).deserialize_into::<u8>().unwrap(), 3);
+        assert!(Primitive::F64(3.5).deserialize_into::<u8>().is_err(), "Fractional float was converted into u8");
+
+        let copy: Primitive = Primitive::from_serialize(&json).unwrap();
+        assert!(matches!(copy, Primitive::Map(_)), "Json was not converted into Map");
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Blob {
+        data: Vec<u8>,
+        header: [u8; 2],
+    }
+
+    #[test]
+    fn serde_primitive_bytes_test() {
+        let bytes = Primitive::Bytes(vec![1, 2, 3]);
+        assert_eq!(bytes.clone().deserialize_into::<Vec<u8>>().unwrap(), vec![1, 2, 3]);
+        assert_eq!(bytes.deserialize_into::<(u8, u8, u8)>().unwrap(), (1, 2, 3));
+
+        let blob = Primitive::Map(vec![
+            ("data".into(), Primitive::Bytes(vec![4, 5])),
+            ("header".into(), Primitive::Bytes(vec![6, 7])),
+        ]);
+        let loaded: Blob = blob.deserialize_into().unwrap();
+        assert_eq!(loaded, Blob { data: vec![4, 5], header: [6, 7] });
+    }
+}
@@ -0,0 +1,572 @@
+//! serde support for the binary format of `Dumpable` and `Loadable`.
+//! Data is laid out like the derive macros do it, so a type serialized with serde
+//! can be loaded by a type deriving `Loadable` with the same fields, and the other way around.
+//! Options are a byte (0 for None, 1 for Some) followed by the value,
+//! and chars are sent as u32.
+
+use std::io::{Read, Write};
+
+use serde::de::{DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor};
+use serde::ser::{
+    SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple, SerializeTupleStruct,
+    SerializeTupleVariant,
+};
+use serde::{Deserialize, Serialize};
+
+use super::limits::{check_length, nested};
+use super::{Dumpable, LoadError, Loadable, SerdeError};
+
+/// Serialize a value in the USDPL binary format
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, SerdeError> {
+    let mut serializer = Serializer::new(Vec::new());
+    value.serialize(&mut serializer)?;
+    Ok(serializer.into_inner())
+}
+
+/// Deserialize a value from the USDPL binary format
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &[u8]) -> Result<T, SerdeError> {
+    T::deserialize(&mut Deserializer::new(bytes))
+}
+
+/// serde Serializer which writes the USDPL binary format
+pub struct Serializer<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> Serializer<W> {
+    /// Serialize into the writer
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// The writer, with everything serialized so far
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn dump<T: Dumpable>(&mut self, value: T) -> Result<(), SerdeError> {
+        value.dump(&mut self.writer)?;
+        Ok(())
+    }
+
+    fn dump_len(&mut self, len: Option<usize>) -> Result<(), SerdeError> {
+        let len = len.ok_or(SerdeError::Unsupported("Sequence without a known length"))?;
+        let len = u32::try_from(len).map_err(|_| SerdeError::Unsupported("Length over u32::MAX"))?;
+        self.dump(len)
+    }
+
+    fn dump_bytes(&mut self, bytes: &[u8]) -> Result<(), SerdeError> {
+        self.dump_len(Some(bytes.len()))?;
+        self.writer.write_all(bytes).map_err(|e| SerdeError::Dump(super::DumpError::Io(e)))
+    }
+
+    /// Variants are numbered from 1, like the derive macros do by default
+    fn dump_variant(&mut self, variant_index: u32) -> Result<(), SerdeError> {
+        let discriminant = u8::try_from(variant_index + 1).map_err(|_| SerdeError::Unsupported("Enum with over 255 variants"))?;
+        self.dump(discriminant)
+    }
+}
+
+impl<W: Write> serde::Serializer for &mut Serializer<W> {
+    type Ok = ();
+    type Error = SerdeError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), SerdeError> {
+        self.dump(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), SerdeError> {
+        self.dump(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), SerdeError> {
+        self.dump_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), SerdeError> {
+        self.dump_bytes(v)
+    }
+
+    fn serialize_none(self) -> Result<(), SerdeError> {
+        self.dump(0u8)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), SerdeError> {
+        self.dump(1u8)?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerdeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str) -> Result<(), SerdeError> {
+        self.dump_variant(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.dump_variant(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, SerdeError> {
+        self.dump_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, SerdeError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, SerdeError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, SerdeError> {
+        self.dump_variant(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, SerdeError> {
+        self.dump_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, SerdeError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, SerdeError> {
+        self.dump_variant(variant_index)?;
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Items of sequences, tuples and maps are written one after the other
+macro_rules! compound_impl {
+    ($trait:ident, $fn:ident $(, $key:ident)?) => {
+        impl<W: Write> $trait for &mut Serializer<W> {
+            type Ok = ();
+            type Error = SerdeError;
+
+            fn $fn<T: Serialize + ?Sized>(&mut self, $($key: &'static str,)? value: &T) -> Result<(), SerdeError> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<(), SerdeError> {
+                Ok(())
+            }
+        }
+    };
+}
+
+compound_impl! {SerializeSeq, serialize_element}
+compound_impl! {SerializeTuple, serialize_element}
+compound_impl! {SerializeTupleStruct, serialize_field}
+compound_impl! {SerializeTupleVariant, serialize_field}
+compound_impl! {SerializeStruct, serialize_field, _key}
+compound_impl! {SerializeStructVariant, serialize_field, _key}
+
+impl<W: Write> SerializeMap for &mut Serializer<W> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+/// serde Deserializer which reads the USDPL binary format.
+/// The format is not self-describing, so types which need `deserialize_any` (like untagged enums) are not supported.
+pub struct Deserializer<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> Deserializer<R> {
+    /// Deserialize from the reader
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    fn load<T: Loadable>(&mut self) -> Result<T, SerdeError> {
+        Ok(T::load(&mut self.reader)?.0)
+    }
+
+    /// Length of a sequence or map, which is checked like the length of a vector
+    fn load_len(&mut self) -> Result<usize, SerdeError> {
+        let len = self.load::<u32>()? as usize;
+        check_length(len)?;
+        Ok(len)
+    }
+}
+
+impl<'de, R: Read> serde::Deserializer<'de> for &mut Deserializer<R> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, SerdeError> {
+        Err(SerdeError::Unsupported("Type which is not known in advance"))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_bool(self.load()?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_i8(self.load()?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_i16(self.load()?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_i32(self.load()?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_i64(self.load()?)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_i128(self.load()?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u8(self.load()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u16(self.load()?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u32(self.load()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u64(self.load()?)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u128(self.load()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_f32(self.load()?)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_f64(self.load()?)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let c = char::from_u32(self.load()?).ok_or(SerdeError::Load(LoadError::InvalidData))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_string(self.load()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_string(self.load()?)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_byte_buf(self.load()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_byte_buf(self.load()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.load::<u8>()? {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(self),
+            _ => Err(SerdeError::Load(LoadError::InvalidData)),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let remaining = self.load_len()?;
+        nested(|| visitor.visit_seq(Items { de: self, remaining }))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_seq(Items { de: self, remaining: len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_seq(Items { de: self, remaining: len })
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let remaining = self.load_len()?;
+        nested(|| visitor.visit_map(Items { de: self, remaining }))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_seq(Items { de: self, remaining: fields.len() })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, SerdeError> {
+        Err(SerdeError::Unsupported("Identifier"))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, SerdeError> {
+        Err(SerdeError::Unsupported("Skipping data"))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Items of a sequence, tuple or map with a known length
+struct Items<'a, R: Read> {
+    de: &'a mut Deserializer<R>,
+    remaining: usize,
+}
+
+impl<'de, 'a, R: Read> SeqAccess<'de> for Items<'a, R> {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, SerdeError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de, 'a, R: Read> MapAccess<'de> for Items<'a, R> {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, SerdeError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, SerdeError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de, R: Read> EnumAccess<'de> for &mut Deserializer<R> {
+    type Error = SerdeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), SerdeError> {
+        let discriminant = self.load::<u8>()?;
+        let variant_index = discriminant.checked_sub(1).ok_or(SerdeError::Load(LoadError::InvalidData))?;
+        let variant = seed.deserialize(IntoDeserializer::<SerdeError>::into_deserializer(variant_index as u32))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, R: Read> VariantAccess<'de> for &mut Deserializer<R> {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, SerdeError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_seq(Items { de: self, remaining: len })
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_seq(Items { de: self, remaining: fields.len() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Settings {
+        name: String,
+        limits: Vec<u16>,
+        nickname: Option<String>,
+        mode: Mode,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Mode {
+        Off,
+        Fixed(u32),
+        Range { min: i64, max: i64 },
+    }
+
+    #[derive(Dumpable, Loadable, Debug, PartialEq)]
+    enum DerivedMode {
+        Off,
+        Fixed(u32),
+        Range { min: i64, max: i64 },
+    }
+
+    #[test]
+    fn serde_wire_idempotence_test() {
+        let settings = Settings {
+            name: "test".into(),
+            limits: vec![1, 2, 3],
+            nickname: None,
+            mode: Mode::Range { min: -1, max: 1 },
+        };
+        let bytes = to_bytes(&settings).unwrap();
+        assert_eq!(from_bytes::<Settings>(&bytes).unwrap(), settings, "Data written and read does not match");
+        assert!(from_bytes::<Settings>(&bytes[..bytes.len() - 1]).is_err(), "Truncated data was read");
+    }
+
+    #[test]
+    fn serde_wire_derive_compat_test() {
+        let bytes = to_bytes(&Mode::Fixed(42)).unwrap();
+        let mut dumped = Vec::new();
+        DerivedMode::Fixed(42).dump(&mut dumped).unwrap();
+        assert_eq!(bytes, dumped, "Serialized and dumped data does not match");
+        let (loaded, _) = DerivedMode::load(&mut bytes.as_slice()).unwrap();
+        assert_eq!(loaded, DerivedMode::Fixed(42));
+
+        let bytes = to_bytes(&("test", 7u8)).unwrap();
+        let mut dumped = Vec::new();
+        ("test".to_string(), 7u8).dump(&mut dumped).unwrap();
+        assert_eq!(bytes, dumped, "Serialized and dumped tuple does not match");
+    }
+}
@@ -1,6 +1,9 @@
 use std::io::{Read, Write, Cursor};
 use base64::{decode_config_buf, encode_config_buf, Config};
 
+use super::limits::LimitedReader;
+use super::LoadLimits;
+
 const B64_CONF: Config = Config::new(base64::CharacterSet::Standard, true);
 
 #[cfg(feature = "encrypt")]
@@ -16,9 +19,18 @@ pub enum LoadError {
     TooSmallBuffer,
     /// Unexpected/corrupted data encountered
     InvalidData,
+    /// String or vector longer than `LoadLimits::max_length`, with the length it claimed
+    TooLong(usize),
+    /// More data than `LoadLimits::max_bytes`
+    TooManyBytes,
+    /// Vectors nested deeper than `LoadLimits::max_depth`
+    TooDeep,
     /// Encrypted data cannot be decrypted
     #[cfg(feature = "encrypt")]
     DecryptionError,
+    /// Encrypted data was received before, or is too old
+    #[cfg(feature = "encrypt")]
+    Replayed,
     /// Read error
     Io(std::io::Error),
     /// Unimplemented
@@ -31,8 +43,13 @@ impl std::fmt::Display for LoadError {
         match self {
             Self::TooSmallBuffer => write!(f, "LoadError: TooSmallBuffer"),
             Self::InvalidData => write!(f, "LoadError: InvalidData"),
+            Self::TooLong(len) => write!(f, "LoadError: TooLong({})", len),
+            Self::TooManyBytes => write!(f, "LoadError: TooManyBytes"),
+            Self::TooDeep => write!(f, "LoadError: TooDeep"),
             #[cfg(feature = "encrypt")]
             Self::DecryptionError => write!(f, "LoadError: DecryptionError"),
+            #[cfg(feature = "encrypt")]
+            Self::Replayed => write!(f, "LoadError: Replayed"),
             Self::Io(err) => write!(f, "LoadError: Io({})", err),
             #[cfg(debug_assertions)]
             Self::Todo => write!(f, "LoadError: TODO!"),
@@ -44,32 +61,88 @@ impl std::fmt::Display for LoadError {
 pub trait Loadable: Sized {
     /// Read the buffer, building the object and returning the amount of bytes read.
     /// If anything is wrong with the buffer, Err should be returned.
+    /// Strings and vectors are checked against `LoadLimits::current()`.
     fn load(buffer: &mut dyn Read) -> Result<(Self, usize), LoadError>;
 
+    /// Load data with these limits, including the total amount of bytes read
+    fn load_limited(buffer: &mut dyn Read, limits: LoadLimits) -> Result<(Self, usize), LoadError> {
+        limits.scope(|| {
+            let mut reader = LimitedReader::new(buffer, limits.max_bytes);
+            let result = Self::load(&mut reader);
+            reader.check(result)
+        })
+    }
+
     /// Load data from a base64-encoded buffer
     fn load_base64(buffer: &[u8]) -> Result<(Self, usize), LoadError> {
         let mut buffer2 = Vec::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
         decode_config_buf(buffer, B64_CONF, &mut buffer2)
             .map_err(|_| LoadError::InvalidData)?;
         let mut cursor = Cursor::new(buffer2);
-        Self::load(&mut cursor)
+        Self::load_limited(&mut cursor, LoadLimits::current())
     }
 
-    /// Load data from an encrypted base64-encoded buffer
+    /// Load data from an encrypted base64-encoded buffer.
+    /// The buffer starts with the nonce, which is rejected if the guard has seen it before or it is too old.
     #[cfg(feature = "encrypt")]
-    fn load_encrypted(buffer: &[u8], key: &[u8], nonce: &[u8]) -> Result<(Self, usize), LoadError> {
+    fn load_encrypted(buffer: &[u8], key: &[u8], guard: &super::ReplayGuard) -> Result<(Self, usize), LoadError> {
         //println!("encrypted buffer: {}", String::from_utf8(buffer.to_vec()).unwrap());
+        let mut decoded_buf = Vec::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
+        base64::decode_config_buf(buffer, B64_CONF, &mut decoded_buf)
+            .map_err(|_| LoadError::InvalidData)?;
+        Self::load_encrypted_bytes(&decoded_buf, key, guard)
+    }
+
+    /// Load data from an encrypted buffer, like `load_encrypted` but without base64.
+    #[cfg(feature = "encrypt")]
+    fn load_encrypted_bytes(buffer: &[u8], key: &[u8], guard: &super::ReplayGuard) -> Result<(Self, usize), LoadError> {
         let key = aes_gcm_siv::Key::from_slice(key);
         let cipher = aes_gcm_siv::Aes256GcmSiv::new(key);
-        let nonce = aes_gcm_siv::Nonce::from_slice(nonce);
+        if buffer.len() < crate::socket::NONCE_SIZE {
+            return Err(LoadError::TooSmallBuffer);
+        }
+        let (nonce_buf, encrypted_buf) = buffer.split_at(crate::socket::NONCE_SIZE);
+        let mut decrypted_buf = encrypted_buf.to_vec();
+        let mut nonce = [0u8; crate::socket::NONCE_SIZE];
+        nonce.copy_from_slice(nonce_buf);
+        //println!("Decoded buf: {:?}", decoded_buf);
+        cipher.decrypt_in_place(aes_gcm_siv::Nonce::from_slice(&nonce), ASSOCIATED_DATA, &mut decrypted_buf)
+            .map_err(|_| LoadError::DecryptionError)?;
+        // only remember authentic nonces, so forged messages can't fill up the guard
+        guard.check(&nonce)?;
+        //println!("Decrypted buf: {:?}", decrypted_buf);
+        let mut cursor = Cursor::new(decrypted_buf);
+        Self::load_limited(&mut cursor, LoadLimits::current())
+    }
+
+    /// Load data from an encrypted base64-encoded buffer, which starts with a session key id
+    /// when it's encrypted with a session key (see `Dumpable::dump_encrypted_with_id`).
+    /// `keys` looks up the key for the id, or returns the compile-time key for None.
+    /// Returns the loaded data, its size and the key it was encrypted with.
+    #[cfg(feature = "encrypt")]
+    fn load_encrypted_with_id<K, F>(buffer: &[u8], keys: F, guard: &super::ReplayGuard) -> Result<(Self, usize, K), LoadError>
+    where
+        K: std::ops::Deref<Target = Vec<u8>>,
+        F: FnOnce(Option<u64>) -> Option<K>,
+    {
         let mut decoded_buf = Vec::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
         base64::decode_config_buf(buffer, B64_CONF, &mut decoded_buf)
             .map_err(|_| LoadError::InvalidData)?;
-        //println!("Decoded buf: {:?}", decoded_buf);
-        cipher.decrypt_in_place(nonce, ASSOCIATED_DATA, &mut decoded_buf).map_err(|_| LoadError::DecryptionError)?;
-        //println!("Decrypted buf: {:?}", decoded_buf);
-        let mut cursor = Cursor::new(decoded_buf);
-        Self::load(&mut cursor)
+        Self::load_encrypted_bytes_with_id(&decoded_buf, keys, guard)
+    }
+
+    /// Load data from an encrypted buffer, like `load_encrypted_with_id` but without base64.
+    /// Data encrypted with a key which `keys` doesn't know fails with `LoadError::DecryptionError`.
+    #[cfg(feature = "encrypt")]
+    fn load_encrypted_bytes_with_id<K, F>(buffer: &[u8], keys: F, guard: &super::ReplayGuard) -> Result<(Self, usize, K), LoadError>
+    where
+        K: std::ops::Deref<Target = Vec<u8>>,
+        F: FnOnce(Option<u64>) -> Option<K>,
+    {
+        let (key_id, encrypted) = crate::handshake::split_key_id(buffer);
+        let key = keys(key_id).ok_or(LoadError::DecryptionError)?;
+        let (loaded, len) = Self::load_encrypted_bytes(encrypted, &key, guard)?;
+        Ok((loaded, len, key))
     }
 }
 
@@ -119,24 +192,58 @@ pub trait Dumpable {
         Ok(len)
     }
 
-    /// Dump data as an encrypted base64-encoded buffer
+    /// Dump data as an encrypted base64-encoded buffer.
+    /// A fresh nonce is generated for every dump, and sent before the encrypted data.
+    #[cfg(feature = "encrypt")]
+    fn dump_encrypted(&self, buffer: &mut Vec<u8>, key: &[u8]) -> Result<usize, DumpError> {
+        let mut framed = Vec::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
+        let len = self.dump_encrypted_bytes(&mut framed, key)?;
+        let mut base64_buf = String::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
+        encode_config_buf(&framed[..len], B64_CONF, &mut base64_buf);
+        //println!("base64 len: {}", base64_buf.as_bytes().len());
+        buffer.extend_from_slice(base64_buf.as_bytes());
+        //let string = String::from_utf8(buffer.as_slice().to_vec()).unwrap();
+        //println!("Encoded slice: {}", string);
+        Ok(base64_buf.len())
+    }
+
+    /// Dump data as an encrypted buffer, like `dump_encrypted` but without base64.
+    /// Returns the amount of bytes written, including the nonce.
     #[cfg(feature = "encrypt")]
-    fn dump_encrypted(&self, buffer: &mut Vec<u8>, key: &[u8], nonce: &[u8]) -> Result<usize, DumpError> {
+    fn dump_encrypted_bytes(&self, buffer: &mut Vec<u8>, key: &[u8]) -> Result<usize, DumpError> {
         let mut buffer2 = Vec::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
         let size = self.dump(&mut buffer2)?;
         buffer2.truncate(size);
         //println!("Buf: {:?}", buffer2);
         let key = aes_gcm_siv::Key::from_slice(key);
         let cipher = aes_gcm_siv::Aes256GcmSiv::new(key);
-        let nonce = aes_gcm_siv::Nonce::from_slice(nonce);
+        let nonce = super::nonce::generate().map_err(|_| DumpError::EncryptionError)?;
+        let nonce = aes_gcm_siv::Nonce::from_slice(&nonce);
         cipher.encrypt_in_place(nonce, ASSOCIATED_DATA, &mut buffer2).map_err(|_| DumpError::EncryptionError)?;
         //println!("Encrypted slice: {:?}", &buffer2);
+        buffer.extend_from_slice(nonce.as_slice());
+        buffer.extend_from_slice(&buffer2);
+        Ok(crate::socket::NONCE_SIZE + buffer2.len())
+    }
+
+    /// Dump data as an encrypted base64-encoded buffer, encrypted with a session key.
+    /// The key's id is sent first, so the back-end knows which key to decrypt with.
+    #[cfg(feature = "encrypt")]
+    fn dump_encrypted_with_id(&self, buffer: &mut Vec<u8>, key: &crate::handshake::SessionKey) -> Result<usize, DumpError> {
+        let mut framed = Vec::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
+        let len = self.dump_encrypted_bytes_with_id(&mut framed, key)?;
         let mut base64_buf = String::with_capacity(crate::socket::PACKET_BUFFER_SIZE);
-        encode_config_buf(buffer2.as_slice(), B64_CONF, &mut base64_buf);
-        //println!("base64 len: {}", base64_buf.as_bytes().len());
+        encode_config_buf(&framed[..len], B64_CONF, &mut base64_buf);
         buffer.extend_from_slice(base64_buf.as_bytes());
-        //let string = String::from_utf8(buffer.as_slice().to_vec()).unwrap();
-        //println!("Encoded slice: {}", string);
         Ok(base64_buf.len())
     }
+
+    /// Dump data as an encrypted buffer, like `dump_encrypted_with_id` but without base64.
+    /// Returns the amount of bytes written, including the key id and nonce.
+    #[cfg(feature = "encrypt")]
+    fn dump_encrypted_bytes_with_id(&self, buffer: &mut Vec<u8>, key: &crate::handshake::SessionKey) -> Result<usize, DumpError> {
+        buffer.extend_from_slice(&key.id.to_le_bytes());
+        let len = self.dump_encrypted_bytes(buffer, &key.key)?;
+        Ok(crate::handshake::KEY_ID_SIZE + len)
+    }
 }
@@ -3,7 +3,9 @@ use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::io::{Read, Write};
 
 use crate::serdes::{DumpError, Dumpable, LoadError, Loadable};
-use crate::{RemoteCall, RemoteCallResponse};
+use crate::{RemoteCall, RemoteCallError, RemoteCallResponse, RemoteEvents};
+#[cfg(feature = "encrypt")]
+use crate::handshake::{Handshake, HandshakeResponse};
 
 /// Host IP address for web browsers
 pub const HOST_STR: &str = "localhost";
@@ -15,6 +17,11 @@ pub const PACKET_BUFFER_SIZE: usize = 1024;
 /// Encryption nonce size
 pub const NONCE_SIZE: usize = 12;
 
+/// HTTP content type of packets sent as raw bytes instead of base64
+pub const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+/// WebSocket sub-protocol for sending packets as binary messages instead of base64 text
+pub const BINARY_PROTOCOL: &str = "usdpl-binary";
+
 /// Address and port
 #[inline]
 pub fn socket_addr(port: u16) -> SocketAddr {
@@ -22,12 +29,15 @@ pub fn socket_addr(port: u16) -> SocketAddr {
 }
 
 /// Accepted Packet types and the data they contain
+#[derive(Debug)]
 pub enum Packet {
-    /// A remote call
+    /// A remote call.
+    /// Calls without a session and deadline use the layout from before those were added (discriminant 1),
+    /// so older back-ends still understand them; other calls have their own discriminant (16).
     Call(RemoteCall),
     /// A reponse to a remote call
     CallResponse(RemoteCallResponse),
-    /// Unused
+    /// Nothing to report (e.g. an event poll timed out without any new events)
     KeepAlive,
     /// Invalid
     Invalid,
@@ -45,13 +55,26 @@ pub enum Packet {
     /// Request translations for language
     #[cfg(feature = "translate")]
     Language(String),
+    /// A failed remote call
+    CallError(RemoteCallError),
+    /// Request events emitted after the event with this id (u64::MAX to only get the latest id)
+    EventPoll(u64),
+    /// Events emitted by the back-end
+    Events(RemoteEvents),
+    /// Request to agree on a session key
+    #[cfg(feature = "encrypt")]
+    Handshake(Handshake),
+    /// Back-end's half of the session key agreement
+    #[cfg(feature = "encrypt")]
+    HandshakeResponse(HandshakeResponse),
 }
 
 impl Packet {
     /// Byte representing the packet type -- the first byte of any packet in USDPL
-    const fn discriminant(&self) -> u8 {
+    fn discriminant(&self) -> u8 {
         match self {
-            Self::Call(_) => 1,
+            Self::Call(c) if c.is_legacy() => 1,
+            Self::Call(_) => 16,
             Self::CallResponse(_) => 2,
             Self::KeepAlive => 3,
             Self::Invalid => 4,
@@ -63,6 +86,13 @@ impl Packet {
             Self::Translations(_) => 9,
             #[cfg(feature = "translate")]
             Self::Language(_) => 10,
+            Self::CallError(_) => 11,
+            Self::EventPoll(_) => 12,
+            Self::Events(_) => 13,
+            #[cfg(feature = "encrypt")]
+            Self::Handshake(_) => 14,
+            #[cfg(feature = "encrypt")]
+            Self::HandshakeResponse(_) => 15,
         }
     }
 }
@@ -74,7 +104,7 @@ impl Loadable for Packet {
         let mut result: (Self, usize) = match discriminant_buf[0] {
             //0 => (None, 0),
             1 => {
-                let (obj, len) = RemoteCall::load(buf)?;
+                let (obj, len) = RemoteCall::load_legacy(buf)?;
                 (Self::Call(obj), len)
             }
             2 => {
@@ -103,6 +133,32 @@ impl Loadable for Packet {
                 let (obj, len) = <_>::load(buf)?;
                 (Self::Language(obj), len)
             },
+            11 => {
+                let (obj, len) = RemoteCallError::load(buf)?;
+                (Self::CallError(obj), len)
+            },
+            12 => {
+                let (obj, len) = u64::load(buf)?;
+                (Self::EventPoll(obj), len)
+            },
+            13 => {
+                let (obj, len) = RemoteEvents::load(buf)?;
+                (Self::Events(obj), len)
+            },
+            #[cfg(feature = "encrypt")]
+            14 => {
+                let (obj, len) = Handshake::load(buf)?;
+                (Self::Handshake(obj), len)
+            },
+            #[cfg(feature = "encrypt")]
+            15 => {
+                let (obj, len) = HandshakeResponse::load(buf)?;
+                (Self::HandshakeResponse(obj), len)
+            },
+            16 => {
+                let (obj, len) = RemoteCall::load(buf)?;
+                (Self::Call(obj), len)
+            },
             _ => return Err(LoadError::InvalidData),
         };
         result.1 += 1;
@@ -114,6 +170,7 @@ impl Dumpable for Packet {
     fn dump(&self, buf: &mut dyn Write) -> Result<usize, DumpError> {
         let size1 = buf.write(&[self.discriminant()]).map_err(DumpError::Io)?;
         let result = match self {
+            Self::Call(c) if c.is_legacy() => c.dump_legacy(buf),
             Self::Call(c) => c.dump(buf),
             Self::CallResponse(c) => c.dump(buf),
             Self::KeepAlive => Ok(0),
@@ -126,30 +183,124 @@ impl Dumpable for Packet {
             Self::Translations(tr) => tr.dump(buf),
             #[cfg(feature = "translate")]
             Self::Language(l) => l.dump(buf),
+            Self::CallError(e) => e.dump(buf),
+            Self::EventPoll(id) => id.dump(buf),
+            Self::Events(e) => e.dump(buf),
+            #[cfg(feature = "encrypt")]
+            Self::Handshake(h) => h.dump(buf),
+            #[cfg(feature = "encrypt")]
+            Self::HandshakeResponse(h) => h.dump(buf),
         }?;
         Ok(size1 + result)
     }
 }
 
+/// A packet tagged with the id of the request it belongs to.
+/// Used by the WebSocket transport, where many requests share one connection.
+#[derive(Dumpable, Loadable, Debug)]
+pub struct Tagged {
+    /// Request id, which the response is tagged with too
+    pub id: u64,
+    /// The request or response
+    pub packet: Packet,
+}
+
 #[cfg(test)]
 mod tests {
+    #[allow(unused_imports)]
     use super::*;
 
+    #[test]
+    fn nested_batch_limit_test() {
+        let mut packet = Packet::KeepAlive;
+        for _ in 0..crate::serdes::LoadLimits::DEFAULT.max_depth + 1 {
+            packet = Packet::Many(vec![packet]);
+        }
+        let mut buffer = Vec::with_capacity(PACKET_BUFFER_SIZE);
+        packet.dump(&mut buffer).unwrap();
+        assert!(
+            matches!(Packet::load(&mut buffer.as_slice()), Err(LoadError::TooDeep)),
+            "Batches nested too deep were loaded"
+        );
+    }
+
+    #[test]
+    fn call_layout_test() {
+        let call = |session: u64, deadline: u64| Packet::Call(RemoteCall {
+            id: 42,
+            session,
+            deadline,
+            function: "test".into(),
+            parameters: vec![7u32.into()],
+        });
+
+        // a call from a front-end without sessions or deadlines, in the layout older back-ends load
+        let mut legacy = vec![1u8];
+        42u64.dump(&mut legacy).unwrap();
+        "test".to_owned().dump(&mut legacy).unwrap();
+        vec![crate::serdes::Primitive::U32(7)].dump(&mut legacy).unwrap();
+        let mut buffer = Vec::with_capacity(PACKET_BUFFER_SIZE);
+        call(0, 0).dump(&mut buffer).unwrap();
+        assert_eq!(buffer, legacy, "Call without session was not dumped in the old layout");
+        match Packet::load(&mut legacy.as_slice()).unwrap() {
+            (Packet::Call(c), len) => {
+                assert_eq!(len, legacy.len(), "Expected load and dump lengths to match");
+                assert_eq!((c.id, c.session, c.deadline), (42, 0, 0), "Old call layout was not loaded");
+                assert_eq!(c.function, "test", "RemoteCall.function does not match");
+            },
+            _ => panic!("Old call layout did not load as a call"),
+        }
+
+        let mut buffer = Vec::with_capacity(PACKET_BUFFER_SIZE);
+        let len = call(7, 1_700_000_000_000).dump(&mut buffer).unwrap();
+        assert_eq!(buffer[0], 16, "Call with session was not dumped with its own discriminant");
+        match Packet::load(&mut buffer.as_slice()).unwrap() {
+            (Packet::Call(c), loaded_len) => {
+                assert_eq!(len, loaded_len, "Expected load and dump lengths to match");
+                assert_eq!((c.id, c.session, c.deadline), (42, 7, 1_700_000_000_000), "Call does not match");
+            },
+            _ => panic!("Call did not load as a call"),
+        }
+    }
+
+    #[test]
+    fn tagged_idempotence_test() {
+        let tagged = Tagged {
+            id: 42,
+            packet: Packet::EventPoll(7),
+        };
+        let mut buffer = String::with_capacity(PACKET_BUFFER_SIZE);
+        let len = tagged.dump_base64(&mut buffer).unwrap();
+        let (loaded, loaded_len) = Tagged::load_base64(buffer.as_bytes()).unwrap();
+        assert_eq!(len, loaded_len, "Expected load and dump lengths to match");
+        assert_eq!(loaded.id, 42, "Tagged.id does not match");
+        assert!(matches!(loaded.packet, Packet::EventPoll(7)), "Tagged.packet does not match");
+    }
+
     #[cfg(feature = "encrypt")]
     #[test]
     fn encryption_integration_test() {
         let key = hex_literal::hex!("59C4E408F27250B3147E7724511824F1D28ED7BEF43CF7103ACE747F77A2B265");
-        let nonce = [0u8; NONCE_SIZE];
+        let guard = crate::serdes::ReplayGuard::default();
         let packet = Packet::Call(RemoteCall{
             id: 42,
+            session: 7,
+            deadline: 0,
             function: "test".into(),
             parameters: Vec::new(),
         });
         let mut buffer = Vec::with_capacity(PACKET_BUFFER_SIZE);
-        let len = packet.dump_encrypted(&mut buffer, &key, &nonce).unwrap();
+        let len = packet.dump_encrypted(&mut buffer, &key).unwrap();
         println!("buffer: {}", String::from_utf8(buffer.as_slice()[..len].to_vec()).unwrap());
 
-        let (packet_out, _len) = Packet::load_encrypted(&buffer.as_slice()[..len], &key, &nonce).unwrap();
+        let (packet_out, _len) = Packet::load_encrypted(&buffer.as_slice()[..len], &key, &guard).unwrap();
+        assert!(
+            matches!(Packet::load_encrypted(&buffer.as_slice()[..len], &key, &guard), Err(LoadError::Replayed)),
+            "Replayed packet was loaded"
+        );
+        let mut buffer2 = Vec::with_capacity(PACKET_BUFFER_SIZE);
+        let len2 = packet.dump_encrypted(&mut buffer2, &key).unwrap();
+        assert_ne!(buffer[..len], buffer2[..len2], "Expected a fresh nonce for every dump");
 
         if let Packet::Call(call_out) = packet_out {
             if let Packet::Call(call_in) = packet {
@@ -163,4 +314,67 @@ mod tests {
             panic!("Packet out not a Call!");
         }
     }
+
+    #[cfg(feature = "encrypt")]
+    #[test]
+    fn encrypted_bytes_test() {
+        let key = hex_literal::hex!("59C4E408F27250B3147E7724511824F1D28ED7BEF43CF7103ACE747F77A2B265");
+        let guard = crate::serdes::ReplayGuard::default();
+        let mut buffer = Vec::with_capacity(PACKET_BUFFER_SIZE);
+        let len = Packet::Message("binary".into()).dump_encrypted_bytes(&mut buffer, &key).unwrap();
+        assert_eq!(len, buffer.len(), "Expected the whole buffer to be written");
+
+        let mut base64_buffer = Vec::with_capacity(PACKET_BUFFER_SIZE);
+        let base64_len = Packet::Message("binary".into()).dump_encrypted(&mut base64_buffer, &key).unwrap();
+        assert!(len < base64_len, "Expected raw bytes to be smaller than base64");
+
+        match Packet::load_encrypted_bytes(&buffer, &key, &guard).unwrap().0 {
+            Packet::Message(message) => assert_eq!(message, "binary", "Packet.Message does not match"),
+            _ => panic!("Packet out not a Message"),
+        }
+        assert!(
+            matches!(Packet::load_encrypted_bytes(&buffer[..NONCE_SIZE - 1], &key, &guard), Err(LoadError::TooSmallBuffer)),
+            "Truncated buffer was loaded"
+        );
+    }
+
+    #[cfg(feature = "encrypt")]
+    #[test]
+    fn encrypted_with_id_test() {
+        use crate::handshake::{new_key_id, SessionKey};
+
+        let handshake_key = hex_literal::hex!("59C4E408F27250B3147E7724511824F1D28ED7BEF43CF7103ACE747F77A2B265").to_vec();
+        let session_key = SessionKey {
+            id: new_key_id().unwrap(),
+            key: hex_literal::hex!("0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF").to_vec(),
+        };
+        let guard = crate::serdes::ReplayGuard::default();
+        let keys = |id: Option<u64>| match id {
+            Some(id) if id == session_key.id => Some(Box::new(session_key.key.clone())),
+            Some(_) => None,
+            None => Some(Box::new(handshake_key.clone())),
+        };
+
+        let mut buffer = Vec::with_capacity(PACKET_BUFFER_SIZE);
+        let len = Packet::Message("session".into()).dump_encrypted_with_id(&mut buffer, &session_key).unwrap();
+        let (packet, _, key) = Packet::load_encrypted_with_id(&buffer[..len], keys, &guard).unwrap();
+        assert!(matches!(packet, Packet::Message(m) if m == "session"), "Packet.Message does not match");
+        assert_eq!(*key, session_key.key, "Message was not decrypted with the session key");
+
+        let mut buffer = Vec::with_capacity(PACKET_BUFFER_SIZE);
+        Packet::Message("handshake".into()).dump_encrypted_bytes(&mut buffer, &handshake_key).unwrap();
+        let (_, _, key) = Packet::load_encrypted_bytes_with_id(&buffer, keys, &guard).unwrap();
+        assert_eq!(*key, handshake_key, "Message without key id was not decrypted with the compile-time key");
+
+        let unknown = SessionKey {
+            id: new_key_id().unwrap(),
+            key: session_key.key.clone(),
+        };
+        let mut buffer = Vec::with_capacity(PACKET_BUFFER_SIZE);
+        Packet::Message("unknown".into()).dump_encrypted_bytes_with_id(&mut buffer, &unknown).unwrap();
+        assert!(
+            matches!(Packet::load_encrypted_bytes_with_id(&buffer, keys, &guard), Err(LoadError::DecryptionError)),
+            "Message encrypted with an unknown key was loaded"
+        );
+    }
 }
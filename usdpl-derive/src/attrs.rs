@@ -0,0 +1,107 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    parse_quote, Attribute, DataEnum, Fields, GenericParam, Generics, Ident, LitInt, LitStr,
+    Path, Type,
+};
+
+/// Options set with `#[usdpl(...)]` on the type itself
+pub(crate) struct ContainerAttrs {
+    /// Path to usdpl-core
+    pub krate: Path,
+}
+
+impl ContainerAttrs {
+    pub fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut krate = parse_quote!(::usdpl_core);
+        for attr in attrs.iter().filter(|a| a.path().is_ident("usdpl")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("crate") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    krate = lit.parse()?;
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported usdpl attribute on type"))
+                }
+            })?;
+        }
+        Ok(Self { krate })
+    }
+}
+
+/// Discriminant byte of every variant, in declaration order
+pub(crate) fn discriminants(data: &DataEnum) -> syn::Result<Vec<u8>> {
+    let mut result: Vec<u8> = Vec::with_capacity(data.variants.len());
+    let mut next = Some(1u8);
+    for variant in data.variants.iter() {
+        let mut explicit = None;
+        for attr in variant.attrs.iter().filter(|a| a.path().is_ident("usdpl")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("discriminant") {
+                    let lit: LitInt = meta.value()?.parse()?;
+                    explicit = Some(lit.base10_parse::<u8>()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported usdpl attribute on variant"))
+                }
+            })?;
+        }
+        let discriminant = match explicit.or(next) {
+            Some(d) => d,
+            None => {
+                return Err(syn::Error::new_spanned(
+                    &variant.ident,
+                    "automatic discriminant overflows u8",
+                ))
+            }
+        };
+        if result.contains(&discriminant) {
+            return Err(syn::Error::new_spanned(
+                &variant.ident,
+                format!("discriminant {} is already in use", discriminant),
+            ));
+        }
+        result.push(discriminant);
+        next = discriminant.checked_add(1);
+    }
+    Ok(result)
+}
+
+/// Add `bound` to every type parameter
+pub(crate) fn add_bounds(mut generics: Generics, bound: Path) -> Generics {
+    for param in generics.params.iter_mut() {
+        if let GenericParam::Type(ty) = param {
+            ty.bounds.push(parse_quote!(#bound));
+        }
+    }
+    generics
+}
+
+/// Add together the byte counts from each (de)serialized part
+pub(crate) fn sum(parts: Vec<TokenStream>) -> TokenStream {
+    if parts.is_empty() {
+        quote! { 0usize }
+    } else {
+        quote! { #(#parts)+* }
+    }
+}
+
+/// Destructuring pattern (also usable as a constructor) for the fields,
+/// alongside the binding name and type of each field in declaration order
+pub(crate) fn bindings(fields: &Fields) -> (TokenStream, Vec<(Ident, &Type)>) {
+    let bound: Vec<(Ident, &Type)> = fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (format_ident!("__field{}", i), &f.ty))
+        .collect();
+    let names = bound.iter().map(|(name, _)| name);
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let idents = named.named.iter().map(|f| f.ident.as_ref().unwrap());
+            quote! { { #(#idents: #names),* } }
+        }
+        Fields::Unnamed(_) => quote! { ( #(#names),* ) },
+        Fields::Unit => quote! {},
+    };
+    (pattern, bound)
+}
@@ -0,0 +1,83 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_quote, Data, DeriveInput, Ident, Path, Type};
+
+use crate::attrs::{add_bounds, bindings, discriminants, sum, ContainerAttrs};
+
+pub(crate) fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let attrs = ContainerAttrs::parse(&input.attrs)?;
+    let krate = &attrs.krate;
+    let name = &input.ident;
+    let generics = add_bounds(input.generics.clone(), parse_quote!(#krate::serdes::Dumpable));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, fields) = bindings(&data.fields);
+            let (dumps, lens) = field_dumps(krate, &fields);
+            let total = sum(lens);
+            quote! {
+                let Self #pattern = self;
+                #(#dumps)*
+                Ok(#total)
+            }
+        }
+        Data::Enum(data) => {
+            let discriminants = discriminants(data)?;
+            let arms = data.variants.iter().zip(discriminants).map(|(variant, d)| {
+                let ident = &variant.ident;
+                let (pattern, fields) = bindings(&variant.fields);
+                let (dumps, mut lens) = field_dumps(krate, &fields);
+                lens.insert(0, quote! { __discriminant_len });
+                let total = sum(lens);
+                quote! {
+                    Self::#ident #pattern => {
+                        let __discriminant_len = #krate::serdes::Dumpable::dump(&#d, buffer)?;
+                        #(#dumps)*
+                        Ok(#total)
+                    }
+                }
+            });
+            if data.variants.is_empty() {
+                quote! { match *self {} }
+            } else {
+                quote! {
+                    match self {
+                        #(#arms)*
+                    }
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new_spanned(
+                data.union_token,
+                "Dumpable cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #krate::serdes::Dumpable for #name #ty_generics #where_clause {
+            fn dump(
+                &self,
+                buffer: &mut dyn ::std::io::Write,
+            ) -> ::core::result::Result<usize, #krate::serdes::DumpError> {
+                #body
+            }
+        }
+    })
+}
+
+/// Statements dumping each field binding, and the byte count for each of those dumps
+fn field_dumps(krate: &Path, fields: &[(Ident, &Type)]) -> (Vec<TokenStream>, Vec<TokenStream>) {
+    fields
+        .iter()
+        .map(|(name, _)| {
+            let len = format_ident!("{}_len", name);
+            (
+                quote! { let #len = #krate::serdes::Dumpable::dump(#name, buffer)?; },
+                quote! { #len },
+            )
+        })
+        .unzip()
+}
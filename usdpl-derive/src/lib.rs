@@ -0,0 +1,35 @@
+//! Derive macros for USDPL serialization.
+//! These are re-exported by `usdpl_core::serdes` and should be used from there.
+//!
+//! Fields are (de)serialized in declaration order.
+//! Enums are prefixed with a discriminant byte, starting at 1 and incrementing for each variant,
+//! unless overridden with `#[usdpl(discriminant = N)]`.
+//! When the traits are not reachable at `::usdpl_core::serdes` (e.g. through `usdpl_back::core`),
+//! use `#[usdpl(crate = "path::to::core")]` on the type.
+//!
+#![warn(missing_docs)]
+
+mod attrs;
+mod dump;
+mod load;
+
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, DeriveInput};
+
+/// Derive `Dumpable` for a struct or enum
+#[proc_macro_derive(Dumpable, attributes(usdpl))]
+pub fn derive_dumpable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    dump::expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derive `Loadable` for a struct or enum
+#[proc_macro_derive(Loadable, attributes(usdpl))]
+pub fn derive_loadable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    load::expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -0,0 +1,78 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_quote, Data, DeriveInput, Ident, Path, Type};
+
+use crate::attrs::{add_bounds, bindings, discriminants, sum, ContainerAttrs};
+
+pub(crate) fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let attrs = ContainerAttrs::parse(&input.attrs)?;
+    let krate = &attrs.krate;
+    let name = &input.ident;
+    let generics = add_bounds(input.generics.clone(), parse_quote!(#krate::serdes::Loadable));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, fields) = bindings(&data.fields);
+            let (loads, lens) = field_loads(krate, &fields);
+            let total = sum(lens);
+            quote! {
+                #(#loads)*
+                Ok((Self #pattern, #total))
+            }
+        }
+        Data::Enum(data) => {
+            let discriminants = discriminants(data)?;
+            let arms = data.variants.iter().zip(discriminants).map(|(variant, d)| {
+                let ident = &variant.ident;
+                let (pattern, fields) = bindings(&variant.fields);
+                let (loads, mut lens) = field_loads(krate, &fields);
+                lens.insert(0, quote! { __discriminant_len });
+                let total = sum(lens);
+                quote! {
+                    #d => {
+                        #(#loads)*
+                        Ok((Self::#ident #pattern, #total))
+                    }
+                }
+            });
+            quote! {
+                let (__discriminant, __discriminant_len) = <u8 as #krate::serdes::Loadable>::load(buffer)?;
+                match __discriminant {
+                    #(#arms)*
+                    _ => Err(#krate::serdes::LoadError::InvalidData),
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new_spanned(
+                data.union_token,
+                "Loadable cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #krate::serdes::Loadable for #name #ty_generics #where_clause {
+            fn load(
+                buffer: &mut dyn ::std::io::Read,
+            ) -> ::core::result::Result<(Self, usize), #krate::serdes::LoadError> {
+                #body
+            }
+        }
+    })
+}
+
+/// Statements loading each field into its binding, and the byte count for each of those loads
+fn field_loads(krate: &Path, fields: &[(Ident, &Type)]) -> (Vec<TokenStream>, Vec<TokenStream>) {
+    fields
+        .iter()
+        .map(|(name, ty)| {
+            let len = format_ident!("{}_len", name);
+            (
+                quote! { let (#name, #len) = <#ty as #krate::serdes::Loadable>::load(buffer)?; },
+                quote! { #len },
+            )
+        })
+        .unzip()
+}
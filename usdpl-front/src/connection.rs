@@ -5,47 +5,115 @@ use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
 
-//use web_sys::{WebSocket, MessageEvent, ErrorEvent};
-use js_sys::JsString;
+use js_sys::{ArrayBuffer, JsString, Uint8Array};
 use web_sys::{Request, RequestInit, RequestMode, Response};
 //use wasm_rs_shared_channel::{Expects, spsc::{Receiver, Sender}};
 
 use usdpl_core::serdes::{Dumpable, Loadable, Primitive};
-use usdpl_core::socket;
+use usdpl_core::{socket, CallError, ErrorCode, RemoteCall};
+
+/// Whether the back-end accepts packets as raw bytes, which it says by responding with them
+static mut BINARY: bool = false;
+
+fn binary() -> bool {
+    unsafe { BINARY }
+}
+
+pub(crate) fn set_binary(binary: bool) {
+    unsafe { BINARY = binary }
+}
+
+/// Key to encrypt requests with, and its id when it was agreed on through a handshake
+#[cfg(feature = "encrypt")]
+#[derive(Clone)]
+pub(crate) struct Key {
+    pub id: Option<u64>,
+    pub bytes: Vec<u8>,
+}
+
+#[cfg(feature = "encrypt")]
+static mut REPLAY_GUARD: Option<usdpl_core::serdes::ReplayGuard> = None;
 
 #[cfg(feature = "encrypt")]
-const NONCE: [u8; socket::NONCE_SIZE]= [0u8; socket::NONCE_SIZE];
+fn replay_guard() -> &'static usdpl_core::serdes::ReplayGuard {
+    unsafe { (*std::ptr::addr_of_mut!(REPLAY_GUARD)).get_or_insert_with(Default::default) }
+}
 
 pub async fn send_recv_packet(
     id: u64,
     packet: socket::Packet,
     port: u16,
     #[cfg(feature = "encrypt")]
-    key: Vec<u8>,
+    key: Key,
 ) -> Result<socket::Packet, JsValue> {
+    if super::websocket::is_open() {
+        return super::websocket::send_recv_packet(id, packet, #[cfg(feature = "encrypt")] key).await;
+    }
+    send_recv_http(id, packet, port, #[cfg(feature = "encrypt")] key).await
+}
 
+/// Send a packet with an HTTP request, even when the WebSocket is connected
+pub(crate) async fn send_recv_http(
+    id: u64,
+    packet: socket::Packet,
+    port: u16,
+    #[cfg(feature = "encrypt")]
+    key: Key,
+) -> Result<socket::Packet, JsValue> {
     let mut opts = RequestInit::new();
     opts.method("POST");
     opts.mode(RequestMode::Cors);
 
     let url = format!("http://usdpl{}.{}:{}/usdpl/call", id, socket::HOST_STR, port);
 
+    let binary = binary();
     #[allow(unused_variables)]
-    let (buffer, len) = dump_to_buffer(packet, #[cfg(feature = "encrypt")] key.as_slice())?;
-    let string: String = String::from_utf8_lossy(buffer.as_slice()).into();
-    #[cfg(feature="debug")]
-    crate::imports::console_log(&format!("Dumped base64 `{}` len:{}", string, len));
-    opts.body(Some(&string.into()));
+    let (buffer, len) = dump_to_buffer(packet, binary, #[cfg(feature = "encrypt")] &key)?;
+    let request_len = buffer.len();
+    if binary {
+        #[cfg(feature="debug")]
+        crate::imports::console_log(&format!("Dumped binary len:{}", len));
+        opts.body(Some(&Uint8Array::from(buffer.as_slice()).into()));
+    } else {
+        let string: String = String::from_utf8_lossy(buffer.as_slice()).into();
+        #[cfg(feature="debug")]
+        crate::imports::console_log(&format!("Dumped base64 `{}` len:{}", string, len));
+        opts.body(Some(&string.into()));
+    }
 
     let request = Request::new_with_str_and_init(&url, &opts)?;
-
-    //request.headers().set("Accept", "text/base64")?;
+    // the back-end responds with raw bytes when it supports them, even to a base64 request
+    request.headers().set("Accept", socket::BINARY_CONTENT_TYPE)?;
+    if binary {
+        request.headers().set("Content-Type", socket::BINARY_CONTENT_TYPE)?;
+    }
     //.set("Authorization", "wasm TODO_KEY")?;
 
     let window = web_sys::window().unwrap();
     let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
 
     let resp: Response = resp_value.dyn_into()?;
+    match resp.status() {
+        200..=299 => {},
+        413 => return Err(super::convert::str_to_js(format!(
+            "Request of {} bytes is larger than the back-end accepts (HTTP 413)",
+            request_len,
+        )).into()),
+        status => return Err(super::convert::str_to_js(format!("HTTP {} {}", status, resp.status_text())).into()),
+    }
+    let binary_response = resp
+        .headers()
+        .get("Content-Type")?
+        .map(|x| x.starts_with(socket::BINARY_CONTENT_TYPE))
+        .unwrap_or(false);
+    if binary_response {
+        set_binary(true);
+        let buffer: ArrayBuffer = JsFuture::from(resp.array_buffer()?).await?.dyn_into()?;
+        let data = Uint8Array::new(&buffer).to_vec();
+        #[cfg(feature="debug")]
+        crate::imports::console_log(&format!("Received binary len:{}", data.len()));
+        return load_from_bytes(&data, true, #[cfg(feature = "encrypt")] &key.bytes);
+    }
     let text = JsFuture::from(resp.text()?).await?;
     let string: JsString = text.dyn_into()?;
 
@@ -53,15 +121,7 @@ pub async fn send_recv_packet(
     #[cfg(feature="debug")]
     crate::imports::console_log(&format!("Received base64 `{}` len:{}", rust_str, rust_str.len()));
 
-    #[cfg(not(feature = "encrypt"))]
-    {Ok(socket::Packet::load_base64(rust_str.as_bytes())
-        .map_err(super::convert::str_to_js)?
-        .0)}
-
-    #[cfg(feature = "encrypt")]
-    {Ok(socket::Packet::load_encrypted(rust_str.as_bytes(), key.as_slice(), &NONCE)
-        .map_err(super::convert::str_to_js)?
-        .0)}
+    load_from_bytes(rust_str.as_bytes(), false, #[cfg(feature = "encrypt")] &key.bytes)
 }
 
 pub async fn send_call(
@@ -69,36 +129,112 @@ pub async fn send_call(
     packet: socket::Packet,
     port: u16,
     #[cfg(feature = "encrypt")]
-    key: Vec<u8>,
-) -> Result<Vec<Primitive>, JsValue> {
-    let packet = send_recv_packet(id, packet, port, #[cfg(feature = "encrypt")] key).await?;
+    key: Key,
+) -> Result<Vec<Primitive>, CallError> {
+    let packet = send_recv_packet(id, packet, port, #[cfg(feature = "encrypt")] key)
+        .await
+        .map_err(transport_error)?;
+    call_result(packet)
+}
+
+/// Send many calls in one packet, with results in the same order as the calls
+pub async fn send_calls(
+    id: u64,
+    calls: Vec<RemoteCall>,
+    port: u16,
+    #[cfg(feature = "encrypt")]
+    key: Key,
+) -> Result<Vec<Result<Vec<Primitive>, CallError>>, CallError> {
+    let count = calls.len();
+    let packet = socket::Packet::Many(calls.into_iter().map(socket::Packet::Call).collect());
+    let packet = send_recv_packet(id, packet, port, #[cfg(feature = "encrypt")] key)
+        .await
+        .map_err(transport_error)?;
+
+    match packet {
+        socket::Packet::Many(packets) if packets.len() == count => Ok(packets.into_iter().map(call_result).collect()),
+        _ => Err(CallError::new(ErrorCode::Transport, "Expected call response messages, got something else")),
+    }
+}
 
+/// Whether the request failed because the back-end couldn't decrypt it (HTTP 400, or the WebSocket was closed),
+/// or its response couldn't be decrypted. This happens when the back-end forgot the session key.
+#[cfg(feature = "encrypt")]
+pub(crate) fn is_key_rejected(e: &CallError) -> bool {
+    e.code == ErrorCode::Transport && (
+        e.message.starts_with("HTTP 400")
+        || e.message.contains("DecryptionError")
+        || e.message == super::websocket::CLOSED_BEFORE_RESPONDING
+    )
+}
+
+/// Error for a failed request, keeping the message readable when it's a string
+fn transport_error(e: JsValue) -> CallError {
+    CallError::new(ErrorCode::Transport, e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+fn call_result(packet: socket::Packet) -> Result<Vec<Primitive>, CallError> {
     match packet
     {
         socket::Packet::CallResponse(resp) => Ok(resp.response),
+        socket::Packet::CallError(resp) => Err(resp.error),
         _ => {
             //imports::console_warn(&format!("USDPL warning: Got non-call-response message from {}", resp.url()));
-            Err("Expected call response message, got something else".into())
+            Err(CallError::new(ErrorCode::Transport, "Expected call response message, got something else"))
         }
     }
 }
 
+/// Encode data as raw bytes when binary, or base64 otherwise
 #[cfg(feature = "encrypt")]
-fn dump_to_buffer(packet: socket::Packet, key: &[u8]) -> Result<(Vec<u8>, usize), JsValue> {
+pub(crate) fn dump_to_buffer<T: Dumpable>(data: T, binary: bool, key: &Key) -> Result<(Vec<u8>, usize), JsValue> {
     let mut buffer = Vec::with_capacity(socket::PACKET_BUFFER_SIZE);
     //buffer.extend_from_slice(&[0u8; socket::PACKET_BUFFER_SIZE]);
-    let len = packet
-        .dump_encrypted(&mut buffer, key, &NONCE)
-        .map_err(super::convert::str_to_js)?;
+    // session keys are sent with their id, so the back-end knows which key to decrypt with
+    let session_key = key.id.map(|id| usdpl_core::handshake::SessionKey { id, key: key.bytes.clone() });
+    let len = match (binary, &session_key) {
+        (true, Some(session_key)) => data.dump_encrypted_bytes_with_id(&mut buffer, session_key),
+        (false, Some(session_key)) => data.dump_encrypted_with_id(&mut buffer, session_key),
+        (true, None) => data.dump_encrypted_bytes(&mut buffer, &key.bytes),
+        (false, None) => data.dump_encrypted(&mut buffer, &key.bytes),
+    }.map_err(super::convert::str_to_js)?;
     Ok((buffer, len))
 }
 
+/// Encode data as raw bytes when binary, or base64 otherwise
 #[cfg(not(feature = "encrypt"))]
-fn dump_to_buffer(packet: socket::Packet) -> Result<(Vec<u8>, usize), JsValue> {
+pub(crate) fn dump_to_buffer<T: Dumpable>(data: T, binary: bool) -> Result<(Vec<u8>, usize), JsValue> {
+    if binary {
+        let mut buffer = Vec::with_capacity(socket::PACKET_BUFFER_SIZE);
+        let len = data
+            .dump(&mut buffer)
+            .map_err(super::convert::str_to_js)?;
+        return Ok((buffer, len));
+    }
     let mut buffer = String::with_capacity(socket::PACKET_BUFFER_SIZE);
     //buffer.extend_from_slice(&[0u8; socket::PACKET_BUFFER_SIZE]);
-    let len = packet
+    let len = data
         .dump_base64(&mut buffer)
         .map_err(super::convert::str_to_js)?;
-    Ok((buffer.as_bytes().to_vec(), len))
+    Ok((buffer.into_bytes(), len))
+}
+
+/// Decode data from raw bytes when binary, or base64 otherwise
+#[cfg(feature = "encrypt")]
+pub(crate) fn load_from_bytes<T: Loadable>(data: &[u8], binary: bool, key: &[u8]) -> Result<T, JsValue> {
+    Ok(match binary {
+        true => T::load_encrypted_bytes(data, key, replay_guard()),
+        false => T::load_encrypted(data, key, replay_guard()),
+    }.map_err(super::convert::str_to_js)?
+        .0)
+}
+
+/// Decode data from raw bytes when binary, or base64 otherwise
+#[cfg(not(feature = "encrypt"))]
+pub(crate) fn load_from_bytes<T: Loadable>(data: &[u8], binary: bool) -> Result<T, JsValue> {
+    Ok(match binary {
+        true => T::load(&mut &data[..]),
+        false => T::load_base64(data),
+    }.map_err(super::convert::str_to_js)?
+        .0)
 }
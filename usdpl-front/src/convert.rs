@@ -1,33 +1,74 @@
-use js_sys::JsString;
+use js_sys::{Array, ArrayBuffer, JsString, Object, Reflect, Uint8Array};
 use js_sys::JSON::{parse, stringify};
 use wasm_bindgen::prelude::JsValue;
+use wasm_bindgen::JsCast;
 
-use usdpl_core::serdes::Primitive;
+use usdpl_core::serdes::{LoadLimits, Primitive};
+use usdpl_core::CallError;
 
-pub(crate) fn primitive_to_js(primitive: Primitive) -> JsValue {
+/// Largest integer which a Javascript number holds exactly (`Number.MAX_SAFE_INTEGER`)
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+/// How deep arrays and objects are converted (also to stop on cycles).
+/// Half the back-end's default nesting limit, leaving room for the packet and parameter vectors around them.
+const MAX_NESTING: usize = LoadLimits::DEFAULT.max_depth / 2;
+
+/// Convert a Primitive into a Javascript value.
+/// 64-bit integers become a BigInt when strict, or when a number can't hold them exactly.
+pub(crate) fn primitive_to_js(primitive: Primitive, strict: bool) -> JsValue {
     match primitive {
         Primitive::Empty => JsValue::null(),
         Primitive::String(s) => JsValue::from_str(&s),
         Primitive::F32(f) => JsValue::from_f64(f as _),
         Primitive::F64(f) => JsValue::from_f64(f),
         Primitive::U32(f) => JsValue::from_f64(f as _),
+        Primitive::U64(f) if strict || f as f64 > MAX_SAFE_INTEGER => JsValue::from(f),
         Primitive::U64(f) => JsValue::from_f64(f as _),
         Primitive::I32(f) => JsValue::from_f64(f as _),
+        Primitive::I64(f) if strict || (f as f64).abs() > MAX_SAFE_INTEGER => JsValue::from(f),
         Primitive::I64(f) => JsValue::from_f64(f as _),
         Primitive::Bool(b) => JsValue::from_bool(b),
         Primitive::Json(s) => parse(&s).ok().unwrap_or(JsValue::from_str(&s)),
+        Primitive::Bytes(b) => Uint8Array::from(b.as_slice()).into(),
+        Primitive::Array(a) => a
+            .into_iter()
+            .map(|item| primitive_to_js(item, strict))
+            .collect::<Array>()
+            .into(),
+        Primitive::Map(m) => {
+            let object = Object::new();
+            for (key, item) in m {
+                Reflect::set(&object, &key.into(), &primitive_to_js(item, strict)).ok();
+            }
+            object.into()
+        }
     }
 }
 
+/// Convert a Javascript value into the Primitive which fits it best.
+/// Whole numbers become integers and BigInts become 64-bit integers, so no precision is lost.
+/// Arrays and plain objects become arrays and maps; other objects are sent as JSON.
 pub(crate) fn js_to_primitive(val: JsValue) -> Primitive {
+    js_to_primitive_nested(val, 0)
+}
+
+fn js_to_primitive_nested(val: JsValue, depth: usize) -> Primitive {
     if let Some(b) = val.as_bool() {
         Primitive::Bool(b)
     } else if let Some(f) = val.as_f64() {
-        Primitive::F64(f)
+        number_to_primitive(f)
+    } else if val.is_bigint() {
+        bigint_to_primitive(val)
+    } else if let Some(b) = js_to_bytes(&val) {
+        Primitive::Bytes(b)
     } else if let Some(s) = val.as_string() {
         Primitive::String(s)
     } else if val.is_null() || val.is_undefined() {
         Primitive::Empty
+    } else if depth < MAX_NESTING && Array::is_array(&val) {
+        Primitive::Array(Array::from(&val).iter().map(|item| js_to_primitive_nested(item, depth + 1)).collect())
+    } else if depth < MAX_NESTING && is_plain_object(&val) {
+        Primitive::Map(object_entries(&val).map(|(key, item)| (key, js_to_primitive_nested(item, depth + 1))).collect())
     } else if let Ok(s) = stringify(&val) {
         Primitive::Json(s.as_string().unwrap())
     } else {
@@ -35,6 +76,129 @@ pub(crate) fn js_to_primitive(val: JsValue) -> Primitive {
     }
 }
 
+/// The smallest integer Primitive for a whole number, or F64 for anything else
+fn number_to_primitive(f: f64) -> Primitive {
+    // -0 is kept as a float, since integers have no sign for zero
+    if f.fract() != 0.0 || f.abs() > MAX_SAFE_INTEGER || (f == 0.0 && f.is_sign_negative()) {
+        Primitive::F64(f)
+    } else if f >= 0.0 {
+        match f <= u32::MAX as f64 {
+            true => Primitive::U32(f as u32),
+            false => Primitive::U64(f as u64),
+        }
+    } else {
+        match f >= i32::MIN as f64 {
+            true => Primitive::I32(f as i32),
+            false => Primitive::I64(f as i64),
+        }
+    }
+}
+
+/// A BigInt as a 64-bit integer, or as the closest float when it doesn't fit
+fn bigint_to_primitive(val: JsValue) -> Primitive {
+    if let Ok(x) = u64::try_from(val.clone()) {
+        Primitive::U64(x)
+    } else if let Ok(x) = i64::try_from(val.clone()) {
+        Primitive::I64(x)
+    } else {
+        let digits = val.unchecked_into::<js_sys::BigInt>().to_string(10).ok().map(String::from);
+        Primitive::F64(digits.and_then(|x| x.parse().ok()).unwrap_or(f64::NAN))
+    }
+}
+
+/// Convert a Javascript value in strict mode, where it must be an object like `{ type: "U64", value: 42n }`.
+/// The type is the name of the Primitive variant, and the value must fit it exactly.
+pub(crate) fn js_to_primitive_strict(val: &JsValue) -> Result<Primitive, String> {
+    js_to_primitive_strict_nested(val, 0)
+}
+
+fn js_to_primitive_strict_nested(val: &JsValue, depth: usize) -> Result<Primitive, String> {
+    let type_name = Reflect::get(val, &"type".into())
+        .ok()
+        .and_then(|x| x.as_string())
+        .ok_or_else(|| "Expected an object with a type and value in strict mode".to_owned())?;
+    let value = Reflect::get(val, &"value".into()).unwrap_or(JsValue::UNDEFINED);
+    let primitive = match type_name.as_str() {
+        "Empty" => Some(Primitive::Empty),
+        "String" => value.as_string().map(Primitive::String),
+        "F32" => value.as_f64().map(|f| Primitive::F32(f as _)),
+        "F64" => value.as_f64().map(Primitive::F64),
+        "U32" => js_to_integer(&value).and_then(|i| u32::try_from(i).ok()).map(Primitive::U32),
+        "U64" => js_to_integer(&value).and_then(|i| u64::try_from(i).ok()).map(Primitive::U64),
+        "I32" => js_to_integer(&value).and_then(|i| i32::try_from(i).ok()).map(Primitive::I32),
+        "I64" => js_to_integer(&value).and_then(|i| i64::try_from(i).ok()).map(Primitive::I64),
+        "Bool" => value.as_bool().map(Primitive::Bool),
+        "Json" => stringify(&value).ok().and_then(|s| s.as_string()).map(Primitive::Json),
+        "Bytes" => js_to_bytes(&value).map(Primitive::Bytes),
+        "Array" | "Map" if depth >= MAX_NESTING => return Err(format!("{} is nested too deep", type_name)),
+        // items are typed objects too
+        "Array" if Array::is_array(&value) => Some(Primitive::Array(
+            Array::from(&value)
+                .iter()
+                .enumerate()
+                .map(|(i, item)| js_to_primitive_strict_nested(&item, depth + 1).map_err(|e| format!("Item {}: {}", i, e)))
+                .collect::<Result<_, _>>()?,
+        )),
+        "Map" if is_plain_object(&value) => Some(Primitive::Map(
+            object_entries(&value)
+                .map(|(key, item)| match js_to_primitive_strict_nested(&item, depth + 1) {
+                    Ok(item) => Ok((key, item)),
+                    Err(e) => Err(format!("Property {}: {}", key, e)),
+                })
+                .collect::<Result<_, _>>()?,
+        )),
+        "Array" | "Map" => None,
+        _ => return Err(format!("Unknown type {}", type_name)),
+    };
+    primitive.ok_or_else(|| format!("Value is not a valid {}", type_name))
+}
+
+/// Whether the value is an object literal (or has no prototype), not an instance of a class like Date
+fn is_plain_object(val: &JsValue) -> bool {
+    if !val.is_object() || val.is_function() {
+        return false;
+    }
+    let prototype: JsValue = Object::get_prototype_of(val).into();
+    prototype.is_null() || prototype == JsValue::from(Object::get_prototype_of(&Object::new()))
+}
+
+/// The object's own enumerable properties, in order
+fn object_entries(val: &JsValue) -> impl Iterator<Item = (String, JsValue)> {
+    Object::entries(val.unchecked_ref()).to_vec().into_iter().map(|entry| {
+        let entry = Array::from(&entry);
+        (entry.get(0).as_string().unwrap_or_default(), entry.get(1))
+    })
+}
+
+/// The contents of a Uint8Array or ArrayBuffer
+fn js_to_bytes(val: &JsValue) -> Option<Vec<u8>> {
+    if let Some(array) = val.dyn_ref::<Uint8Array>() {
+        Some(array.to_vec())
+    } else {
+        val.dyn_ref::<ArrayBuffer>().map(|buffer| Uint8Array::new(buffer).to_vec())
+    }
+}
+
+/// A whole number or BigInt as an integer, if it's exact
+fn js_to_integer(val: &JsValue) -> Option<i128> {
+    if let Some(f) = val.as_f64() {
+        match f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER {
+            true => Some(f as i128),
+            false => None,
+        }
+    } else {
+        i128::try_from(val.clone()).ok()
+    }
+}
+
+/// Javascript Error with the error code in a `code` property
+pub(crate) fn call_error_to_js(error: CallError) -> JsValue {
+    let js_error = js_sys::Error::new(&error.message);
+    js_error.set_name("CallError");
+    Reflect::set(&js_error, &"code".into(), &error.code.name().into()).ok();
+    js_error.into()
+}
+
 pub(crate) fn str_to_js<S: std::string::ToString>(s: S) -> JsString {
     s.to_string().into()
 }
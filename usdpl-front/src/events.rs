@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::ptr::addr_of_mut;
+
+use js_sys::{Array, Function, Promise};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+use usdpl_core::socket::Packet;
+use usdpl_core::RemoteEvent;
+
+/// Delay before polling again after a failed poll, in milliseconds
+const RETRY_DELAY_MS: i32 = 5000;
+
+static mut LISTENERS: Option<HashMap<String, Vec<Function>>> = None;
+
+static mut POLLING: bool = false;
+
+fn listeners() -> &'static mut HashMap<String, Vec<Function>> {
+    unsafe { (*addr_of_mut!(LISTENERS)).get_or_insert_with(HashMap::new) }
+}
+
+/// Call `callback` for every event with this topic, starting to poll if necessary
+pub(crate) fn add_listener(topic: String, callback: Function) {
+    listeners().entry(topic).or_default().push(callback);
+    if !unsafe { POLLING } {
+        unsafe { POLLING = true };
+        wasm_bindgen_futures::spawn_local(poll_forever());
+    }
+}
+
+/// Forget every callback for this topic, polling stops once there are no callbacks left
+pub(crate) fn remove_listeners(topic: &str) {
+    listeners().remove(topic);
+}
+
+async fn poll_forever() {
+    let mut last_id = u64::MAX;
+    while !listeners().is_empty() {
+        let result = super::connection::send_recv_packet(
+            super::increment_id(),
+            Packet::EventPoll(last_id),
+            super::get_port(),
+            #[cfg(feature = "encrypt")]
+            super::get_key(),
+        ).await;
+        match result {
+            Ok(Packet::Events(events)) => {
+                last_id = events.last_id;
+                for event in events.events {
+                    dispatch(event);
+                }
+            },
+            Ok(Packet::KeepAlive) => {},
+            #[allow(unused_variables)]
+            Ok(packet) => {
+                #[cfg(feature = "debug")]
+                super::imports::console_error("USDPL: Got wrong packet response for event poll");
+                sleep(RETRY_DELAY_MS).await;
+            },
+            #[allow(unused_variables)]
+            Err(e) => {
+                #[cfg(feature = "debug")]
+                super::imports::console_error(&format!("USDPL: Got error while polling events: {:?}", e));
+                sleep(RETRY_DELAY_MS).await;
+            }
+        }
+    }
+    unsafe { POLLING = false };
+}
+
+fn dispatch(event: RemoteEvent) {
+    // clone callbacks, since a callback may (un)register listeners
+    let callbacks = match listeners().get(&event.topic) {
+        Some(callbacks) => callbacks.clone(),
+        None => return,
+    };
+    let data = Array::new_with_length(event.data.len() as _);
+    for (i, item) in event.data.into_iter().enumerate() {
+        data.set(i as _, super::convert::primitive_to_js(item, super::strict_types()));
+    }
+    for callback in callbacks {
+        #[allow(unused_variables)]
+        if let Err(e) = callback.call1(&JsValue::NULL, &data) {
+            #[cfg(feature = "debug")]
+            super::imports::console_error(&format!("USDPL: Event callback for {} failed: {:?}", event.topic, e));
+        }
+    }
+}
+
+async fn sleep(millis: i32) {
+    let promise = Promise::new(&mut |resolve, _reject| {
+        web_sys::window()
+            .unwrap()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, millis)
+            .ok();
+    });
+    JsFuture::from(promise).await.ok();
+}
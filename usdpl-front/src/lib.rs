@@ -7,24 +7,33 @@
 
 mod connection;
 mod convert;
+mod events;
 mod imports;
+mod websocket;
 
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::ptr::{addr_of, addr_of_mut};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use js_sys::Array;
 use wasm_bindgen::prelude::*;
 
-use usdpl_core::{socket::Packet, RemoteCall};
+use usdpl_core::{socket::Packet, CallError, ErrorCode, RemoteCall};
 //const REMOTE_CALL_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
 //const REMOTE_PORT: std::sync::atomic::AtomicU16 = std::sync::atomic::AtomicU16::new(31337);
 
 static mut CTX: UsdplContext = UsdplContext {
     port: 31337,
     id: AtomicU64::new(0),
+    session: 0,
     #[cfg(feature = "encrypt")]
     key: Vec::new(),
+    #[cfg(feature = "encrypt")]
+    session_key: None,
 };
 
+/// Whether parameters must say which Primitive variant they are (see `set_strict_types`)
+static STRICT_TYPES: AtomicBool = AtomicBool::new(false);
+
 static mut CACHE: Option<std::collections::HashMap<String, JsValue>> = None;
 
 #[cfg(feature = "translate")]
@@ -40,8 +49,13 @@ fn encryption_key() -> Vec<u8> {
 struct UsdplContext {
     port: u16,
     id: AtomicU64,
+    /// Identifies this front-end to the back-end, so call ids only need to be unique per front-end
+    session: u64,
     #[cfg(feature = "encrypt")]
     key: Vec<u8>,
+    /// Key agreed on with the back-end through a handshake, used instead of the compile-time key
+    #[cfg(feature = "encrypt")]
+    session_key: Option<usdpl_core::handshake::SessionKey>,
 }
 
 fn get_port() -> u16 {
@@ -49,16 +63,68 @@ fn get_port() -> u16 {
 }
 
 #[cfg(feature = "encrypt")]
-fn get_key() -> Vec<u8> {
-    unsafe { CTX.key.clone() }
+fn get_key() -> connection::Key {
+    let ctx = unsafe { &*addr_of!(CTX) };
+    match &ctx.session_key {
+        Some(session_key) => connection::Key { id: Some(session_key.id), bytes: session_key.key.clone() },
+        None => connection::Key { id: None, bytes: ctx.key.clone() },
+    }
+}
+
+/// Send a request with the current key.
+/// When the back-end rejects the session key (e.g. it restarted or the key timed out),
+/// agree on a new one with `init_handshake` and send the request once more.
+#[cfg(feature = "encrypt")]
+async fn with_session_key<T, F, Fut>(send: F) -> Result<T, CallError>
+where
+    F: Fn(connection::Key) -> Fut,
+    Fut: std::future::Future<Output = Result<T, CallError>>,
+{
+    let key = get_key();
+    let has_session_key = key.id.is_some();
+    match send(key).await {
+        Err(e) if has_session_key && connection::is_key_rejected(&e) => {
+            #[cfg(feature = "debug")]
+            imports::console_log(&format!("USDPL: Session key was rejected ({}), redoing handshake", e));
+            if init_handshake().await {
+                send(get_key()).await
+            } else {
+                Err(e)
+            }
+        },
+        result => result,
+    }
+}
+
+fn get_session() -> u64 {
+    unsafe { (*addr_of!(CTX)).session }
+}
+
+fn strict_types() -> bool {
+    STRICT_TYPES.load(Ordering::SeqCst)
+}
+
+/// Convert call parameters, which must be typed objects in strict mode
+fn params_to_primitives<I: Iterator<Item = JsValue>>(parameters: I) -> Result<Vec<usdpl_core::serdes::Primitive>, String> {
+    if !strict_types() {
+        return Ok(parameters.map(convert::js_to_primitive).collect());
+    }
+    parameters
+        .enumerate()
+        .map(|(i, val)| convert::js_to_primitive_strict(&val).map_err(|e| format!("Parameter {}: {}", i, e)))
+        .collect()
 }
 
 fn increment_id() -> u64 {
-    let atomic = unsafe { &CTX.id };
+    let atomic = unsafe { &(*addr_of!(CTX)).id };
     atomic.fetch_add(1, Ordering::SeqCst)
 }
 
-/// Initialize the front-end library
+/// Initialize the front-end library.
+/// The back-end may listen on a fallback port when its port is taken (see `Instance::with_fallback_ports`).
+/// It writes the port it listens on to its discovery file, which the plugin's Python side can read
+/// (like `get_port` in the Decky template) to pass the right port here.
+/// Ports are not probed from here, since other plugins' back-ends may be listening on them.
 #[wasm_bindgen]
 pub fn init_usdpl(port: u16) {
     #[cfg(feature = "console_error_panic_hook")]
@@ -66,16 +132,59 @@ pub fn init_usdpl(port: u16) {
     //REMOTE_PORT.store(port, std::sync::atomic::Ordering::SeqCst);
     unsafe {
         CTX = UsdplContext {
-            port: port,
+            port,
             id: AtomicU64::new(0),
+            // random, so that front-ends (e.g. the QAM and a browser tab) don't share a session
+            session: (js_sys::Math::random() * (1u64 << f64::MANTISSA_DIGITS) as f64) as u64,
             #[cfg(feature = "encrypt")]
             key: encryption_key(),
+            #[cfg(feature = "encrypt")]
+            session_key: None,
         };
     }
 
     unsafe {
         CACHE = Some(std::collections::HashMap::new());
     }
+    // the back-end on this port may be an older one, which only understands base64
+    connection::set_binary(false);
+}
+
+/// Agree on a session key with the back-end, which is used to encrypt everything afterwards.
+/// The compile-time key is only used to authenticate the handshake.
+/// Resolves to whether the handshake succeeded.
+#[cfg(feature = "encrypt")]
+#[wasm_bindgen]
+pub async fn init_handshake() -> bool {
+    #[cfg(feature = "debug")]
+    imports::console_log("init_handshake()");
+    let exchange = usdpl_core::handshake::KeyExchange::new();
+    let result = connection::send_recv_http(
+        increment_id(),
+        Packet::Handshake(usdpl_core::handshake::Handshake { public_key: exchange.public_key() }),
+        get_port(),
+        connection::Key { id: None, bytes: unsafe { (*addr_of!(CTX)).key.clone() } },
+    ).await;
+    let session_key = match result {
+        Ok(Packet::HandshakeResponse(response)) => exchange
+            .session_key(&response.public_key, true)
+            .map(|key| usdpl_core::handshake::SessionKey { id: response.key_id, key }),
+        #[allow(unused_variables)]
+        Ok(packet) => None,
+        #[allow(unused_variables)]
+        Err(e) => {
+            #[cfg(feature = "debug")]
+            imports::console_error(&format!("USDPL: Got error during handshake: {:?}", e));
+            None
+        }
+    };
+    match session_key {
+        Some(key) => {
+            unsafe { (*addr_of_mut!(CTX)).session_key = Some(key) };
+            true
+        },
+        None => false,
+    }
 }
 
 /// Get the targeted plugin framework, or "any" if unknown
@@ -94,7 +203,7 @@ pub fn version_usdpl() -> String {
 #[wasm_bindgen]
 pub fn set_value(key: String, value: JsValue) -> JsValue {
     unsafe {
-        CACHE.as_mut().unwrap().insert(key, value).unwrap_or(JsValue::NULL)
+        (*addr_of_mut!(CACHE)).as_mut().unwrap().insert(key, value).unwrap_or(JsValue::NULL)
     }
 }
 
@@ -102,58 +211,178 @@ pub fn set_value(key: String, value: JsValue) -> JsValue {
 #[wasm_bindgen]
 pub fn get_value(key: String) -> JsValue {
     unsafe {
-        CACHE.as_ref().unwrap().get(&key).map(|x| x.to_owned()).unwrap_or(JsValue::UNDEFINED)
+        (*addr_of!(CACHE)).as_ref().unwrap().get(&key).map(|x| x.to_owned()).unwrap_or(JsValue::UNDEFINED)
     }
 }
 
+/// Choose the exact type of every call parameter, instead of having it inferred from the Javascript value.
+/// In strict mode, each parameter must be an object like `{ type: "U64", value: 42n }`,
+/// where the type is one of `Empty`, `String`, `F32`, `F64`, `U32`, `U64`, `I32`, `I64`, `Bool`, `Json`, `Bytes`,
+/// `Array` (of typed objects) or `Map` (an object with typed objects as values).
+/// Calls with any other parameter fail with `InvalidParameters` before being sent.
+/// 64-bit integers in results and events are always a BigInt in strict mode.
+///
+/// Otherwise, whole numbers are sent as the smallest integer type they fit in, BigInts as 64-bit integers,
+/// Uint8Arrays and ArrayBuffers as bytes, arrays and plain objects as arrays and maps,
+/// and 64-bit integers are only received as a BigInt when a number can't hold them exactly.
+/// Bytes are always received as a Uint8Array.
+#[wasm_bindgen]
+pub fn set_strict_types(strict: bool) {
+    STRICT_TYPES.store(strict, Ordering::SeqCst);
+}
+
+/// Connect to the back-end with a WebSocket, which is then used instead of HTTP requests.
+/// Calls fall back to HTTP while the socket is not connected (e.g. after the back-end restarts).
+/// Resolves to whether the socket connected.
+#[wasm_bindgen]
+pub async fn init_websocket() -> bool {
+    #[cfg(feature = "debug")]
+    imports::console_log("init_websocket()");
+    websocket::connect(get_port()).await
+}
+
 /// Call a function on the back-end.
+/// The back-end gives up on the call after `timeout` milliseconds, if specified.
 /// Returns null (None) if this fails for any reason.
 #[wasm_bindgen]
-pub async fn call_backend(name: String, parameters: Vec<JsValue>) -> JsValue {
+pub async fn call_backend(name: String, parameters: Vec<JsValue>, timeout: Option<f64>) -> JsValue {
     #[cfg(feature = "debug")]
     imports::console_log(&format!(
         "call_backend({}, [params; {}])",
         name,
         parameters.len()
     ));
-    let next_id = increment_id();
-    let mut params = Vec::with_capacity(parameters.len());
-    for val in parameters {
-        params.push(convert::js_to_primitive(val));
-    }
-    let port = get_port();
-    #[cfg(feature = "debug")]
-    imports::console_log(&format!("USDPL: Got port {}", port));
-    let results = connection::send_call(
-        next_id,
-        Packet::Call(RemoteCall {
-            id: next_id,
-            function: name.clone(),
-            parameters: params,
-        }),
-        port,
-        #[cfg(feature = "encrypt")]
-        get_key()
-    )
-    .await;
-    let results = match results {
+    match call_backend_internal(&name, parameters, timeout).await {
         Ok(x) => x,
         #[allow(unused_variables)]
         Err(e) => {
             #[cfg(feature = "debug")]
-            imports::console_error(&format!("USDPL: Got error while calling {}: {:?}", name, e));
-            return JsValue::NULL;
+            imports::console_error(&format!("USDPL: Got error while calling {}: {}", name, e));
+            JsValue::NULL
         }
+    }
+}
+
+/// Call a function on the back-end.
+/// The back-end gives up on the call after `timeout` milliseconds, if specified.
+/// The promise is rejected with an Error named `CallError` if this fails.
+/// Its `code` property is one of `UnknownFunction`, `Rejected`, `InvalidParameters`,
+/// `Failed`, `Internal`, `Transport` or `Timeout`.
+#[wasm_bindgen]
+pub async fn call_backend_checked(name: String, parameters: Vec<JsValue>, timeout: Option<f64>) -> Result<JsValue, JsValue> {
+    #[cfg(feature = "debug")]
+    imports::console_log(&format!(
+        "call_backend_checked({}, [params; {}])",
+        name,
+        parameters.len()
+    ));
+    call_backend_internal(&name, parameters, timeout)
+        .await
+        .map_err(convert::call_error_to_js)
+}
+
+async fn call_backend_internal(name: &str, parameters: Vec<JsValue>, timeout: Option<f64>) -> Result<JsValue, CallError> {
+    let params = params_to_primitives(parameters.into_iter())
+        .map_err(|e| CallError::new(ErrorCode::InvalidParameters, e))?;
+    let next_id = increment_id();
+    let port = get_port();
+    #[cfg(feature = "debug")]
+    imports::console_log(&format!("USDPL: Got port {}", port));
+    let call = RemoteCall {
+        id: next_id,
+        session: get_session(),
+        deadline: deadline(timeout),
+        function: name.to_owned(),
+        parameters: params,
     };
+    #[cfg(feature = "encrypt")]
+    let results = with_session_key(|key| connection::send_call(next_id, Packet::Call(call.clone()), port, key)).await?;
+    #[cfg(not(feature = "encrypt"))]
+    let results = connection::send_call(next_id, Packet::Call(call), port).await?;
+    Ok(results_to_js(results))
+}
+
+/// Call many functions on the back-end with one request, which runs them at the same time.
+/// Each call is an array of the function's name and an array of its parameters, e.g. `["hello", [42]]`.
+/// The back-end gives up on the calls after `timeout` milliseconds, if specified.
+/// Resolves to an array of results in the same order as the calls,
+/// where each failed call's result is an Error named `CallError` (see `call_backend_checked`).
+/// The promise is rejected with a `CallError` if the request fails.
+#[wasm_bindgen]
+pub async fn call_backend_many(calls: Vec<JsValue>, timeout: Option<f64>) -> Result<JsValue, JsValue> {
+    #[cfg(feature = "debug")]
+    imports::console_log(&format!("call_backend_many([calls; {}])", calls.len()));
+    call_backend_many_internal(calls, timeout)
+        .await
+        .map_err(convert::call_error_to_js)
+}
+
+async fn call_backend_many_internal(calls: Vec<JsValue>, timeout: Option<f64>) -> Result<JsValue, CallError> {
+    let deadline = deadline(timeout);
+    let mut remote_calls = Vec::with_capacity(calls.len());
+    for (i, call) in calls.into_iter().enumerate() {
+        let call = Array::from(&call);
+        let name = call.get(0).as_string().ok_or_else(|| CallError::new(
+            ErrorCode::InvalidParameters,
+            format!("Call {} is not an array of a function name and parameters", i),
+        ))?;
+        let params = params_to_primitives(Array::from(&call.get(1)).iter())
+            .map_err(|e| CallError::new(ErrorCode::InvalidParameters, format!("Call {}: {}", i, e)))?;
+        let id = increment_id();
+        remote_calls.push(RemoteCall {
+            id,
+            session: get_session(),
+            deadline,
+            function: name,
+            parameters: params,
+        });
+    }
+    let id = increment_id();
+    let port = get_port();
+    #[cfg(feature = "encrypt")]
+    let results = with_session_key(|key| connection::send_calls(id, remote_calls.clone(), port, key)).await?;
+    #[cfg(not(feature = "encrypt"))]
+    let results = connection::send_calls(id, remote_calls, port).await?;
+    let results_js = Array::new_with_length(results.len() as _);
+    for (i, result) in results.into_iter().enumerate() {
+        let result = match result {
+            Ok(x) => results_to_js(x),
+            Err(e) => convert::call_error_to_js(e),
+        };
+        results_js.set(i as _, result);
+    }
+    Ok(results_js.into())
+}
+
+/// Deadline for a call with a timeout in milliseconds, for the back-end
+fn deadline(timeout: Option<f64>) -> u64 {
+    timeout.map(|t| (js_sys::Date::now() + t.max(0.0)) as u64).unwrap_or(0)
+}
+
+fn results_to_js(results: Vec<usdpl_core::serdes::Primitive>) -> JsValue {
     let results_js = Array::new_with_length(results.len() as _);
-    let mut i = 0;
-    for item in results {
-        results_js.set(i as _, convert::primitive_to_js(item));
-        i += 1;
+    for (i, item) in results.into_iter().enumerate() {
+        results_js.set(i as _, convert::primitive_to_js(item, strict_types()));
     }
     results_js.into()
 }
 
+/// Call `callback` with the event's data (as an array) every time the back-end emits an event with this topic
+#[wasm_bindgen]
+pub fn on_event(topic: String, callback: js_sys::Function) {
+    #[cfg(feature = "debug")]
+    imports::console_log(&format!("on_event({}, callback)", topic));
+    events::add_listener(topic, callback);
+}
+
+/// Stop calling the callbacks registered for this topic
+#[wasm_bindgen]
+pub fn off_event(topic: String) {
+    #[cfg(feature = "debug")]
+    imports::console_log(&format!("off_event({})", topic));
+    events::remove_listeners(&topic);
+}
+
 /// Initialize translation strings for the front-end
 #[wasm_bindgen]
 pub async fn init_tr(locale: String) {
@@ -192,8 +421,8 @@ pub async fn init_tr(locale: String) {
 /// Translate a phrase, equivalent to tr_n(msg_id, 0)
 #[wasm_bindgen]
 pub fn tr(msg_id: String) -> String {
-    if let Some(translations) = unsafe { TRANSLATIONS.as_ref().unwrap().get(&msg_id) } {
-        if let Some(translated) = translations.get(0) {
+    if let Some(translations) = unsafe { (*addr_of!(TRANSLATIONS)).as_ref().unwrap().get(&msg_id) } {
+        if let Some(translated) = translations.first() {
             translated.to_owned()
         } else {
             msg_id
@@ -206,7 +435,7 @@ pub fn tr(msg_id: String) -> String {
 /// Translate a phrase, retrieving the plural form for `n` items
 #[wasm_bindgen]
 pub fn tr_n(msg_id: String, n: usize) -> String {
-    if let Some(translations) = unsafe { TRANSLATIONS.as_ref().unwrap().get(&msg_id) } {
+    if let Some(translations) = unsafe { (*addr_of!(TRANSLATIONS)).as_ref().unwrap().get(&msg_id) } {
         if let Some(translated) = translations.get(n) {
             translated.to_owned()
         } else {
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::ptr::addr_of_mut;
+
+use js_sys::{ArrayBuffer, Function, Promise, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+use usdpl_core::socket::{self, Packet, Tagged};
+
+/// Error of requests which were waiting for a response when the socket closed.
+/// The back-end closes the socket when it can't decrypt a request.
+pub(crate) const CLOSED_BEFORE_RESPONDING: &str = "USDPL WebSocket closed before responding";
+
+/// How long a request waits for its response, in milliseconds.
+/// This is longer than the back-end holds on to an event poll.
+const RESPONSE_TIMEOUT_MS: i32 = 60_000;
+
+static mut SOCKET: Option<WebSocket> = None;
+
+/// Whether the back-end agreed to binary messages for the open socket
+static mut BINARY: bool = false;
+
+/// Resolve functions of requests waiting for a response, by request id
+static mut PENDING: Option<HashMap<u64, Function>> = None;
+
+/// Responses which have not been picked up by their request yet, by request id
+static mut RESPONSES: Option<HashMap<u64, Packet>> = None;
+
+fn socket() -> &'static mut Option<WebSocket> {
+    unsafe { &mut *addr_of_mut!(SOCKET) }
+}
+
+fn binary() -> &'static mut bool {
+    unsafe { &mut *addr_of_mut!(BINARY) }
+}
+
+fn pending() -> &'static mut HashMap<u64, Function> {
+    unsafe { (*addr_of_mut!(PENDING)).get_or_insert_with(HashMap::new) }
+}
+
+fn responses() -> &'static mut HashMap<u64, Packet> {
+    unsafe { (*addr_of_mut!(RESPONSES)).get_or_insert_with(HashMap::new) }
+}
+
+/// Whether requests can be sent over the WebSocket
+pub(crate) fn is_open() -> bool {
+    socket()
+        .as_ref()
+        .map(|s| s.ready_state() == WebSocket::OPEN)
+        .unwrap_or(false)
+}
+
+/// Open the WebSocket, returning whether it connected
+pub(crate) async fn connect(port: u16) -> bool {
+    if is_open() {
+        return true;
+    }
+    let url = format!("ws://usdpl.{}:{}/usdpl/ws", socket::HOST_STR, port);
+    // older back-ends ignore the sub-protocol, so base64 text is used with them
+    let ws = match WebSocket::new_with_str(&url, socket::BINARY_PROTOCOL) {
+        Ok(x) => x,
+        #[allow(unused_variables)]
+        Err(e) => {
+            #[cfg(feature = "debug")]
+            super::imports::console_error(&format!("USDPL: Failed to create WebSocket: {:?}", e));
+            return false;
+        }
+    };
+    let opened = Promise::new(&mut |resolve, _reject| {
+        ws.set_onopen(Some(&resolve.bind1(&JsValue::NULL, &JsValue::TRUE)));
+        ws.set_onerror(Some(&resolve.bind1(&JsValue::NULL, &JsValue::FALSE)));
+    });
+    let opened = JsFuture::from(opened).await.ok().and_then(|x| x.as_bool()).unwrap_or(false);
+    ws.set_onopen(None);
+    ws.set_onerror(None);
+    if !opened {
+        #[cfg(feature = "debug")]
+        super::imports::console_error("USDPL: Failed to connect WebSocket, using HTTP");
+        return false;
+    }
+
+    let is_binary = ws.protocol() == socket::BINARY_PROTOCOL;
+    if is_binary {
+        ws.set_binary_type(BinaryType::Arraybuffer);
+        super::connection::set_binary(true);
+    }
+
+    let received_ws = ws.clone();
+    let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+        let data = event.data();
+        // the key may change after connecting, when a handshake completes
+        let received = if let Some(text) = data.as_string() {
+            receive(text.as_bytes(), false, #[cfg(feature = "encrypt")] &super::get_key().bytes)
+        } else if let Ok(buffer) = data.dyn_into::<ArrayBuffer>() {
+            receive(&Uint8Array::new(&buffer).to_vec(), true, #[cfg(feature = "encrypt")] &super::get_key().bytes)
+        } else {
+            true
+        };
+        if !received {
+            // the response's request can't be told apart from the others, so they are all failed
+            received_ws.close().ok();
+            disconnected(&received_ws);
+        }
+    });
+    ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    on_message.forget();
+
+    let closed_ws = ws.clone();
+    let on_close = Closure::<dyn FnMut(JsValue)>::new(move |_event: JsValue| {
+        disconnected(&closed_ws);
+    });
+    ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+    on_close.forget();
+
+    *socket() = Some(ws);
+    *binary() = is_binary;
+    true
+}
+
+/// Hand a response to the request waiting for it, returning whether it could be loaded
+fn receive(
+    data: &[u8],
+    binary: bool,
+    #[cfg(feature = "encrypt")]
+    key: &[u8],
+) -> bool {
+    let response: Tagged = match super::connection::load_from_bytes(data, binary, #[cfg(feature = "encrypt")] key) {
+        Ok(x) => x,
+        #[allow(unused_variables)]
+        Err(e) => {
+            #[cfg(feature = "debug")]
+            super::imports::console_error(&format!("USDPL: Failed to load WebSocket packet: {:?}", e));
+            return false;
+        }
+    };
+    if let Some(resolve) = pending().remove(&response.id) {
+        responses().insert(response.id, response.packet);
+        resolve.call0(&JsValue::NULL).ok();
+    }
+    true
+}
+
+/// Forget the closed socket and wake up every request still waiting for a response
+fn disconnected(ws: &WebSocket) {
+    if socket().as_ref() == Some(ws) {
+        *socket() = None;
+    }
+    #[cfg(feature = "debug")]
+    super::imports::console_log("USDPL: WebSocket closed, using HTTP");
+    for (_, resolve) in pending().drain() {
+        resolve.call0(&JsValue::NULL).ok();
+    }
+}
+
+/// Send a packet over the WebSocket and wait for the response with the same id
+pub(crate) async fn send_recv_packet(
+    id: u64,
+    packet: Packet,
+    #[cfg(feature = "encrypt")]
+    key: super::connection::Key,
+) -> Result<Packet, JsValue> {
+    let ws = socket().clone().ok_or_else(|| JsValue::from("USDPL WebSocket is not connected"))?;
+    let binary = *binary();
+    #[allow(unused_variables)]
+    let (buffer, len) = super::connection::dump_to_buffer(Tagged { id, packet }, binary, #[cfg(feature = "encrypt")] &key)?;
+
+    let response = Promise::new(&mut |resolve, _reject| {
+        web_sys::window()
+            .unwrap()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, RESPONSE_TIMEOUT_MS)
+            .ok();
+        pending().insert(id, resolve);
+    });
+    let sent = if binary {
+        #[cfg(feature = "debug")]
+        super::imports::console_log(&format!("Sending binary len:{} over WebSocket", len));
+        ws.send_with_u8_array(&buffer)
+    } else {
+        let string: String = String::from_utf8_lossy(buffer.as_slice()).into();
+        #[cfg(feature = "debug")]
+        super::imports::console_log(&format!("Sending base64 `{}` len:{} over WebSocket", string, len));
+        ws.send_with_str(&string)
+    };
+    if let Err(e) = sent {
+        pending().remove(&id);
+        return Err(e);
+    }
+    JsFuture::from(response).await?;
+    if let Some(packet) = responses().remove(&id) {
+        return Ok(packet);
+    }
+    // still pending when the timeout resolved it
+    match pending().remove(&id) {
+        Some(_) => Err(JsValue::from(format!("USDPL WebSocket did not respond within {} ms", RESPONSE_TIMEOUT_MS))),
+        None => Err(JsValue::from(CLOSED_BEFORE_RESPONDING)),
+    }
+}